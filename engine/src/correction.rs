@@ -1,20 +1,190 @@
 use std::fs;
 use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
 use flate2::write::GzDecoder;
-use once_cell::sync::OnceCell;
-use symspell::{AsciiStringStrategy, SymSpell};
+use hashbrown::HashSet;
+use once_cell::sync::{Lazy, OnceCell};
+use parking_lot::RwLock;
+use serde::Deserialize;
+use symspell::{AsciiStringStrategy, SymSpell, Verbosity};
+use tantivy::tokenizer::{AsciiFoldingFilter, RawTokenizer, TextAnalyzer, TokenStream};
 
-static SYMSPELL: OnceCell<SymSpell<AsciiStringStrategy>> = OnceCell::new();
+/// The active dictionary, this can be replaced at runtime while any
+/// corrections in progress keep using the dictionary they started with.
+static SYMSPELL: Lazy<RwLock<Option<Arc<SymSpell<AsciiStringStrategy>>>>> = Lazy::new(Default::default);
+static NORMALIZER: OnceCell<CorrectionNormalizer> = OnceCell::new();
 static ENABLED: AtomicBool = AtomicBool::new(false);
 
+/// How text is normalized before it is corrected.
+///
+/// The same normalizer is used when correcting documents and queries,
+/// changing it requires re-indexing any documents corrected with the
+/// previous normalizer otherwise they may no longer match.
+#[derive(Debug, Copy, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CorrectionNormalizer {
+    /// The text is corrected as is. (Default)
+    Raw,
+
+    /// Accented characters are folded into their ASCII equivalent
+    /// e.g. `café` becomes `cafe`.
+    AsciiFolding,
+}
+
+impl Default for CorrectionNormalizer {
+    fn default() -> Self {
+        Self::Raw
+    }
+}
+
+impl FromStr for CorrectionNormalizer {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(Self::Raw),
+            "ascii-folding" => Ok(Self::AsciiFolding),
+            other => Err(anyhow::Error::msg(format!(
+                "unknown correction normalizer {:?}, expected one of `raw` or `ascii-folding`",
+                other
+            ))),
+        }
+    }
+}
+
+impl CorrectionNormalizer {
+    fn normalize(&self, text: &str) -> String {
+        match self {
+            Self::Raw => text.to_string(),
+            Self::AsciiFolding => fold_to_ascii(text),
+        }
+    }
+}
+
+/// Folds any diacritics and other non-ascii characters of the text to
+/// their ascii equivalent the same way as tantivy's `AsciiFoldingFilter`.
+pub(crate) fn fold_to_ascii(text: &str) -> String {
+    let analyzer = TextAnalyzer::from(RawTokenizer).filter(AsciiFoldingFilter);
+    let mut stream = analyzer.token_stream(text);
+
+    // The raw tokenizer emits the whole text as a single token.
+    let mut folded = String::with_capacity(text.len());
+    stream.process(&mut |token| folded.push_str(&token.text));
+    folded
+}
+
+/// Normalizes the text with the configured normalizer.
+fn normalize(text: &str) -> String {
+    NORMALIZER.get().copied().unwrap_or_default().normalize(text)
+}
+
+/// Controls how many suggestions symspell considers when looking up
+/// alternative corrections for a single word.
+#[derive(Debug, Copy, Clone, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CorrectionVerbosity {
+    /// Only the single most frequent suggestion with the smallest edit distance.
+    Top,
+
+    /// All suggestions with the smallest edit distance found.
+    Closest,
+
+    /// All suggestions within the max edit distance.
+    All,
+}
+
+impl Default for CorrectionVerbosity {
+    fn default() -> Self {
+        Self::Top
+    }
+}
+
+impl From<CorrectionVerbosity> for Verbosity {
+    fn from(v: CorrectionVerbosity) -> Self {
+        match v {
+            CorrectionVerbosity::Top => Verbosity::Top,
+            CorrectionVerbosity::Closest => Verbosity::Closest,
+            CorrectionVerbosity::All => Verbosity::All,
+        }
+    }
+}
+
 pub(crate) fn enabled() -> bool {
-    ENABLED.load(Ordering::Relaxed)
+    ENABLED.load(Ordering::Acquire)
+}
+
+/// Gets the active dictionary.
+fn symspell() -> Arc<SymSpell<AsciiStringStrategy>> {
+    SYMSPELL.read().clone().expect("get symspell")
 }
 
-pub(crate) fn enable_load_dictionaries() -> anyhow::Result<()> {
-    ENABLED.store(true, Ordering::Relaxed);
+/// Makes the given dictionary the active dictionary, enabling the
+/// correction system if it was not already enabled.
+///
+/// The system is only enabled by `enable_load_dictionaries` at startup,
+/// reloads check it's already enabled first.
+fn replace_dictionary(symspell: SymSpell<AsciiStringStrategy>) {
+    *SYMSPELL.write() = Some(Arc::new(symspell));
+
+    // The dictionary is always set before the system is marked as enabled.
+    ENABLED.store(true, Ordering::Release);
+}
+
+/// Reads the frequency dictionary at the given path.
+///
+/// The dictionary must have a term and it's count per line separated
+/// by a space.
+fn read_dictionary(path: &str) -> anyhow::Result<SymSpell<AsciiStringStrategy>> {
+    if !Path::new(path).is_file() {
+        return Err(anyhow::Error::msg(format!("no dictionary file exists at {:?}", path)));
+    }
+
+    let mut symspell: SymSpell<AsciiStringStrategy> = SymSpell::default();
+    if !symspell.load_dictionary(path, 0, 1, " ") {
+        return Err(anyhow::Error::msg(format!("failed to load the dictionary at {:?}", path)));
+    }
+
+    Ok(symspell)
+}
+
+/// Loads the frequency dictionary at the given path, replacing the
+/// active dictionary once it has been loaded.
+///
+/// This can only replace an already active dictionary, indexes using
+/// fast fuzzy are only built with the corrected fields if the system
+/// was enabled at startup. Documents which were corrected with the
+/// previous dictionary are not re-corrected.
+pub(crate) fn load_dictionary(path: &str) -> anyhow::Result<()> {
+    if !enabled() {
+        return Err(anyhow::Error::msg(
+            "the fast fuzzy system was not enabled at startup, no dictionary can be loaded",
+        ));
+    }
+
+    let symspell = read_dictionary(path)?;
+    replace_dictionary(symspell);
+    info!("[ CORRECTION ] loaded dictionary from {:?}", path);
+
+    Ok(())
+}
+
+/// Enables the correction system, loading the dictionary at the given
+/// path or the bundled dictionary if no path is given.
+pub(crate) fn enable_load_dictionaries(
+    normalizer: CorrectionNormalizer,
+    dictionary: Option<String>,
+) -> anyhow::Result<()> {
+    let _ = NORMALIZER.set(normalizer);
+
+    if let Some(path) = dictionary {
+        replace_dictionary(read_dictionary(&path)?);
+        info!("[ CORRECTION ] loaded dictionary from {:?}", path);
+        return Ok(());
+    }
 
     let mut symspell: SymSpell<AsciiStringStrategy> = SymSpell::default();
 
@@ -28,19 +198,58 @@ pub(crate) fn enable_load_dictionaries() -> anyhow::Result<()> {
 
     fs::remove_file("./_temp.txt")?;
 
-    let _ = SYMSPELL.set(symspell);
+    replace_dictionary(symspell);
 
     Ok(())
 }
 
 pub(crate) fn correct_sentence(query: &str, edit_distance: i64) -> String {
-    let sym = SYMSPELL.get().expect("get symspell");
+    let sym = symspell();
 
-    let mut suggestions = sym.lookup_compound(query, edit_distance);
+    let query = normalize(query);
+    let mut suggestions = sym.lookup_compound(&query, edit_distance);
 
     if suggestions.len() == 0 {
-        return query.into();
+        return query;
     }
 
     return suggestions.remove(0).term;
 }
+
+/// Corrects a sentence returning each corrected word along with
+/// whether or not the correction altered it.
+///
+/// A word is considered unaltered if it appears in the original sentence,
+/// this holds even when the correction splits or joins words.
+pub(crate) fn correct_sentence_words(query: &str, edit_distance: i64) -> Vec<(String, bool)> {
+    let original: HashSet<String> = normalize(query)
+        .to_lowercase()
+        .split_whitespace()
+        .map(|word| word.to_string())
+        .collect();
+
+    correct_sentence(query, edit_distance)
+        .split_whitespace()
+        .map(|word| (word.to_string(), !original.contains(word)))
+        .collect()
+}
+
+/// Looks up the ranked correction candidates for a single word.
+///
+/// Candidates are ordered by edit distance and then by frequency with
+/// at most `max_suggestions` being returned, if no suggestions exist
+/// this returns an empty vec.
+pub(crate) fn correct_word_candidates(
+    word: &str,
+    edit_distance: i64,
+    max_suggestions: usize,
+    verbosity: CorrectionVerbosity,
+) -> Vec<String> {
+    let sym = symspell();
+
+    sym.lookup(&normalize(word), verbosity.into(), edit_distance)
+        .into_iter()
+        .take(max_suggestions)
+        .map(|suggestion| suggestion.term)
+        .collect()
+}
@@ -4,7 +4,7 @@ use anyhow::{Error, Result};
 use hashbrown::HashMap;
 use tokio::sync::RwLock;
 
-use crate::correction::enable_load_dictionaries;
+use crate::correction::{enable_load_dictionaries, load_dictionary, CorrectionNormalizer};
 use crate::index::IndexHandler;
 use crate::storage::StorageManager;
 use crate::structures::IndexDeclaration;
@@ -23,12 +23,24 @@ pub struct SearchEngine {
 impl SearchEngine {
     /// Creates a new search engine loading the existing index metadata
     /// from the given directory.
-    pub async fn create(dir: &str, enable_fast_fuzzy: bool) -> Result<Self> {
+    ///
+    /// The normalizer is used by the fast fuzzy system when correcting
+    /// both documents and queries, the system uses the dictionary at the
+    /// given path if set otherwise the bundled dictionary.
+    pub async fn create(
+        dir: &str,
+        enable_fast_fuzzy: bool,
+        correction_normalizer: CorrectionNormalizer,
+        correction_dictionary: Option<String>,
+    ) -> Result<Self> {
         crate::stop_words::init_stop_words()?;
 
         if enable_fast_fuzzy {
             info!("fuzzy search has been enabled! Beginning startup procedure.");
-            tokio::task::spawn_blocking(move || enable_load_dictionaries()).await??;
+            tokio::task::spawn_blocking(move || {
+                enable_load_dictionaries(correction_normalizer, correction_dictionary)
+            })
+            .await??;
         }
 
         let storage = StorageManager::with_directory(dir.to_string()).await?;
@@ -49,10 +61,25 @@ impl SearchEngine {
         })
     }
 
+    /// Loads the fast fuzzy dictionary at the given path, replacing the
+    /// active dictionary without restarting.
+    ///
+    /// Searches keep using the previous dictionary until the new one has
+    /// been fully loaded, documents which were already corrected with the
+    /// previous dictionary are not re-corrected.
+    ///
+    /// This errors if fast fuzzy was not enabled at startup.
+    pub async fn load_correction_dictionary(&self, path: String) -> Result<()> {
+        tokio::task::spawn_blocking(move || load_dictionary(&path)).await?
+    }
+
     /// Adds a declared index to the search engine.
     ///
     /// This will set it in the index storage and then build the index handlers.
     pub async fn add_index(&self, index: IndexDeclaration, override_if_exists: bool) -> Result<()> {
+        // The declaration is checked before any existing index is removed.
+        let loaded = index.clone().into_schema()?;
+
         let remove = {
             let lock = self.indexes.read().await;
             if lock.contains_key(&index.name) {
@@ -78,8 +105,6 @@ impl SearchEngine {
             };
         }
 
-        let copy_index = index.clone();
-        let loaded = copy_index.into_schema();
         let name = loaded.name.clone();
         let index_handler = Arc::new(IndexHandler::build_loaded(loaded).await?);
 
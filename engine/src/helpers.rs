@@ -1,6 +1,7 @@
 use std::hash::{Hash, Hasher};
 
 use ahash::AHasher;
+use serde::Serialize;
 
 use crate::correction::correct_sentence;
 use crate::structures;
@@ -12,9 +13,59 @@ pub(crate) fn hash<T: Hash>(v: &T) -> u64 {
     hasher.finish()
 }
 
+/// A text value which was altered by the indexing-time correction.
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldCorrection {
+    /// The name of the field containing the value.
+    pub field: String,
+
+    /// The value as it was given.
+    pub original: String,
+
+    /// The value after correction.
+    pub corrected: String,
+}
+
 pub fn correct_doc_fields(doc: &mut structures::Document, indexed_text_fields: &Vec<String>) {
+    correct_fields(doc, indexed_text_fields, None)
+}
+
+/// Corrects the document the same as `correct_doc_fields` returning
+/// a report of every value which the correction altered.
+///
+/// This is useful for auditing over-aggressive corrections, e.g. a brand
+/// name being corrected to a dictionary word.
+pub fn correct_doc_fields_with_report(
+    doc: &mut structures::Document,
+    indexed_text_fields: &Vec<String>,
+) -> Vec<FieldCorrection> {
+    let mut report = vec![];
+    correct_fields(doc, indexed_text_fields, Some(&mut report));
+    report
+}
+
+fn correct_fields(
+    doc: &mut structures::Document,
+    indexed_text_fields: &Vec<String>,
+    mut report: Option<&mut Vec<FieldCorrection>>,
+) {
     let mut changes = vec![];
 
+    let mut correct = |field: &str, data: &str| {
+        let corrected = correct_sentence(data, 1);
+        if let Some(report) = report.as_mut() {
+            if corrected != data {
+                report.push(FieldCorrection {
+                    field: field.to_string(),
+                    original: data.to_string(),
+                    corrected: corrected.clone(),
+                });
+            }
+        }
+
+        corrected
+    };
+
     for target in indexed_text_fields {
         let id = hash(target);
 
@@ -23,7 +74,7 @@ pub fn correct_doc_fields(doc: &mut structures::Document, indexed_text_fields: &
             match values {
                 DocumentItem::Single(value) =>  {
                     if let DocumentValue::Text(ref data) = value {
-                        let corrected = correct_sentence(data, 1);
+                        let corrected = correct(target, data);
                         changes.push((format!("_{}", id), DocumentItem::Single(DocumentValue::Text(corrected))));
                     }
                 },
@@ -31,7 +82,7 @@ pub fn correct_doc_fields(doc: &mut structures::Document, indexed_text_fields: &
                     let mut local_changes = vec![];
                     for val in values {
                         if let DocumentValue::Text(ref data) = val {
-                            let corrected = correct_sentence(data, 1);
+                            let corrected = correct(target, data);
                             local_changes.push(DocumentValue::Text(corrected));
                         }
                     }
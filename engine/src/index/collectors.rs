@@ -0,0 +1,1300 @@
+use std::cmp::Ordering as CmpOrdering;
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use anyhow::{Error, Result};
+use chrono::Datelike;
+use hashbrown::HashMap;
+use parking_lot::Mutex;
+use tantivy::collector::{Collector, SegmentCollector, TopDocs};
+use tantivy::fastfield::{DynamicFastFieldReader, FastFieldReader};
+use tantivy::query::{Scorer, Weight};
+use tantivy::schema::{Cardinality, Field, Schema, Type};
+use tantivy::{u64_to_f64, u64_to_i64, DocAddress, DocId, DocSet, Score, SegmentReader, TERMINATED};
+
+use crate::helpers::hash;
+use crate::index::queries::fast_field_cardinality;
+use crate::structures::{HistogramInterval, SortOrder};
+
+/// The amount of documents collected between each check of the deadline.
+///
+/// Checking the clock for every document is surprisingly expensive
+/// so we only check it periodically.
+const DEADLINE_CHECK_INTERVAL: usize = 1024;
+
+/// A optional time budget for a search.
+///
+/// The deadline is only checked periodically while scoring so a search
+/// can overrun it by up to `DEADLINE_CHECK_INTERVAL` documents.
+#[derive(Clone)]
+pub(super) struct Deadline {
+    at: Option<Instant>,
+    expired: Arc<AtomicBool>,
+}
+
+impl Deadline {
+    pub(super) fn new(at: Option<Instant>) -> Self {
+        Self {
+            at,
+            expired: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Whether or not any collector stopped early due to the deadline.
+    pub(super) fn expired(&self) -> bool {
+        self.expired.load(Ordering::Relaxed)
+    }
+
+    fn check(&self) -> bool {
+        if self.expired() {
+            return true;
+        }
+
+        match self.at {
+            Some(at) if Instant::now() >= at => {
+                self.expired.store(true, Ordering::Relaxed);
+                true
+            },
+            _ => false,
+        }
+    }
+}
+
+/// The amount of documents a segment reserves from a document budget
+/// at a time, this avoids contending on the budget for every document.
+const BUDGET_RESERVE_SIZE: usize = 1024;
+
+/// A optional limit on the amount of documents a search scores.
+///
+/// Unlike the deadline this doesn't depend on how fast the hardware is,
+/// however with a multi-threaded executor the segments are scored
+/// concurrently so which documents are scored before the budget runs
+/// out can differ between runs.
+///
+/// Each pass of a search has the full budget to itself, e.g. a hybrid
+/// search counts the matches and ranks both the text and vector queries
+/// so it can score up to three times the budget.
+#[derive(Clone)]
+pub(super) struct DocBudget {
+    max_docs: Option<usize>,
+    exhausted: Arc<AtomicBool>,
+}
+
+impl DocBudget {
+    pub(super) fn new(max_docs: Option<usize>) -> Self {
+        Self {
+            max_docs,
+            exhausted: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Whether or not any collector stopped early due to the budget.
+    pub(super) fn exhausted(&self) -> bool {
+        self.exhausted.load(Ordering::Relaxed)
+    }
+}
+
+/// The deadline and document budget of a search.
+///
+/// Any collectors wrapped by the limits stop collecting once either
+/// limit has been reached, keeping whatever they have collected so far.
+#[derive(Clone)]
+pub(super) struct SearchLimits {
+    pub(super) deadline: Deadline,
+    pub(super) budget: DocBudget,
+}
+
+impl SearchLimits {
+    pub(super) fn new(deadline: Deadline, budget: DocBudget) -> Self {
+        Self { deadline, budget }
+    }
+
+    /// Wraps a given collector so that it respects the limits.
+    ///
+    /// The limits must wrap the outermost collector of a search, as with
+    /// any limit set they drive the scorer of each segment themselves so
+    /// scoring stops once a limit has been reached, any segments started
+    /// after that are skipped entirely.
+    pub(super) fn wrap<C: Collector>(&self, inner: C) -> LimitedCollector<C> {
+        LimitedCollector {
+            inner,
+            limited: self.deadline.at.is_some() || self.budget.max_docs.is_some(),
+            deadline: self.deadline.clone(),
+            remaining: Arc::new(AtomicUsize::new(self.budget.max_docs.unwrap_or(usize::MAX))),
+            exhausted: self.budget.exhausted.clone(),
+        }
+    }
+
+    /// Wraps a given collector like `wrap` but also stops scoring once
+    /// `max_docs` documents have been collected.
+    ///
+    /// Reaching `max_docs` doesn't mark the budget as exhausted, it's up
+    /// to the wrapped collector to detect it e.g. with `CappedCount`.
+    pub(super) fn wrap_capped<C: Collector>(&self, inner: C, max_docs: usize) -> LimitedCollector<C> {
+        match self.budget.max_docs {
+            Some(budget) if budget <= max_docs => self.wrap(inner),
+            _ => LimitedCollector {
+                inner,
+                limited: self.deadline.at.is_some() || max_docs != usize::MAX,
+                deadline: self.deadline.clone(),
+                remaining: Arc::new(AtomicUsize::new(max_docs)),
+                exhausted: Arc::new(AtomicBool::new(false)),
+            },
+        }
+    }
+}
+
+/// A collector wrapper which stops collecting documents once the deadline
+/// has passed or the budgeted amount of documents have been collected.
+///
+/// The limits are checked before each segment is collected and while
+/// collecting a segment, any documents collected before a limit is
+/// reached are kept.
+///
+/// Only the outermost collector of a search collects the segments, so
+/// this must not be wrapped by another collector.
+pub(super) struct LimitedCollector<C: Collector> {
+    inner: C,
+    limited: bool,
+    deadline: Deadline,
+    remaining: Arc<AtomicUsize>,
+    exhausted: Arc<AtomicBool>,
+}
+
+impl<C: Collector> Collector for LimitedCollector<C> {
+    type Fruit = C::Fruit;
+    type Child = LimitedSegmentCollector<C::Child>;
+
+    fn for_segment(
+        &self,
+        segment_local_id: u32,
+        segment: &SegmentReader,
+    ) -> tantivy::Result<Self::Child> {
+        let inner = self.inner.for_segment(segment_local_id, segment)?;
+
+        let mut collector = LimitedSegmentCollector {
+            inner,
+            deadline: self.deadline.clone(),
+            remaining: self.remaining.clone(),
+            exhausted: self.exhausted.clone(),
+            reserved: 0,
+            collected: 0,
+            stopped: false,
+        };
+        collector.stopped = self.deadline.check() || !collector.reserve();
+
+        Ok(collector)
+    }
+
+    fn requires_scoring(&self) -> bool {
+        self.inner.requires_scoring()
+    }
+
+    fn merge_fruits(
+        &self,
+        segment_fruits: Vec<<Self::Child as SegmentCollector>::Fruit>,
+    ) -> tantivy::Result<Self::Fruit> {
+        self.inner.merge_fruits(segment_fruits)
+    }
+
+    fn collect_segment(
+        &self,
+        weight: &dyn Weight,
+        segment_ord: u32,
+        reader: &SegmentReader,
+    ) -> tantivy::Result<<Self::Child as SegmentCollector>::Fruit> {
+        // Without any limits the inner collector collects the segment itself,
+        // e.g. `TopDocs` skips the blocks which can't make the top hits.
+        if !self.limited {
+            return self.inner.collect_segment(weight, segment_ord, reader);
+        }
+
+        let mut collector = self.for_segment(segment_ord, reader)?;
+
+        // The scorer isn't created at all if a limit had already been reached.
+        if !collector.stopped {
+            collect_until_stopped(weight, reader, &mut collector, |collector| collector.stopped)?;
+        }
+
+        Ok(collector.harvest())
+    }
+}
+
+/// Collects the matching documents of a segment until the collector
+/// has stopped.
+///
+/// Unlike tantivy's default collection of a segment this stops advancing
+/// the scorer once the collector stops, rather than scoring the rest of
+/// the segment's matches only for them to be ignored.
+fn collect_until_stopped<C: SegmentCollector>(
+    weight: &dyn Weight,
+    reader: &SegmentReader,
+    collector: &mut C,
+    stopped: impl Fn(&C) -> bool,
+) -> tantivy::Result<()> {
+    let mut scorer = weight.scorer(reader, 1.0)?;
+
+    let mut doc = scorer.doc();
+    while doc != TERMINATED && !stopped(collector) {
+        if !reader.is_deleted(doc) {
+            collector.collect(doc, scorer.score());
+        }
+        doc = scorer.advance();
+    }
+
+    Ok(())
+}
+
+pub(super) struct LimitedSegmentCollector<C: SegmentCollector> {
+    inner: C,
+    deadline: Deadline,
+    remaining: Arc<AtomicUsize>,
+    exhausted: Arc<AtomicBool>,
+    reserved: usize,
+    collected: usize,
+    stopped: bool,
+}
+
+impl<C: SegmentCollector> LimitedSegmentCollector<C> {
+    /// Reserves the next part of the budget for this segment returning
+    /// false if the budget has been used up.
+    fn reserve(&mut self) -> bool {
+        let res = self.remaining.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |remaining| {
+            if remaining == 0 {
+                None
+            } else {
+                Some(remaining - remaining.min(BUDGET_RESERVE_SIZE))
+            }
+        });
+
+        match res {
+            Ok(previous) => {
+                self.reserved = previous.min(BUDGET_RESERVE_SIZE);
+                true
+            },
+            Err(_) => {
+                self.exhausted.store(true, Ordering::Relaxed);
+                false
+            },
+        }
+    }
+}
+
+impl<C: SegmentCollector> SegmentCollector for LimitedSegmentCollector<C> {
+    type Fruit = C::Fruit;
+
+    fn collect(&mut self, doc: DocId, score: Score) {
+        if self.stopped {
+            return;
+        }
+
+        self.collected += 1;
+        if self.collected % DEADLINE_CHECK_INTERVAL == 0 && self.deadline.check() {
+            self.stopped = true;
+            return;
+        }
+
+        if self.reserved == 0 && !self.reserve() {
+            self.stopped = true;
+            return;
+        }
+
+        self.reserved -= 1;
+        self.inner.collect(doc, score);
+    }
+
+    fn harvest(self) -> Self::Fruit {
+        // Any unused reservation is returned so other segments can use it.
+        if self.reserved > 0 {
+            self.remaining.fetch_add(self.reserved, Ordering::Relaxed);
+        }
+
+        self.inner.harvest()
+    }
+}
+
+/// A single value numeric fast field column of a segment read as f64s.
+pub(super) struct NumericColumn {
+    column: DynamicFastFieldReader<u64>,
+    value_type: Type,
+}
+
+impl NumericColumn {
+    /// Checks that the given field is a single value numeric fast field.
+    pub(super) fn validate(schema: &Schema, name: &str) -> Result<Field> {
+        let field = schema
+            .get_field(name)
+            .ok_or_else(|| Error::msg(format!("field {:?} does not exist", name)))?;
+
+        let entry = schema.get_field_entry(field);
+        match fast_field_cardinality(entry.field_type()) {
+            Some(Cardinality::SingleValue) => Ok(field),
+            _ => Err(Error::msg(format!(
+                "field {:?} must be a single value numeric fast field",
+                name
+            ))),
+        }
+    }
+
+    /// Opens the column of a field which has already been validated.
+    pub(super) fn open(reader: &SegmentReader, field: Field) -> Self {
+        let value_type = reader.schema().get_field_entry(field).field_type().value_type();
+        let column = reader
+            .fast_fields()
+            .u64_lenient(field)
+            .expect("fast field was validated before searching");
+
+        Self { column, value_type }
+    }
+
+    pub(super) fn get(&self, doc: DocId) -> f64 {
+        let value = self.column.get(doc);
+        match self.value_type {
+            Type::I64 | Type::Date => u64_to_i64(value) as f64,
+            Type::F64 => u64_to_f64(value),
+            _ => value as f64,
+        }
+    }
+}
+
+/// Multiplies the relevance score of a document by a factor derived from
+/// the value of a numeric fast field.
+///
+/// By default the factor is `1 + ln(1 + value)` clamped to `max_boost`,
+/// negative values are treated as 0 giving a factor of 1 so they never
+/// penalise a document.
+#[derive(Clone)]
+pub(super) struct FieldValueBoost {
+    field: Field,
+    function: BoostFunction,
+}
+
+/// How the value of the field is turned into the boost factor.
+#[derive(Clone, Copy)]
+enum BoostFunction {
+    /// `1 + ln(1 + value)` clamped to the max boost.
+    Log { max_boost: Score },
+
+    /// `1 + weight * 0.5 ^ (age / half_life)` where the age is the time in
+    /// seconds between the origin and the value, values after the origin
+    /// get the full boost.
+    Decay { origin: f64, half_life: f64, weight: Score },
+}
+
+impl FieldValueBoost {
+    pub(super) fn new(field: Field, max_boost: Score) -> Self {
+        Self {
+            field,
+            function: BoostFunction::Log { max_boost },
+        }
+    }
+
+    /// Boosts documents by how recent the value of the date field is
+    /// relative to the origin timestamp, halving every `half_life` seconds.
+    pub(super) fn recency(field: Field, origin: i64, half_life: u64, weight: Score) -> Self {
+        Self {
+            field,
+            function: BoostFunction::Decay {
+                origin: origin as f64,
+                half_life: half_life as f64,
+                weight,
+            },
+        }
+    }
+
+    /// Wraps the given top docs collector so that the collected scores are
+    /// boosted by the field value.
+    pub(super) fn wrap(
+        self,
+        collector: TopDocs,
+    ) -> impl Collector<Fruit = Vec<(Score, DocAddress)>> {
+        collector.tweak_score(move |segment_reader: &SegmentReader| {
+            let column = NumericColumn::open(segment_reader, self.field);
+            let function = self.function;
+
+            move |doc: DocId, score: Score| {
+                let value = column.get(doc);
+                let factor = match function {
+                    BoostFunction::Log { max_boost } => {
+                        (1.0 + value.max(0.0).ln_1p() as Score).min(max_boost).max(1.0)
+                    },
+                    BoostFunction::Decay { origin, half_life, weight } => {
+                        let age = (origin - value).max(0.0);
+                        1.0 + weight * 0.5f64.powf(age / half_life) as Score
+                    },
+                };
+
+                score * factor
+            }
+        })
+    }
+}
+
+/// Orders hits with the same score by the hash of a seed and their id
+/// rather than by their address.
+///
+/// The id is hashed instead of the address so the order of the ties
+/// doesn't change as segments are merged.
+#[derive(Clone)]
+pub(super) struct ShuffledTies {
+    id_field: Field,
+    seed: u64,
+}
+
+impl ShuffledTies {
+    pub(super) fn new(id_field: Field, seed: u64) -> Self {
+        Self { id_field, seed }
+    }
+
+    /// Wraps the given top docs collector so that the collected scores
+    /// are paired with the tie breaking hash.
+    pub(super) fn wrap(
+        self,
+        collector: TopDocs,
+    ) -> impl Collector<Fruit = Vec<((Score, u64), DocAddress)>> {
+        collector.tweak_score(move |segment_reader: &SegmentReader| {
+            let column = segment_reader
+                .fast_fields()
+                .u64(self.id_field)
+                .expect("the id field is always a fast field");
+            let seed = self.seed;
+
+            move |doc: DocId, score: Score| (score, hash(&(seed, column.get(doc))))
+        })
+    }
+}
+
+/// Tracks how many segments produced candidates for a search.
+#[derive(Clone, Default)]
+pub(super) struct SegmentStats {
+    total: Arc<AtomicUsize>,
+    scanned: Arc<AtomicUsize>,
+}
+
+impl SegmentStats {
+    /// Wraps a given collector so that it reports the segments it collects.
+    pub(super) fn wrap<C: Collector>(&self, inner: C) -> SegmentStatsCollector<C> {
+        SegmentStatsCollector {
+            inner,
+            stats: self.clone(),
+        }
+    }
+
+    /// The total amount of segments in the searcher.
+    pub(super) fn total(&self) -> usize {
+        self.total.load(Ordering::Relaxed)
+    }
+
+    /// The amount of segments which produced at least one candidate.
+    pub(super) fn scanned(&self) -> usize {
+        self.scanned.load(Ordering::Relaxed)
+    }
+
+    /// The amount of segments which were empty or had every document
+    /// filtered out by the query.
+    pub(super) fn skipped(&self) -> usize {
+        self.total().saturating_sub(self.scanned())
+    }
+}
+
+/// A collector wrapper which counts the segments that produce candidates.
+pub(super) struct SegmentStatsCollector<C: Collector> {
+    inner: C,
+    stats: SegmentStats,
+}
+
+impl<C: Collector> Collector for SegmentStatsCollector<C> {
+    type Fruit = C::Fruit;
+    type Child = SegmentStatsSegmentCollector<C::Child>;
+
+    fn for_segment(
+        &self,
+        segment_local_id: u32,
+        segment: &SegmentReader,
+    ) -> tantivy::Result<Self::Child> {
+        self.stats.total.fetch_add(1, Ordering::Relaxed);
+        let inner = self.inner.for_segment(segment_local_id, segment)?;
+
+        Ok(SegmentStatsSegmentCollector {
+            inner,
+            stats: self.stats.clone(),
+            has_candidates: false,
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        self.inner.requires_scoring()
+    }
+
+    fn merge_fruits(
+        &self,
+        segment_fruits: Vec<<Self::Child as SegmentCollector>::Fruit>,
+    ) -> tantivy::Result<Self::Fruit> {
+        self.inner.merge_fruits(segment_fruits)
+    }
+}
+
+pub(super) struct SegmentStatsSegmentCollector<C: SegmentCollector> {
+    inner: C,
+    stats: SegmentStats,
+    has_candidates: bool,
+}
+
+impl<C: SegmentCollector> SegmentCollector for SegmentStatsSegmentCollector<C> {
+    type Fruit = C::Fruit;
+
+    fn collect(&mut self, doc: DocId, score: Score) {
+        self.has_candidates = true;
+        self.inner.collect(doc, score);
+    }
+
+    fn harvest(self) -> Self::Fruit {
+        if self.has_candidates {
+            self.stats.scanned.fetch_add(1, Ordering::Relaxed);
+        }
+
+        self.inner.harvest()
+    }
+}
+
+/// Counts the matching documents upto an optional cap.
+///
+/// Once the cap is exceeded the count is no longer exact, every segment
+/// stops counting one past the cap so the overflow can still be detected.
+///
+/// This only bounds the count, when it's combined with other collectors
+/// every matching document is still scored. Searches which only count
+/// the matches can stop scoring at the cap by wrapping the count with
+/// `SearchLimits::wrap_capped` using the count's `limit`.
+#[derive(Copy, Clone)]
+pub(super) struct CappedCount {
+    cap: Option<usize>,
+}
+
+impl CappedCount {
+    pub(super) fn new(cap: Option<usize>) -> Self {
+        Self { cap }
+    }
+
+    /// Gets the reported count and whether or not the cap was exceeded
+    /// from the collected count.
+    pub(super) fn resolve(&self, count: usize) -> (usize, bool) {
+        match self.cap {
+            Some(cap) if count > cap => (cap, true),
+            _ => (count, false),
+        }
+    }
+
+    /// The amount of documents counted before the cap is known to have
+    /// been exceeded.
+    pub(super) fn limit(&self) -> usize {
+        self.cap.map(|cap| cap.saturating_add(1)).unwrap_or(usize::MAX)
+    }
+}
+
+impl Collector for CappedCount {
+    type Fruit = usize;
+    type Child = CappedSegmentCount;
+
+    fn for_segment(&self, _segment_local_id: u32, _segment: &SegmentReader) -> tantivy::Result<Self::Child> {
+        Ok(CappedSegmentCount {
+            count: 0,
+            limit: self.limit(),
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        false
+    }
+
+    fn merge_fruits(&self, segment_counts: Vec<usize>) -> tantivy::Result<Self::Fruit> {
+        let count = segment_counts.into_iter().fold(0usize, |total, count| total.saturating_add(count));
+
+        Ok(count.min(self.limit()))
+    }
+}
+
+pub(super) struct CappedSegmentCount {
+    count: usize,
+    limit: usize,
+}
+
+impl SegmentCollector for CappedSegmentCount {
+    type Fruit = usize;
+
+    fn collect(&mut self, _doc: DocId, _score: Score) {
+        if self.count < self.limit {
+            self.count += 1;
+        }
+    }
+
+    fn harvest(self) -> Self::Fruit {
+        self.count
+    }
+}
+
+/// A group of hits sharing the same value of the grouped field.
+pub(super) struct GroupFruit {
+    /// The raw fast field value of the group.
+    pub(super) key: u64,
+
+    /// The amount of matching documents in the group.
+    pub(super) count: usize,
+
+    /// The top hits of the group ordered by score descending.
+    pub(super) hits: Vec<(Score, DocAddress)>,
+}
+
+impl GroupFruit {
+    fn best_score(&self) -> Score {
+        self.hits.first().map(|(score, _)| *score).unwrap_or(0.0)
+    }
+}
+
+/// Groups the matching documents by the value of a single value fast
+/// field, collecting the top hits of each group.
+///
+/// The groups are ranked by the score of their best hit with only the
+/// top `groups_limit` groups being kept, along with the total amount of
+/// groups the matching documents belong to.
+pub(super) struct GroupCollector {
+    field: Field,
+    groups_limit: usize,
+    per_group_limit: usize,
+}
+
+impl GroupCollector {
+    pub(super) fn new(field: Field, groups_limit: usize, per_group_limit: usize) -> Self {
+        Self {
+            field,
+            groups_limit,
+            per_group_limit: per_group_limit.max(1),
+        }
+    }
+}
+
+impl Collector for GroupCollector {
+    type Fruit = (Vec<GroupFruit>, usize);
+    type Child = GroupSegmentCollector;
+
+    fn for_segment(
+        &self,
+        segment_local_id: u32,
+        segment: &SegmentReader,
+    ) -> tantivy::Result<Self::Child> {
+        let column = segment.fast_fields().u64_lenient(self.field)?;
+
+        Ok(GroupSegmentCollector {
+            column,
+            segment_ord: segment_local_id,
+            per_group_limit: self.per_group_limit,
+            groups: HashMap::new(),
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        true
+    }
+
+    fn merge_fruits(&self, segment_fruits: Vec<Vec<GroupFruit>>) -> tantivy::Result<Self::Fruit> {
+        let mut merged: HashMap<u64, GroupFruit> = HashMap::new();
+        for group in segment_fruits.into_iter().flatten() {
+            match merged.get_mut(&group.key) {
+                None => {
+                    merged.insert(group.key, group);
+                },
+                Some(existing) => {
+                    existing.count += group.count;
+                    existing.hits.extend(group.hits);
+                    sort_hits(&mut existing.hits);
+                    existing.hits.truncate(self.per_group_limit);
+                },
+            }
+        }
+
+        let mut groups: Vec<GroupFruit> = merged.into_iter().map(|(_, group)| group).collect();
+        groups.sort_by(|a, b| {
+            b.best_score()
+                .partial_cmp(&a.best_score())
+                .unwrap_or(CmpOrdering::Equal)
+                .then(a.hits.first().map(|h| h.1).cmp(&b.hits.first().map(|h| h.1)))
+        });
+        let total_groups = groups.len();
+        groups.truncate(self.groups_limit);
+
+        Ok((groups, total_groups))
+    }
+}
+
+pub(super) struct GroupSegmentCollector {
+    column: DynamicFastFieldReader<u64>,
+    segment_ord: u32,
+    per_group_limit: usize,
+    groups: HashMap<u64, (usize, Vec<(Score, DocId)>)>,
+}
+
+impl SegmentCollector for GroupSegmentCollector {
+    type Fruit = Vec<GroupFruit>;
+
+    fn collect(&mut self, doc: DocId, score: Score) {
+        let key = self.column.get(doc);
+        let (count, hits) = self.groups.entry(key).or_default();
+        *count += 1;
+
+        // Docs are collected in ascending order so ties keep the earlier doc.
+        if hits.len() < self.per_group_limit {
+            hits.push((score, doc));
+        } else if let Some(worst) = hits.iter_mut().min_by(|a, b| {
+            a.0.partial_cmp(&b.0)
+                .unwrap_or(CmpOrdering::Equal)
+                .then(b.1.cmp(&a.1))
+        }) {
+            if score > worst.0 {
+                *worst = (score, doc);
+            }
+        }
+    }
+
+    fn harvest(self) -> Self::Fruit {
+        let segment_ord = self.segment_ord;
+        self.groups
+            .into_iter()
+            .map(|(key, (count, hits))| {
+                let mut hits: Vec<(Score, DocAddress)> = hits
+                    .into_iter()
+                    .map(|(score, doc)| (score, DocAddress::new(segment_ord, doc)))
+                    .collect();
+                sort_hits(&mut hits);
+
+                GroupFruit { key, count, hits }
+            })
+            .collect()
+    }
+}
+
+/// The amount of seconds in a day.
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// The amount of days between 0001-01-01 and the unix epoch.
+const EPOCH_DAYS_FROM_CE: i64 = 719_163;
+
+/// The field and intervals a date histogram counts documents by.
+struct HistogramBuckets {
+    field: Field,
+    interval: HistogramInterval,
+    utc_offset: i64,
+}
+
+impl HistogramBuckets {
+    /// Gets the local timestamp of the start of the bucket containing the day.
+    ///
+    /// Returns `None` if the start is outside of the range of a timestamp.
+    fn bucket_start(&self, day: i64) -> Option<i64> {
+        match self.interval {
+            HistogramInterval::Day => day.checked_mul(SECONDS_PER_DAY),
+
+            // The epoch was a Thursday so days are shifted to start weeks on Monday.
+            HistogramInterval::Week => day
+                .checked_add(3)?
+                .div_euclid(7)
+                .checked_mul(7)?
+                .checked_sub(3)?
+                .checked_mul(SECONDS_PER_DAY),
+            HistogramInterval::Month => {
+                let days = i32::try_from(day.checked_add(EPOCH_DAYS_FROM_CE)?).ok()?;
+                let date = chrono::NaiveDate::from_num_days_from_ce_opt(days)?;
+                let month = chrono::NaiveDate::from_ymd_opt(date.year(), date.month(), 1)?;
+
+                Some(month.and_hms(0, 0, 0).timestamp())
+            },
+        }
+    }
+}
+
+/// Counts the matching documents per interval of a date fast field
+/// alongside the collector of a search if enabled.
+///
+/// Each segment counts the documents per local day which are merged into
+/// the buckets of the interval, every interval is a whole number of days.
+#[derive(Clone)]
+pub(super) struct DateHistogram {
+    buckets: Option<Arc<HistogramBuckets>>,
+    days: Arc<Mutex<HashMap<i64, usize>>>,
+}
+
+impl DateHistogram {
+    /// Creates a histogram with buckets aligned to the given offset
+    /// from UTC in seconds.
+    pub(super) fn new(field: Field, interval: HistogramInterval, utc_offset: i64) -> Self {
+        Self {
+            buckets: Some(Arc::new(HistogramBuckets {
+                field,
+                interval,
+                utc_offset,
+            })),
+            days: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Creates a histogram which counts nothing.
+    pub(super) fn disabled() -> Self {
+        Self {
+            buckets: None,
+            days: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub(super) fn is_enabled(&self) -> bool {
+        self.buckets.is_some()
+    }
+
+    /// Wraps a given collector so that the documents it collects are counted.
+    pub(super) fn wrap<C: Collector>(&self, inner: C) -> DateHistogramCollector<C> {
+        DateHistogramCollector {
+            inner,
+            histogram: self.clone(),
+        }
+    }
+
+    /// Gets the UTC timestamp of the start of every bucket with the amount
+    /// of documents counted in it, `None` if the histogram is disabled.
+    ///
+    /// Buckets starting outside of the range of a timestamp are clamped
+    /// to the nearest representable timestamp.
+    pub(super) fn buckets(&self) -> Option<Vec<(i64, usize)>> {
+        let options = self.buckets.as_ref()?;
+
+        let mut buckets: HashMap<i64, usize> = HashMap::new();
+        for (day, count) in self.days.lock().drain() {
+            let start = options
+                .bucket_start(day)
+                .and_then(|start| start.checked_sub(options.utc_offset))
+                .unwrap_or(if day < 0 { i64::MIN } else { i64::MAX });
+
+            *buckets.entry(start).or_default() += count;
+        }
+
+        let mut buckets: Vec<(i64, usize)> = buckets.into_iter().collect();
+        buckets.sort_unstable();
+
+        Some(buckets)
+    }
+}
+
+/// A collector wrapper which counts every document it collects
+/// per local day.
+pub(super) struct DateHistogramCollector<C: Collector> {
+    inner: C,
+    histogram: DateHistogram,
+}
+
+impl<C: Collector> Collector for DateHistogramCollector<C> {
+    type Fruit = C::Fruit;
+    type Child = DateHistogramSegmentCollector<C::Child>;
+
+    fn for_segment(
+        &self,
+        segment_local_id: u32,
+        segment: &SegmentReader,
+    ) -> tantivy::Result<Self::Child> {
+        let inner = self.inner.for_segment(segment_local_id, segment)?;
+        let column = match self.histogram.buckets {
+            None => None,
+            Some(ref options) => Some((
+                segment.fast_fields().u64_lenient(options.field)?,
+                options.utc_offset,
+            )),
+        };
+
+        Ok(DateHistogramSegmentCollector {
+            inner,
+            histogram: self.histogram.clone(),
+            column,
+            days: HashMap::new(),
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        self.inner.requires_scoring()
+    }
+
+    fn merge_fruits(
+        &self,
+        segment_fruits: Vec<<Self::Child as SegmentCollector>::Fruit>,
+    ) -> tantivy::Result<Self::Fruit> {
+        self.inner.merge_fruits(segment_fruits)
+    }
+}
+
+pub(super) struct DateHistogramSegmentCollector<C: SegmentCollector> {
+    inner: C,
+    histogram: DateHistogram,
+    column: Option<(DynamicFastFieldReader<u64>, i64)>,
+    days: HashMap<i64, usize>,
+}
+
+impl<C: SegmentCollector> SegmentCollector for DateHistogramSegmentCollector<C> {
+    type Fruit = C::Fruit;
+
+    fn collect(&mut self, doc: DocId, score: Score) {
+        if let Some((ref column, utc_offset)) = self.column {
+            let local = u64_to_i64(column.get(doc)).saturating_add(utc_offset);
+            *self.days.entry(local.div_euclid(SECONDS_PER_DAY)).or_default() += 1;
+        }
+
+        self.inner.collect(doc, score);
+    }
+
+    fn harvest(self) -> Self::Fruit {
+        if !self.days.is_empty() {
+            let mut days = self.histogram.days.lock();
+            for (day, count) in self.days {
+                *days.entry(day).or_default() += count;
+            }
+        }
+
+        self.inner.harvest()
+    }
+}
+
+/// What a single key of a multi key sort reads.
+#[derive(Clone, Copy)]
+pub(super) enum SortField {
+    /// The relevance score of the document.
+    Score,
+
+    /// The value of a single value numeric fast field.
+    Field(Field),
+}
+
+/// The value of a single sort key of a document.
+///
+/// Fast field values are kept as their raw `u64` representation which
+/// orders the same as the values themselves.
+#[derive(Clone, Copy)]
+pub(super) enum SortValue {
+    Score(Score),
+    Value(u64),
+}
+
+impl SortValue {
+    fn compare(&self, other: &Self) -> CmpOrdering {
+        match (self, other) {
+            (Self::Score(a), Self::Score(b)) => a.partial_cmp(b).unwrap_or(CmpOrdering::Equal),
+            (Self::Value(a), Self::Value(b)) => a.cmp(b),
+            _ => CmpOrdering::Equal,
+        }
+    }
+}
+
+/// Collects the top documents ordered by several keys, each being either
+/// the relevance score or a fast field value with it's own direction.
+///
+/// Keys are compared lexicographically, ties on every key are ordered
+/// by address.
+pub(super) struct MultiSortCollector {
+    keys: Vec<(SortField, SortOrder)>,
+    orders: Arc<Vec<SortOrder>>,
+    limit: usize,
+    offset: usize,
+}
+
+impl MultiSortCollector {
+    pub(super) fn new(keys: Vec<(SortField, SortOrder)>, limit: usize, offset: usize) -> Self {
+        let orders = keys.iter().map(|(_, order)| *order).collect();
+        Self {
+            keys,
+            orders: Arc::new(orders),
+            limit,
+            offset,
+        }
+    }
+}
+
+impl Collector for MultiSortCollector {
+    type Fruit = Vec<(Vec<SortValue>, DocAddress)>;
+    type Child = MultiSortSegmentCollector;
+
+    fn for_segment(
+        &self,
+        segment_local_id: u32,
+        segment: &SegmentReader,
+    ) -> tantivy::Result<Self::Child> {
+        let mut columns = Vec::with_capacity(self.keys.len());
+        for (field, _) in self.keys.iter() {
+            let column = match field {
+                SortField::Score => None,
+                SortField::Field(field) => Some(segment.fast_fields().u64_lenient(*field)?),
+            };
+            columns.push(column);
+        }
+
+        Ok(MultiSortSegmentCollector {
+            columns,
+            orders: self.orders.clone(),
+            segment_ord: segment_local_id,
+            depth: (self.limit + self.offset).max(1),
+            hits: Vec::new(),
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        self.keys.iter().any(|(field, _)| matches!(field, SortField::Score))
+    }
+
+    fn merge_fruits(
+        &self,
+        segment_fruits: Vec<Vec<(Vec<SortValue>, DocAddress)>>,
+    ) -> tantivy::Result<Self::Fruit> {
+        let mut hits: Vec<(Vec<SortValue>, DocAddress)> = segment_fruits.into_iter().flatten().collect();
+        hits.sort_by(|a, b| compare_keys(&self.orders, &a.0, &b.0).then(a.1.cmp(&b.1)));
+
+        Ok(hits.into_iter().skip(self.offset).take(self.limit).collect())
+    }
+}
+
+pub(super) struct MultiSortSegmentCollector {
+    columns: Vec<Option<DynamicFastFieldReader<u64>>>,
+    orders: Arc<Vec<SortOrder>>,
+    segment_ord: u32,
+    depth: usize,
+    hits: Vec<(Vec<SortValue>, DocId)>,
+}
+
+impl MultiSortSegmentCollector {
+    /// Keeps only the top `depth` hits.
+    fn truncate(&mut self) {
+        let orders = self.orders.clone();
+        self.hits.sort_by(|a, b| compare_keys(&orders, &a.0, &b.0).then(a.1.cmp(&b.1)));
+        self.hits.truncate(self.depth);
+    }
+}
+
+impl SegmentCollector for MultiSortSegmentCollector {
+    type Fruit = Vec<(Vec<SortValue>, DocAddress)>;
+
+    fn collect(&mut self, doc: DocId, score: Score) {
+        let values = self
+            .columns
+            .iter()
+            .map(|column| match column {
+                None => SortValue::Score(score),
+                Some(column) => SortValue::Value(column.get(doc)),
+            })
+            .collect();
+        self.hits.push((values, doc));
+
+        // Sorting in batches keeps the memory bounded without sorting
+        // on every document.
+        if self.hits.len() >= self.depth * 2 {
+            self.truncate();
+        }
+    }
+
+    fn harvest(mut self) -> Self::Fruit {
+        self.truncate();
+
+        let segment_ord = self.segment_ord;
+        self.hits
+            .into_iter()
+            .map(|(values, doc)| (values, DocAddress::new(segment_ord, doc)))
+            .collect()
+    }
+}
+
+/// Compares the key values of two documents in the direction of each key.
+fn compare_keys(orders: &[SortOrder], a: &[SortValue], b: &[SortValue]) -> CmpOrdering {
+    for (order, (a, b)) in orders.iter().zip(a.iter().zip(b.iter())) {
+        let ordering = match order {
+            SortOrder::Asc => a.compare(b),
+            SortOrder::Desc => b.compare(a),
+        };
+
+        if ordering != CmpOrdering::Equal {
+            return ordering;
+        }
+    }
+
+    CmpOrdering::Equal
+}
+
+/// Sorts the hits by score descending, ties are ordered by address.
+fn sort_hits(hits: &mut [(Score, DocAddress)]) {
+    hits.sort_by(|a, b| {
+        b.0.partial_cmp(&a.0)
+            .unwrap_or(CmpOrdering::Equal)
+            .then(a.1.cmp(&b.1))
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use tantivy::collector::Count;
+    use tantivy::merge_policy::NoMergePolicy;
+    use tantivy::query::AllQuery;
+    use tantivy::schema::IntOptions;
+    use tantivy::{Index, Searcher};
+
+    use super::*;
+
+    /// Creates an index with a segment per given list of documents, each
+    /// document being the value of it's `key` field and the score it's
+    /// collected with.
+    fn segmented_index(segments: &[&[(u64, f64)]]) -> (Searcher, Field, Field) {
+        let opts = IntOptions::default().set_fast(Cardinality::SingleValue);
+        let mut builder = Schema::builder();
+        let key = builder.add_u64_field("key", opts.clone());
+        let score = builder.add_f64_field("score", opts);
+
+        let index = Index::create_in_ram(builder.build());
+        let mut writer = index.writer_with_num_threads(1, 15_000_000).unwrap();
+        writer.set_merge_policy(Box::new(NoMergePolicy));
+        for docs in segments {
+            for (value, doc_score) in docs.iter() {
+                let mut doc = tantivy::Document::new();
+                doc.add_u64(key, *value);
+                doc.add_f64(score, *doc_score);
+                writer.add_document(doc);
+            }
+            writer.commit().unwrap();
+        }
+
+        let searcher = index.reader().unwrap().searcher();
+        assert_eq!(searcher.segment_readers().len(), segments.len());
+        (searcher, key, score)
+    }
+
+    /// Collects every document of every segment with the score stored in
+    /// it's `score` field.
+    fn collect<C: Collector>(collector: &C, searcher: &Searcher, score: Field) -> C::Fruit {
+        let mut fruits = Vec::new();
+        for (ord, reader) in searcher.segment_readers().iter().enumerate() {
+            let scores = reader.fast_fields().u64_lenient(score).unwrap();
+            let mut child = collector.for_segment(ord as u32, reader).unwrap();
+            for doc in 0..reader.max_doc() {
+                child.collect(doc, u64_to_f64(scores.get(doc)) as Score);
+            }
+            fruits.push(child.harvest());
+        }
+
+        collector.merge_fruits(fruits).unwrap()
+    }
+
+    fn group_summary(groups: &[GroupFruit]) -> Vec<(u64, usize, Vec<Score>)> {
+        groups
+            .iter()
+            .map(|group| (group.key, group.count, group.hits.iter().map(|(score, _)| *score).collect()))
+            .collect()
+    }
+
+    fn sort_keys(hits: &[(Vec<SortValue>, DocAddress)]) -> Vec<(u64, Score)> {
+        hits.iter()
+            .map(|(values, _)| match (values[0], values[1]) {
+                (SortValue::Value(key), SortValue::Score(score)) => (key, score),
+                _ => panic!("unexpected sort values"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn groups_are_merged_across_segments() {
+        let (searcher, key, score) = segmented_index(&[&[(1, 1.0), (2, 3.0)], &[(1, 5.0), (3, 2.0)]]);
+
+        let (groups, total) = collect(&GroupCollector::new(key, 10, 10), &searcher, score);
+        assert_eq!(total, 3);
+        assert_eq!(
+            group_summary(&groups),
+            vec![(1, 2, vec![5.0, 1.0]), (2, 1, vec![3.0]), (3, 1, vec![2.0])],
+        );
+    }
+
+    #[test]
+    fn groups_are_limited_after_merging() {
+        let (searcher, key, score) = segmented_index(&[&[(1, 1.0), (2, 3.0)], &[(1, 5.0), (3, 2.0)]]);
+
+        let (groups, total) = collect(&GroupCollector::new(key, 2, 10), &searcher, score);
+        assert_eq!(total, 3);
+        assert_eq!(group_summary(&groups), vec![(1, 2, vec![5.0, 1.0]), (2, 1, vec![3.0])]);
+    }
+
+    #[test]
+    fn per_group_limit_truncates_across_segments() {
+        let (searcher, key, score) = segmented_index(&[
+            &[(1, 1.0), (1, 4.0), (1, 0.5)],
+            &[(1, 3.0), (1, 2.0)],
+        ]);
+
+        let (groups, _) = collect(&GroupCollector::new(key, 10, 2), &searcher, score);
+        assert_eq!(group_summary(&groups), vec![(1, 5, vec![4.0, 3.0])]);
+    }
+
+    #[test]
+    fn tied_group_hits_keep_the_earliest_address() {
+        let (searcher, key, score) = segmented_index(&[&[(2, 1.0), (1, 1.0), (2, 1.0), (2, 1.0)]]);
+
+        let (groups, _) = collect(&GroupCollector::new(key, 10, 2), &searcher, score);
+        assert_eq!(groups.len(), 2);
+
+        // Groups tied on their best score are ordered by their best hit.
+        assert_eq!(groups[0].key, 2);
+        let addresses: Vec<DocAddress> = groups[0].hits.iter().map(|(_, address)| *address).collect();
+        assert_eq!(addresses, vec![DocAddress::new(0, 0), DocAddress::new(0, 2)]);
+        assert_eq!(groups[1].key, 1);
+    }
+
+    #[test]
+    fn multi_sort_merges_segments() {
+        let (searcher, key, score) = segmented_index(&[&[(2, 1.0), (1, 1.0)], &[(1, 3.0), (3, 0.5)]]);
+        let keys = vec![(SortField::Field(key), SortOrder::Asc), (SortField::Score, SortOrder::Desc)];
+
+        let hits = collect(&MultiSortCollector::new(keys, 10, 0), &searcher, score);
+        assert_eq!(sort_keys(&hits), vec![(1, 3.0), (1, 1.0), (2, 1.0), (3, 0.5)]);
+    }
+
+    #[test]
+    fn multi_sort_offset_applies_after_merging() {
+        // Enough documents per segment for the segments to truncate their hits.
+        let (searcher, key, score) = segmented_index(&[
+            &[(5, 1.0), (4, 1.0), (3, 1.0), (2, 2.0), (2, 1.0)],
+            &[(1, 1.0), (6, 1.0), (7, 1.0), (8, 1.0), (9, 1.0)],
+        ]);
+        let keys = vec![(SortField::Field(key), SortOrder::Asc), (SortField::Score, SortOrder::Desc)];
+
+        let hits = collect(&MultiSortCollector::new(keys, 2, 1), &searcher, score);
+        assert_eq!(sort_keys(&hits), vec![(2, 2.0), (2, 1.0)]);
+    }
+
+    #[test]
+    fn multi_sort_ties_are_ordered_by_address() {
+        let (searcher, key, score) = segmented_index(&[&[(1, 1.0), (1, 1.0)], &[(1, 1.0)]]);
+        let keys = vec![(SortField::Field(key), SortOrder::Desc), (SortField::Score, SortOrder::Desc)];
+
+        let hits = collect(&MultiSortCollector::new(keys, 10, 0), &searcher, score);
+        let addresses: Vec<DocAddress> = hits.iter().map(|(_, address)| *address).collect();
+        assert_eq!(addresses, vec![DocAddress::new(0, 0), DocAddress::new(0, 1), DocAddress::new(1, 0)]);
+    }
+
+    #[test]
+    fn doc_budget_is_shared_across_segments() {
+        let docs: &[(u64, f64)] = &[(1, 1.0); 5];
+        let (searcher, _, _) = segmented_index(&[docs, docs, docs]);
+
+        let limits = SearchLimits::new(Deadline::new(None), DocBudget::new(Some(7)));
+        let count = searcher.search(&AllQuery, &limits.wrap(Count)).unwrap();
+        assert!(count <= 7);
+        assert!(limits.budget.exhausted());
+    }
+
+    #[test]
+    fn doc_budget_is_not_exhausted_under_the_budget() {
+        let docs: &[(u64, f64)] = &[(1, 1.0); 5];
+        let (searcher, _, _) = segmented_index(&[docs, docs, docs]);
+
+        let limits = SearchLimits::new(Deadline::new(None), DocBudget::new(Some(15)));
+        let count = searcher.search(&AllQuery, &limits.wrap(Count)).unwrap();
+        assert_eq!(count, 15);
+        assert!(!limits.budget.exhausted());
+    }
+}
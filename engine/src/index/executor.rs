@@ -1,19 +1,55 @@
 use anyhow::{Result, Error};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::borrow::Borrow;
+use std::time::Duration;
 use crossbeam::queue::ArrayQueue;
 use tantivy::Executor;
 
+use crate::structures::ExecutorKind;
+
+/// The amount of times taking an executor is attempted before erroring.
+const ACQUIRE_ATTEMPTS: u32 = 4;
+
+/// The initial wait between attempts to take an executor, this doubles
+/// after every attempt.
+static ACQUIRE_BACKOFF: Duration = Duration::from_micros(250);
+
+/// A pool of tantivy executors.
+///
+/// The pool always holds `min_executors` executors with the configured
+/// amount of reader threads, under load up to `max_executors - min_executors`
+/// further searches run on single-threaded overflow executors.
+///
+/// Overflow executors do not own any threads as they execute the search
+/// on the calling thread, so they're only counted rather than pooled. The
+/// cost of the overflow capacity is the extra worker thread per overflow
+/// search the index's thread pool is created with up front.
+///
+/// The pool holds as many executors as the handler's concurrency limiter
+/// has permits, so as long as every executor is returned before it's
+/// permit is released an executor is always available to a permit holder.
 #[derive(Clone)]
 pub(super) struct ExecutorPool {
     index_name: Arc<String>,
     executors: Arc<ArrayQueue<Executor>>,
+    overflow_count: Arc<AtomicUsize>,
+    max_overflow: usize,
 }
 
 impl ExecutorPool {
-    pub(super) fn create(index_name: &str, pool_size: usize, threads_per_reader: usize) -> Result<Self> {
-        let executors = ArrayQueue::new(pool_size);
-        for i in 0..pool_size {
+    pub(super) fn create(
+        index_name: &str,
+        min_executors: usize,
+        max_executors: usize,
+        threads_per_reader: usize,
+    ) -> Result<Self> {
+        if max_executors < min_executors {
+            return Err(Error::msg("the max executors cannot be less than the min executors"));
+        }
+
+        let executors = ArrayQueue::new(min_executors);
+        for i in 0..min_executors {
             let executor = if threads_per_reader > 1 {
                 info!(
                     "[ READER {} @ {} ] executor startup, mode: multi-threaded, threads: {}",
@@ -37,7 +73,9 @@ impl ExecutorPool {
 
         Ok(Self {
             executors,
-            index_name: Arc::new(index_name.to_string())
+            index_name: Arc::new(index_name.to_string()),
+            overflow_count: Arc::new(AtomicUsize::new(0)),
+            max_overflow: max_executors - min_executors,
         })
     }
 
@@ -47,29 +85,121 @@ impl ExecutorPool {
         }
     }
 
-    pub(super) fn acquire(&self) -> Result<ExecutorHandle> {
+    /// Takes an executor from the pool.
+    pub(super) async fn acquire(&self) -> Result<ExecutorHandle> {
+        self.acquire_kind(None).await
+    }
+
+    /// Takes an executor of the given kind from the pool if one is given.
+    ///
+    /// If the pool is momentarily empty, e.g. an executor is still being
+    /// returned by a worker, this retries a bounded amount of times with a
+    /// short backoff before erroring.
+    ///
+    /// The backoff yields to the runtime rather than blocking the thread.
+    pub(super) async fn acquire_kind(&self, kind: Option<ExecutorKind>) -> Result<ExecutorHandle> {
         debug!("[ EXECUTOR-POOL @ {} ] taking executor from pool", self.index_name.as_ref());
-        if let Some(executor) = self.executors.pop() {
-            Ok(ExecutorHandle {
-                inner: Some(executor),
-                queue: self.executors.clone(),
-                index_name: self.index_name.clone(),
-            })
-        } else {
-            Err(Error::msg("no executors were available even though the max concurrent was not reached"))
+        for attempt in 0..ACQUIRE_ATTEMPTS {
+            let last_attempt = attempt + 1 == ACQUIRE_ATTEMPTS;
+            if let Some(handle) = self.try_acquire(kind, last_attempt) {
+                return Ok(handle);
+            }
+
+            if !last_attempt {
+                tokio::time::sleep(ACQUIRE_BACKOFF * (1 << attempt)).await;
+            }
         }
+
+        warn!(
+            "[ EXECUTOR-POOL @ {} ] no executors were available after {} attempts",
+            self.index_name.as_ref(),
+            ACQUIRE_ATTEMPTS,
+        );
+        Err(Error::msg("no executors were available even though the max concurrent was not reached"))
+    }
+
+    /// Takes an executor of the given kind without waiting.
+    ///
+    /// Overflow executors are single-threaded, so parallel searches only
+    /// fall back to one on the last attempt, the caller can check for this
+    /// with `ExecutorHandle::is_overflow`. Single-threaded searches take an
+    /// overflow slot first leaving the pooled executors to other searches,
+    /// either way they run on a single-threaded executor.
+    fn try_acquire(&self, kind: Option<ExecutorKind>, last_attempt: bool) -> Option<ExecutorHandle> {
+        let mut handle = match kind {
+            Some(ExecutorKind::Parallel) if !last_attempt => self.try_acquire_pooled(),
+            Some(ExecutorKind::Single) => self.try_acquire_overflow().or_else(|| self.try_acquire_pooled()),
+            _ => self.try_acquire_pooled().or_else(|| self.try_acquire_overflow()),
+        }?;
+
+        if let Some(ExecutorKind::Single) = kind {
+            handle.single_thread = Some(Executor::single_thread());
+        }
+
+        Some(handle)
+    }
+
+    fn try_acquire_pooled(&self) -> Option<ExecutorHandle> {
+        self.executors.pop().map(|executor| ExecutorHandle {
+            inner: Some(executor),
+            single_thread: None,
+            queue: Some(self.executors.clone()),
+            overflow: None,
+            index_name: self.index_name.clone(),
+        })
+    }
+
+    /// Creates a single-threaded overflow executor if the pool has not
+    /// reached it's ceiling, the slot is released once the handle is dropped.
+    fn try_acquire_overflow(&self) -> Option<ExecutorHandle> {
+        let reserved = self.overflow_count.fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| {
+            if count < self.max_overflow {
+                Some(count + 1)
+            } else {
+                None
+            }
+        });
+
+        if reserved.is_err() {
+            return None;
+        }
+
+        debug!(
+            "[ EXECUTOR-POOL @ {} ] pool exhausted, using overflow executor, mode: single-threaded (no-op)",
+            self.index_name.as_ref(),
+        );
+        Some(ExecutorHandle {
+            inner: Some(Executor::single_thread()),
+            single_thread: None,
+            queue: None,
+            overflow: Some(self.overflow_count.clone()),
+            index_name: self.index_name.clone(),
+        })
     }
 }
 
 pub(super) struct ExecutorHandle {
     index_name: Arc<String>,
     inner: Option<Executor>,
-    queue: Arc<ArrayQueue<Executor>>,
+
+    /// The executor searches run on instead of the held executor if a
+    /// single-threaded executor was requested.
+    single_thread: Option<Executor>,
+    queue: Option<Arc<ArrayQueue<Executor>>>,
+    overflow: Option<Arc<AtomicUsize>>,
+}
+
+impl ExecutorHandle {
+    /// Whether or not the executor is a single-threaded overflow executor
+    /// rather than one of the pool's configured executors.
+    pub(super) fn is_overflow(&self) -> bool {
+        self.overflow.is_some()
+    }
 }
 
 impl Borrow<Executor> for ExecutorHandle {
     fn borrow(&self) -> &Executor {
-        let ref_ = self.inner.as_ref();
+        let ref_ = self.single_thread.as_ref().or_else(|| self.inner.as_ref());
         ref_.expect("extract executor")
     }
 }
@@ -78,10 +208,80 @@ impl Drop for ExecutorHandle {
     fn drop(&mut self) {
         debug!("[ EXECUTOR-POOL @ {} ] returning executor to pool", self.index_name.as_ref());
         if let Some(inner) = self.inner.take() {
-            let maybe_err = self.queue.push(inner);
+            if let Some(overflow) = self.overflow.as_ref() {
+                overflow.fetch_sub(1, Ordering::AcqRel);
+                return;
+            }
+
+            let queue = match self.queue.as_ref() {
+                Some(queue) => queue,
+                None => return,
+            };
+
+            // This can run while unwinding from a panicked search so we must
+            // not panic again here, otherwise the process aborts.
+            let maybe_err = queue.push(inner);
             if maybe_err.is_err() {
-                panic!("failed to return executor to pool");
+                error!(
+                    "[ EXECUTOR-POOL @ {} ] failed to return executor to pool, this is a bug.",
+                    self.index_name.as_ref(),
+                );
             }
         };
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn executor_is_returned_after_a_panic() {
+        let pool = ExecutorPool::create("test", 1, 1, 1).unwrap();
+
+        for _ in 0..3 {
+            let handle = pool.try_acquire(None, true).expect("the pool should have an executor");
+            let res = std::thread::spawn(move || {
+                let _handle = handle;
+                panic!("search worker panicked");
+            })
+            .join();
+            assert!(res.is_err());
+        }
+
+        assert!(pool.try_acquire(None, true).is_some());
+    }
+
+    #[test]
+    fn overflow_executor_is_returned_after_a_panic() {
+        let pool = ExecutorPool::create("test", 0, 1, 1).unwrap();
+
+        let handle = pool.try_acquire(None, true).expect("the pool should grow an overflow executor");
+        assert!(pool.try_acquire(None, true).is_none());
+
+        let res = std::thread::spawn(move || {
+            let _handle = handle;
+            panic!("search worker panicked");
+        })
+        .join();
+        assert!(res.is_err());
+
+        assert_eq!(pool.overflow_count.load(Ordering::Relaxed), 0);
+        assert!(pool.try_acquire(None, true).is_some());
+    }
+
+    #[test]
+    fn single_threaded_executors_are_taken_from_the_pool() {
+        let pool = ExecutorPool::create("test", 1, 2, 2).unwrap();
+
+        let overflow = pool.try_acquire(Some(ExecutorKind::Single), true).expect("the pool should have an executor");
+        assert!(overflow.is_overflow());
+
+        let pooled = pool.try_acquire(Some(ExecutorKind::Single), true).expect("the pool should have an executor");
+        assert!(!pooled.is_overflow());
+        assert!(pool.try_acquire(Some(ExecutorKind::Single), true).is_none());
+
+        drop(pooled);
+        assert!(pool.try_acquire(Some(ExecutorKind::Parallel), false).is_some());
+    }
+}
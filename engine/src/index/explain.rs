@@ -0,0 +1,157 @@
+use std::collections::BTreeSet;
+
+use anyhow::Result;
+use hashbrown::{HashMap, HashSet};
+use tantivy::query::{BoostQuery, Explanation, Query, TermQuery};
+use tantivy::schema::{Field, FieldType, IndexRecordOption};
+use tantivy::{DocAddress, Score, Searcher};
+
+use crate::index::queries::{Bm25TermQuery, ConstantScoreQuery};
+use crate::structures::Bm25Params;
+
+/// Breaks the score of a hit down into the contributions of each of
+/// the query's terms.
+///
+/// Each term is explained on it's own so the contributions are keyed by
+/// `field:term`, along with the leaf components of it's BM25 score
+/// (e.g. idf and term frequency). Queries which do not expose their terms,
+/// like fuzzy queries, only report their total score.
+///
+/// The terms are scored the same way as the search scores them, with the
+/// custom BM25 parameters, the boost of their field and a constant score
+/// for the fields excluded from BM25 scoring.
+pub(super) struct ScoreExplainer {
+    query: Box<dyn Query>,
+    terms: Vec<(String, Box<dyn Query>)>,
+}
+
+impl ScoreExplainer {
+    pub(super) fn create(
+        searcher: &Searcher,
+        query: &dyn Query,
+        search_fields: &[(Field, Score)],
+        bm25: Option<Bm25Params>,
+        constant_fields: &HashSet<Field>,
+    ) -> Self {
+        let schema = searcher.schema();
+
+        let mut query_terms = BTreeSet::new();
+        query.query_terms(&mut query_terms);
+
+        let terms = query_terms
+            .into_iter()
+            .filter(|term| {
+                matches!(schema.get_field_entry(term.field()).field_type(), FieldType::Str(_))
+            })
+            .map(|term| {
+                let field = term.field();
+                let key = format!("{}:{}", schema.get_field_name(field), term.text());
+
+                let mut query: Box<dyn Query> = match bm25 {
+                    None => Box::new(TermQuery::new(term, IndexRecordOption::WithFreqs)),
+                    Some(params) => Box::new(Bm25TermQuery::new(term, params)),
+                };
+
+                if constant_fields.contains(&field) {
+                    query = Box::new(ConstantScoreQuery::new(query, 1.0));
+                }
+
+                // A boost of 0 means the field is not boosted.
+                match search_fields.iter().find(|(search_field, _)| *search_field == field) {
+                    Some((_, boost)) if *boost > 0.0f32 && *boost != 1.0f32 => {
+                        (key, Box::new(BoostQuery::new(query, *boost)) as Box<dyn Query>)
+                    },
+                    _ => (key, query),
+                }
+            })
+            .collect();
+
+        Self {
+            query: query.box_clone(),
+            terms,
+        }
+    }
+
+    /// Explains the score of the given hit.
+    pub(super) fn explain(&self, searcher: &Searcher, address: DocAddress) -> Result<HashMap<String, Score>> {
+        let mut components = HashMap::new();
+
+        let explanation = self.query.explain(searcher, address)?;
+        components.insert("_score".to_string(), explanation.value());
+
+        for (key, query) in self.terms.iter() {
+            // The term doesn't need to be in every hit e.g. with disjunctions.
+            let explanation = match query.explain(searcher, address) {
+                Ok(explanation) => explanation,
+                Err(_) => continue,
+            };
+
+            components.insert(key.clone(), explanation.value());
+            add_leaf_components(&mut components, key, &explanation)?;
+        }
+
+        Ok(components)
+    }
+}
+
+/// Explains the full score tree of only the top hits of a search,
+/// bounding the cost of explaining to a few hits.
+pub(super) struct TopHitsExplainer {
+    query: Box<dyn Query>,
+    top: usize,
+}
+
+impl TopHitsExplainer {
+    pub(super) fn create(query: &dyn Query, top: usize) -> Self {
+        Self {
+            query: query.box_clone(),
+            top,
+        }
+    }
+
+    /// Explains the hit at the given rank if it's one of the top hits.
+    pub(super) fn explain(
+        &self,
+        searcher: &Searcher,
+        rank: usize,
+        address: DocAddress,
+    ) -> Result<Option<Explanation>> {
+        if rank >= self.top {
+            return Ok(None);
+        }
+
+        Ok(Some(self.query.explain(searcher, address)?))
+    }
+}
+
+/// Adds the leaves of the explanation tree keyed by the term and the
+/// short name of the component, e.g. `body:rust idf`.
+fn add_leaf_components(
+    components: &mut HashMap<String, Score>,
+    key: &str,
+    explanation: &Explanation,
+) -> Result<()> {
+    // Explanations only expose their structure via serialization.
+    let tree = serde_json::to_value(explanation)?;
+
+    let mut stack = vec![&tree];
+    while let Some(node) = stack.pop() {
+        match node.get("details").and_then(|details| details.as_array()) {
+            Some(details) if !details.is_empty() => stack.extend(details.iter()),
+            _ => {
+                let description = node
+                    .get("description")
+                    .and_then(|description| description.as_str())
+                    .unwrap_or_default();
+                let name = description.split(',').next().unwrap_or(description).trim();
+                let value = node.get("value").and_then(|value| value.as_f64());
+
+                if let Some(value) = value {
+                    components.insert(format!("{} {}", key, name), value as Score);
+                }
+            },
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,71 @@
+use hashbrown::HashMap;
+use tantivy::{DocAddress, Score};
+
+use crate::structures::{FusionMethod, HybridOptions};
+
+/// Fuses the ranked hits of a text query and a vector query into a
+/// single ranking ordered by the fused score.
+///
+/// Both rankings are expected to be ordered by their score descending,
+/// a document missing from one of the rankings contributes nothing for
+/// that ranking.
+pub(super) fn fuse(
+    text: Vec<(Score, DocAddress)>,
+    vector: Vec<(Score, DocAddress)>,
+    options: &HybridOptions,
+) -> Vec<(Score, DocAddress)> {
+    let vector_weight = options.vector_weight;
+    let text_weight = 1.0 - vector_weight;
+
+    let mut fused: HashMap<DocAddress, Score> = HashMap::with_capacity(text.len() + vector.len());
+    match options.method {
+        FusionMethod::Rrf => {
+            let k = options.rank_constant as Score;
+            for (rank, (_, address)) in text.iter().enumerate() {
+                *fused.entry(*address).or_default() += text_weight / (k + rank as Score + 1.0);
+            }
+
+            for (rank, (_, address)) in vector.iter().enumerate() {
+                *fused.entry(*address).or_default() += vector_weight / (k + rank as Score + 1.0);
+            }
+        },
+        FusionMethod::Linear => {
+            // The text and vector scores have very different scales so
+            // each is normalized to 0..1 before being combined.
+            for (score, address) in normalize(&text) {
+                *fused.entry(address).or_default() += text_weight * score;
+            }
+
+            for (score, address) in normalize(&vector) {
+                *fused.entry(address).or_default() += vector_weight * score;
+            }
+        },
+    }
+
+    let mut fused: Vec<(Score, DocAddress)> = fused
+        .into_iter()
+        .map(|(address, score)| (score, address))
+        .collect();
+
+    fused.sort_by(|a, b| {
+        b.0.partial_cmp(&a.0)
+            .unwrap_or(std::cmp::Ordering::Equal)
+            .then(a.1.cmp(&b.1))
+    });
+
+    fused
+}
+
+/// Min-max normalizes the scores of the ranking to 0..1.
+///
+/// If every score is the same they're all normalized to 1.
+fn normalize(ranking: &[(Score, DocAddress)]) -> impl Iterator<Item = (Score, DocAddress)> + '_ {
+    let min = ranking.iter().map(|(score, _)| *score).fold(Score::INFINITY, Score::min);
+    let max = ranking.iter().map(|(score, _)| *score).fold(Score::NEG_INFINITY, Score::max);
+    let range = max - min;
+
+    ranking.iter().map(move |(score, address)| {
+        let normalized = if range > 0.0 { (score - min) / range } else { 1.0 };
+        (normalized, *address)
+    })
+}
@@ -0,0 +1,119 @@
+use hashbrown::HashSet;
+use serde::Serialize;
+use tantivy::schema::{Field, FieldType, Value};
+use tantivy::tokenizer::TextAnalyzer;
+use tantivy::{Document, Index, Score};
+
+/// The closest document term to a given query term.
+#[derive(Serialize)]
+pub struct FuzzyTermMatch {
+    /// The term of the query.
+    term: String,
+
+    /// The closest term of the document if any.
+    matched: Option<String>,
+
+    /// The edit distance between the query term and the matched term.
+    distance: Option<usize>,
+}
+
+/// Finds the closest document terms to each query term of a fuzzy query.
+///
+/// This re-tokenizes the stored values of the search fields, any search
+/// fields which are not stored text fields are ignored.
+pub(super) struct FuzzyMatcher {
+    terms: Vec<String>,
+    fields: Vec<(Field, TextAnalyzer)>,
+}
+
+impl FuzzyMatcher {
+    /// Creates a new matcher splitting the query the same way as
+    /// the fuzzy query system.
+    pub(super) fn create(index: &Index, search_fields: &[(Field, Score)], query: &str) -> Self {
+        let terms = query
+            .to_lowercase()
+            .split_whitespace()
+            .map(|term| term.to_string())
+            .collect();
+
+        let schema = index.schema();
+        let mut fields = Vec::with_capacity(search_fields.len());
+        for (field, _) in search_fields {
+            let entry = schema.get_field_entry(*field);
+            if !(entry.is_stored() && matches!(entry.field_type(), FieldType::Str(_))) {
+                continue;
+            }
+
+            if let Ok(analyzer) = index.tokenizer_for_field(*field) {
+                fields.push((*field, analyzer));
+            }
+        }
+
+        Self { terms, fields }
+    }
+
+    /// Gets the closest matched term for each query term in the document.
+    pub(super) fn match_doc(&self, doc: &Document) -> Vec<FuzzyTermMatch> {
+        let mut doc_terms = HashSet::new();
+        for (field, analyzer) in self.fields.iter() {
+            for value in doc.get_all(*field) {
+                if let Value::Str(text) = value {
+                    let mut stream = analyzer.token_stream(text);
+                    while stream.advance() {
+                        doc_terms.insert(stream.token().text.clone());
+                    }
+                }
+            }
+        }
+
+        self.terms
+            .iter()
+            .map(|term| {
+                let closest = doc_terms
+                    .iter()
+                    .map(|candidate| (candidate, prefix_edit_distance(term, candidate)))
+                    .min_by_key(|(candidate, distance)| (*distance, candidate.len()));
+
+                FuzzyTermMatch {
+                    term: term.clone(),
+                    matched: closest.map(|(candidate, _)| candidate.clone()),
+                    distance: closest.map(|(_, distance)| distance),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Calculates the smallest edit distance between the query term and any
+/// prefix of the candidate, counting transpositions as a single edit.
+///
+/// This mirrors the prefix fuzzy term queries used by the fuzzy system.
+fn prefix_edit_distance(term: &str, candidate: &str) -> usize {
+    let a: Vec<char> = term.chars().collect();
+    let b: Vec<char> = candidate.chars().collect();
+
+    let mut rows = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for (i, row) in rows.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in rows[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            let mut distance = (rows[i - 1][j] + 1)
+                .min(rows[i][j - 1] + 1)
+                .min(rows[i - 1][j - 1] + cost);
+
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                distance = distance.min(rows[i - 2][j - 2] + 1);
+            }
+
+            rows[i][j] = distance;
+        }
+    }
+
+    rows[a.len()].iter().copied().min().unwrap_or(a.len())
+}
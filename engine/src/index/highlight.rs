@@ -0,0 +1,485 @@
+use std::collections::BTreeSet;
+
+use anyhow::{Error, Result};
+use hashbrown::{HashMap, HashSet};
+use serde::Serialize;
+use tantivy::query::Query;
+use tantivy::schema::{Field, FieldType, Value};
+use tantivy::tokenizer::TextAnalyzer;
+use tantivy::{Document, Score, Searcher, Term};
+
+use crate::structures::HighlightOptions;
+
+/// A token matching one of the query terms.
+struct Match {
+    from: usize,
+    to: usize,
+    term: String,
+    weight: Score,
+}
+
+/// The location of a matched term within a field.
+#[derive(Serialize)]
+pub struct MatchPosition {
+    /// The index of the field value containing the match.
+    value: usize,
+
+    /// The character offset the match starts at.
+    start: usize,
+
+    /// The character offset the match ends at (exclusive).
+    end: usize,
+}
+
+/// A field to generate highlighted fragments for.
+struct HighlightField {
+    name: String,
+    field: Field,
+    analyzer: TextAnalyzer,
+
+    /// The query terms to highlight and their weight.
+    terms: HashMap<String, Score>,
+
+    /// Whether or not the field is part of the returned document, in
+    /// which case a snippet of it is redundant.
+    returned: bool,
+
+    /// Whether or not snippets are generated for the field, fields which
+    /// are only returned are used to check where the document matched.
+    snippet: bool,
+}
+
+/// Generates highlighted fragments of stored text fields for a given query.
+///
+/// Fragments are centered on the matched terms and are selected by the
+/// sum of the weights of the distinct terms they contain, each term's
+/// weight is it's inverse document frequency for the given field.
+pub(super) struct Highlighter {
+    fields: Vec<HighlightField>,
+    max_num_chars: usize,
+    num_fragments: usize,
+    fragment_separator: String,
+    fallback_to_prefix: bool,
+    skip_returned: bool,
+}
+
+impl Highlighter {
+    /// Creates a new highlighter for the given query.
+    ///
+    /// As fuzzy queries do not expose their terms, the raw query string
+    /// is also tokenized and it's tokens highlighted.
+    ///
+    /// The returned fields are the fields projected into the hits if the
+    /// hits are projected, these are only used if returned matches are skipped.
+    pub(super) fn create(
+        searcher: &Searcher,
+        query: &dyn Query,
+        query_string: Option<&str>,
+        options: HighlightOptions,
+        returned_fields: Option<&HashSet<String>>,
+    ) -> Result<Self> {
+        let schema = searcher.schema();
+
+        let mut query_terms = BTreeSet::new();
+        query.query_terms(&mut query_terms);
+
+        let returned_fields = returned_fields.filter(|_| options.skip_returned);
+        let is_returned = |name: &str| returned_fields.map(|fields| fields.contains(name)).unwrap_or(false);
+
+        let mut fields = Vec::with_capacity(options.fields.len());
+        for name in options.fields {
+            let field = schema
+                .get_field(&name)
+                .ok_or_else(|| Error::msg(format!("highlight field {:?} does not exist", &name)))?;
+
+            let entry = schema.get_field_entry(field);
+            if !(entry.is_stored() && matches!(entry.field_type(), FieldType::Str(_))) {
+                return Err(Error::msg(format!(
+                    "highlight field {:?} must be a stored text field",
+                    &name
+                )));
+            }
+
+            let returned = is_returned(&name);
+            fields.push(highlight_field(searcher, &query_terms, query_string, name, field, returned, true)?);
+        }
+
+        // The returned text fields which aren't highlighted are still checked
+        // for matches, a match within them makes every snippet redundant.
+        for name in returned_fields.into_iter().flatten() {
+            if fields.iter().any(|field| &field.name == name) {
+                continue;
+            }
+
+            let field = match schema.get_field(name) {
+                Some(field) => field,
+                None => continue,
+            };
+
+            let entry = schema.get_field_entry(field);
+            if entry.is_stored() && matches!(entry.field_type(), FieldType::Str(_)) {
+                fields.push(highlight_field(searcher, &query_terms, query_string, name.clone(), field, true, false)?);
+            }
+        }
+
+        Ok(Self {
+            fields,
+            max_num_chars: options.max_num_chars,
+            num_fragments: options.num_fragments.max(1),
+            fragment_separator: options.fragment_separator,
+            fallback_to_prefix: options.fallback_to_prefix,
+            skip_returned: returned_fields.is_some(),
+        })
+    }
+
+    /// Generates the highlighted fragments for each of the highlight fields
+    /// of a given document.
+    ///
+    /// Fields without any matches are omitted unless falling back to
+    /// the prefix of the stored value is enabled.
+    ///
+    /// For multi-valued fields every value is considered and the fragments
+    /// are taken from whichever value contains the best match.
+    ///
+    /// If returned matches are skipped no snippets are generated when the
+    /// document matched within one of the returned fields, as the match is
+    /// already visible in the hit.
+    pub(super) fn highlight(&self, doc: &Document) -> HashMap<String, String> {
+        let mut highlights = HashMap::with_capacity(self.fields.len());
+
+        if self.skip_returned
+            && self
+                .fields
+                .iter()
+                .any(|field| field.returned && has_match(field, doc))
+        {
+            return highlights;
+        }
+
+        for field in self.fields.iter() {
+            if !field.snippet || field.returned {
+                continue;
+            }
+
+            let texts = doc.get_all(field.field).filter_map(|value| match value {
+                Value::Str(text) => Some(text.as_str()),
+                _ => None,
+            });
+
+            let mut first = None;
+            let mut best: Option<(Score, Vec<String>)> = None;
+            for text in texts {
+                if first.is_none() && !text.is_empty() {
+                    first = Some(text);
+                }
+
+                let (score, fragments) = match self.fragments(field, text) {
+                    Some(found) => found,
+                    None => continue,
+                };
+
+                // The earlier value is kept when they score the same.
+                if best.as_ref().map(|(best, _)| score > *best).unwrap_or(true) {
+                    best = Some((score, fragments));
+                }
+            }
+
+            match (best, first) {
+                (Some((_, fragments)), _) => {
+                    highlights.insert(field.name.clone(), fragments.join(&self.fragment_separator));
+                },
+                (None, Some(text)) if self.fallback_to_prefix => {
+                    highlights.insert(field.name.clone(), escape_html(self.prefix(text)));
+                },
+                _ => {},
+            }
+        }
+
+        highlights
+    }
+
+    /// Gets the character offsets of every matched term for each of the
+    /// highlight fields of a given document.
+    ///
+    /// Fields without any matches are omitted.
+    pub(super) fn match_positions(&self, doc: &Document) -> HashMap<String, Vec<MatchPosition>> {
+        let mut positions = HashMap::with_capacity(self.fields.len());
+
+        for field in self.fields.iter() {
+            let mut field_positions = vec![];
+            let texts = doc.get_all(field.field).filter_map(|value| match value {
+                Value::Str(text) => Some(text),
+                _ => None,
+            });
+
+            for (value, text) in texts.enumerate() {
+                // Tokens are emitted in order so the byte offsets can be
+                // converted to character offsets incrementally.
+                let mut last_byte = 0;
+                let mut last_char = 0;
+                let mut to_char = |byte: usize| {
+                    if byte >= last_byte {
+                        last_char += text[last_byte..byte].chars().count();
+                    } else {
+                        last_char = text[..byte].chars().count();
+                    }
+                    last_byte = byte;
+                    last_char
+                };
+
+                field.analyzer.token_stream(text).process(&mut |token| {
+                    if field.terms.contains_key(&token.text) {
+                        let start = to_char(token.offset_from);
+                        let end = to_char(token.offset_to);
+                        field_positions.push(MatchPosition { value, start, end });
+                    }
+                });
+            }
+
+            if !field_positions.is_empty() {
+                positions.insert(field.name.clone(), field_positions);
+            }
+        }
+
+        positions
+    }
+
+    /// Selects and renders the best scoring fragments of the text along
+    /// with the score of the best fragment, or `None` if nothing matched.
+    fn fragments(&self, field: &HighlightField, text: &str) -> Option<(Score, Vec<String>)> {
+        let mut tokens = vec![];
+        let mut matches = vec![];
+        field.analyzer.token_stream(text).process(&mut |token| {
+            tokens.push((token.offset_from, token.offset_to));
+
+            if let Some(weight) = field.terms.get(&token.text) {
+                matches.push(Match {
+                    from: token.offset_from,
+                    to: token.offset_to,
+                    term: token.text.clone(),
+                    weight: *weight,
+                });
+            }
+        });
+
+        if matches.is_empty() {
+            return None;
+        }
+
+        // Every match produces a candidate window centered on itself, this
+        // makes sure a match deep into a long document still gets a fragment
+        // rather than just the start of the document.
+        let mut candidates: Vec<(usize, usize, Score)> = matches
+            .iter()
+            .map(|m| {
+                let (start, end) = self.window_around(text, &tokens, m);
+                (start, end, score_window(&matches, start, end))
+            })
+            .collect();
+
+        candidates.sort_by(|a, b| {
+            b.2.partial_cmp(&a.2)
+                .unwrap_or(std::cmp::Ordering::Equal)
+                .then(a.0.cmp(&b.0))
+        });
+
+        let best_score = candidates[0].2;
+        let mut selected: Vec<(usize, usize)> = vec![];
+        for (start, end, _) in candidates {
+            if selected.len() >= self.num_fragments {
+                break;
+            }
+
+            let overlaps = selected
+                .iter()
+                .any(|(s_start, s_end)| start < *s_end && *s_start < end);
+
+            if !overlaps {
+                selected.push((start, end));
+            }
+        }
+
+        selected.sort_unstable();
+        let fragments = selected
+            .into_iter()
+            .map(|(start, end)| render_fragment(text, &matches, start, end))
+            .collect();
+
+        Some((best_score, fragments))
+    }
+
+    /// Gets the start of the text truncated to at most `max_num_chars`,
+    /// snapped back to the last whitespace so words are not cut in half.
+    fn prefix<'a>(&self, text: &'a str) -> &'a str {
+        if text.len() <= self.max_num_chars {
+            return text;
+        }
+
+        let mut end = self.max_num_chars;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        match text[..end].rfind(char::is_whitespace) {
+            Some(boundary) if boundary > 0 => text[..boundary].trim_end(),
+            _ => &text[..end],
+        }
+    }
+
+    /// Produces a window of at most `max_num_chars` centered on the given
+    /// match, snapped inwards to the nearest token boundaries.
+    fn window_around(&self, text: &str, tokens: &[(usize, usize)], m: &Match) -> (usize, usize) {
+        let center = (m.from + m.to) / 2;
+        let end = (center.saturating_sub(self.max_num_chars / 2) + self.max_num_chars).min(text.len());
+        let start = end.saturating_sub(self.max_num_chars);
+
+        let start = tokens
+            .iter()
+            .map(|(from, _)| *from)
+            .find(|from| *from >= start)
+            .unwrap_or(m.from)
+            .min(m.from);
+
+        let end = tokens
+            .iter()
+            .rev()
+            .map(|(_, to)| *to)
+            .find(|to| *to <= end)
+            .unwrap_or(m.to)
+            .max(m.to);
+
+        (start, end)
+    }
+}
+
+/// Sums the weights of the distinct terms matched within the window.
+fn score_window(matches: &[Match], start: usize, end: usize) -> Score {
+    let mut seen = BTreeSet::new();
+    matches
+        .iter()
+        .filter(|m| m.from >= start && m.to <= end)
+        .filter(|m| seen.insert(m.term.as_str()))
+        .map(|m| m.weight)
+        .sum()
+}
+
+/// Renders the fragment as HTML wrapping any matches in `<b>` tags.
+fn render_fragment(text: &str, matches: &[Match], start: usize, end: usize) -> String {
+    let mut html = String::new();
+    let mut cursor = start;
+
+    for m in matches.iter().filter(|m| m.from >= start && m.to <= end) {
+        if m.from < cursor {
+            continue;
+        }
+
+        html.push_str(&escape_html(&text[cursor..m.from]));
+        html.push_str("<b>");
+        html.push_str(&escape_html(&text[m.from..m.to]));
+        html.push_str("</b>");
+        cursor = m.to;
+    }
+
+    html.push_str(&escape_html(&text[cursor..end]));
+    html
+}
+
+/// Checks whether any value of the field contains one of the query terms.
+fn has_match(field: &HighlightField, doc: &Document) -> bool {
+    doc.get_all(field.field).any(|value| {
+        let text = match value {
+            Value::Str(text) => text,
+            _ => return false,
+        };
+
+        let mut found = false;
+        field.analyzer.token_stream(text).process(&mut |token| {
+            found |= field.terms.contains_key(&token.text);
+        });
+        found
+    })
+}
+
+/// Builds a highlight field resolving the weight of each query term
+/// within the field.
+fn highlight_field(
+    searcher: &Searcher,
+    query_terms: &BTreeSet<Term>,
+    query_string: Option<&str>,
+    name: String,
+    field: Field,
+    returned: bool,
+    snippet: bool,
+) -> Result<HighlightField> {
+    let schema = searcher.schema();
+    let entry = schema.get_field_entry(field);
+
+    // Stored only fields (like fast-fuzzy text fields) have no
+    // analyzer of their own so we fall back to the default one.
+    let analyzer = match searcher.index().tokenizer_for_field(field) {
+        Ok(analyzer) => analyzer,
+        Err(_) => searcher
+            .index()
+            .tokenizers()
+            .get("default")
+            .ok_or_else(|| Error::msg("default tokenizer is not registered"))?,
+    };
+
+    let mut texts: BTreeSet<String> = query_terms
+        .iter()
+        .filter(|term| {
+            matches!(schema.get_field_entry(term.field()).field_type(), FieldType::Str(_))
+        })
+        .map(|term| term.text().to_string())
+        .collect();
+
+    if let Some(query_string) = query_string {
+        analyzer
+            .token_stream(query_string)
+            .process(&mut |token| {
+                texts.insert(token.text.clone());
+            });
+    }
+
+    let mut terms = HashMap::with_capacity(texts.len());
+    for text in texts {
+        let weight = if entry.is_indexed() {
+            let doc_freq = searcher.doc_freq(&Term::from_field_text(field, &text))?;
+            if doc_freq == 0 {
+                continue;
+            }
+
+            let num_docs = searcher.num_docs() as f32;
+            1.0 + (num_docs / (doc_freq as f32 + 1.0)).ln().max(0.0)
+        } else {
+            1.0
+        };
+
+        terms.insert(text, weight);
+    }
+
+    Ok(HighlightField {
+        name,
+        field,
+        analyzer,
+        terms,
+        returned,
+        snippet,
+    })
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#x27;"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
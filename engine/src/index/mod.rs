@@ -1,23 +1,37 @@
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::{Error, Result};
+use bytes::Bytes;
+use futures_util::Stream;
 use parking_lot::Mutex;
 use tantivy::directory::MmapDirectory;
-use tantivy::query::QueryParser;
-use tantivy::schema::{Schema, Value, FieldType};
-use tantivy::{Document, Index, IndexBuilder, ReloadPolicy, Term};
+use tantivy::query::{Query, QueryParser};
+use tantivy::schema::{Field, NamedFieldDocument, Schema, Value};
+use tantivy::{Document, Index, IndexBuilder, Score, Term};
 use tokio::fs;
 use tokio::task::JoinHandle;
 
 use crate::correction;
 use crate::helpers::{self, hash};
 use crate::index::reader::QueryHit;
-use crate::structures::{self, IndexStorageType, LoadedIndex, QueryPayload, DocumentValue};
-use chrono::Utc;
+use crate::preprocess::QueryPreprocessor;
+use crate::query_log::QueryLogger;
+use crate::structures::{self, IndexStorageType, LoadedIndex, QueryPayload, DocumentValue, ReloadMode};
+use hashbrown::HashMap;
 
 pub(super) mod reader;
 pub(super) mod writer;
+pub(super) mod collectors;
 pub(super) mod executor;
+pub(super) mod explain;
+pub(super) mod fusion;
+pub(super) mod fuzzy_matches;
+pub(super) mod highlight;
+pub(super) mod queries;
+pub(super) mod refine;
+pub(super) mod term_vectors;
+pub(super) mod vector;
 
 static INDEX_DATA_PATH: &str = "./lnx/index-data";
 
@@ -58,8 +72,17 @@ pub struct IndexHandler {
     /// The set of fields which are indexed.
     indexed_text_fields: Vec<String>,
 
+    /// The set of decimal fields and their scale.
+    decimal_fields: Arc<HashMap<String, u32>>,
+
+    /// The set of vector fields and their dimensions.
+    vector_fields: Arc<HashMap<String, usize>>,
+
     /// Whether or not to use fast fuzzy searching.
     use_fast_fuzzy: bool,
+
+    /// The name of the private field holding each document's id.
+    id_field: String,
 }
 
 impl IndexHandler {
@@ -114,6 +137,9 @@ impl IndexHandler {
     /// this system is being deployed on hence being a required field.
     /// The amount of threads spawned is equal the the (`max_concurrency` * `reader_threads`) + `1`
     /// as well as the tokio runtime threads.
+    ///
+    /// Setting `overflow_concurrency` adds a further `overflow_concurrency` worker threads
+    /// to the index thread pool, the overflow executors themselves spawn no threads.
     pub(crate) async fn build_loaded(loader: LoadedIndex) -> Result<Self> {
         let (index, dir) = Self::get_index_from_loader(&loader).await?;
         let schema_copy = index.schema();
@@ -142,6 +168,8 @@ impl IndexHandler {
                     };
                 },
                 (Some(field), None) => {
+                    raw_search_fields.push(field);
+
                     if let Some(boost) = loader.boost_fields.get(&ref_field) {
                         debug!("boosting field for query parser {} {}", &ref_field, boost);
                         search_fields.push((field, *boost));
@@ -164,13 +192,32 @@ impl IndexHandler {
             };
         }
 
+        // A field which isn't indexed can never match and the query parser
+        // rejects every query with it, this is most likely a misconfigured
+        // schema so it's dropped rather than failing every `Normal` query.
+        raw_search_fields.retain(|field| {
+            let entry = schema_copy.get_field_entry(*field);
+            if !entry.is_indexed() {
+                warn!(
+                    "[ SETUP @ {} ] search field {:?} is not indexed and will be ignored",
+                    &loader.name,
+                    entry.name(),
+                );
+            }
+
+            entry.is_indexed()
+        });
+        search_fields.retain(|(field, _)| raw_search_fields.contains(field));
+
         let mut parser = QueryParser::for_index(&index, raw_search_fields);
         if loader.set_conjunction_by_default {
             parser.set_conjunction_by_default();
         }
 
+        // The boosts are registered with the parser so `Normal` queries are
+        // weighted the same as the fuzzy query modes, a boost of 0 is unset.
         for (field, boost) in search_fields.iter() {
-            if *boost != 0.0f32 {
+            if *boost > 0.0f32 {
                 parser.set_field_boost(*field, *boost);
             }
         }
@@ -181,15 +228,8 @@ impl IndexHandler {
             &loader.name ,loader.writer_threads, loader.writer_buffer
         );
 
-        let reader = index
-            .reader_builder()
-            .num_searchers(loader.max_concurrency as usize)
-            .reload_policy(ReloadPolicy::OnCommit)
-            .try_into()?;
-        info!(
-            "[ READER @ {} ] index reader has been allocated with {} searchers",
-            &loader.name, loader.max_concurrency
-        );
+        let decimal_fields = Arc::new(loader.decimal_fields);
+        let vector_fields = Arc::new(loader.vector_fields);
 
         let (sender, receiver) = async_channel::bounded(1);
         let worker_handler =
@@ -198,13 +238,22 @@ impl IndexHandler {
         let reader_handler = reader::IndexReaderHandler::create(
             loader.name.clone(),
             loader.max_concurrency as usize,
-            reader,
+            loader.overflow_concurrency as usize,
+            &index,
+            loader.reload_policy,
             loader.reader_threads as usize,
             parser,
             loader.fuzzy_search_fields,
             schema_copy,
             loader.use_fast_fuzzy,
             loader.strip_stop_words,
+            decimal_fields.clone(),
+            vector_fields.clone(),
+            loader.max_fuzzy_terms,
+            loader.id_field.clone(),
+            &loader.indexed_text_fields,
+            loader.bm25,
+            loader.skip_malformed_docs,
         )?;
 
         Ok(Self {
@@ -216,7 +265,10 @@ impl IndexHandler {
             alive: receiver,
             dir,
             indexed_text_fields: loader.indexed_text_fields,
+            decimal_fields,
+            vector_fields,
             use_fast_fuzzy: loader.use_fast_fuzzy,
+            id_field: loader.id_field,
         })
     }
 
@@ -230,6 +282,12 @@ impl IndexHandler {
         &self.indexed_text_fields
     }
 
+    /// Gets what each public field of the schema can be used for,
+    /// e.g. whether it can be searched or sorted by.
+    pub fn field_capabilities(&self) -> HashMap<String, reader::FieldCaps> {
+        self.reader.field_capabilities()
+    }
+
     /// Builds a `Term` from a given field and value.
     ///
     /// This assumes that the value type matches up with the field type.
@@ -241,48 +299,45 @@ impl IndexHandler {
             .unwrap_or_else(|| Err(Error::msg("unknown field")))?;
 
         let entry = self.schema.get_field_entry(field);
-        let field_type = entry.field_type();
-
-        let term = match (value, field_type) {
-            (DocumentValue::I64(v), FieldType::I64(_)) => Term::from_field_i64(field, v),
-            (DocumentValue::U64(v), FieldType::U64(_)) => Term::from_field_u64(field, v),
-            (DocumentValue::F64(v), FieldType::F64(_)) => Term::from_field_f64(field, v),
-            (DocumentValue::Text(v), FieldType::Str(_)) => Term::from_field_text(field, &v),
-            (DocumentValue::Datetime(v), FieldType::Str(_)) => Term::from_field_text(field, &v.to_string()),
-            (DocumentValue::Datetime(v), FieldType::Date(_)) => Term::from_field_date(field, &v),
-            (DocumentValue::I64(v), FieldType::Date(_)) => {
-                match chrono::NaiveDateTime::from_timestamp_opt(v, 0) {
-                    Some(dt) => {
-                        let dt = chrono::DateTime::from_utc(dt, Utc);
-                        Term::from_field_date(field, &dt)
-                    },
-                    None =>
-                        return Err(Error::msg(format!("filed {:?} is type {:?} in schema but did not get a valid value (invalid timestamp)", &field, field_type))),
-                }
-            },
-            (DocumentValue::U64(v), FieldType::Date(_)) => {
-                match chrono::NaiveDateTime::from_timestamp_opt(v as i64, 0) {
-                    Some(dt) => {
-                        let dt = chrono::DateTime::from_utc(dt, Utc);
-                        Term::from_field_date(field, &dt)
-                    },
-                    None =>
-                        return Err(Error::msg(format!("filed {:?} is type {:?} in schema but did not get a valid value (invalid timestamp)", &field, field_type))),
-                }
-            },
-            _ => return Err(Error::msg(format!("filed {:?} is type {:?} in schema but did not get a valid value", &field, field_type)))
-        };
+        let scale = self.decimal_fields.get(entry.name()).copied();
 
-        Ok(term)
+        structures::value_to_term(field, entry.field_type(), scale, value)
     }
 
     /// Gets a document with a given document address.
     ///
     /// This uses a concurrency permit while completing the operation.
     pub async fn get_doc(&self, doc_address: u64) -> Result<QueryHit> {
-        let mut doc = self.reader.get_doc(doc_address).await?;
+        let doc = self.reader.get_doc(doc_address).await?;
+        self.to_hit(doc)
+    }
 
-        let id = doc.0.remove("_id").ok_or_else(|| {
+    /// Gets the single document whose given field exactly matches the value.
+    ///
+    /// This errors if no document or more than one document matches,
+    /// making it suitable for looking up documents by a natural key.
+    ///
+    /// This uses a concurrency permit while completing the operation.
+    pub async fn get_doc_by(&self, field: &str, value: &str) -> Result<QueryHit> {
+        let doc = self.reader.get_doc_by(field, value).await?;
+        self.to_hit(doc)
+    }
+
+    /// Gets the documents with the given ids in the same order as the ids,
+    /// any ids which do not exist are `None`.
+    ///
+    /// This uses a concurrency permit while completing the operation.
+    pub async fn get_docs(&self, ids: &[u64]) -> Result<Vec<Option<QueryHit>>> {
+        let docs = self.reader.get_docs(ids).await?;
+
+        docs.into_iter()
+            .map(|doc| doc.map(|doc| self.to_hit(doc)).transpose())
+            .collect()
+    }
+
+    /// Converts a retrieved document into a hit labeled with it's id.
+    fn to_hit(&self, mut doc: NamedFieldDocument) -> Result<QueryHit> {
+        let id = doc.0.remove(&self.id_field).ok_or_else(|| {
             Error::msg(
                 "document has been missed labeled (missing identifier tag), the dataset is invalid",
             )
@@ -293,6 +348,13 @@ impl IndexHandler {
                 document_id: format!("{}", v),
                 doc,
                 ratio: serde_json::json!(100.0),
+                highlights: None,
+                positions: None,
+                fuzzy_matches: None,
+                score_components: None,
+                explanation: None,
+                term_vectors: None,
+                inner_hits: None,
             })
         } else {
             Err(Error::msg(
@@ -301,9 +363,45 @@ impl IndexHandler {
         }
     }
 
+    /// Changes when the index reader picks up newly committed documents.
+    ///
+    /// This allows switching to manual reloads during bulk indexing
+    /// and back to reloading on commit afterwards.
+    pub fn set_reload_policy(&self, policy: ReloadMode) -> Result<()> {
+        self.reader.set_reload_policy(policy)
+    }
+
+    /// Reloads the index reader so searches see the latest commit,
+    /// returning the generation searches will report from now on.
+    ///
+    /// This is only required with the `Manual` reload policy.
+    pub fn reload(&self) -> Result<u64> {
+        self.reader.reload()
+    }
+
+    /// Spawns a background task reloading the index reader every `interval`,
+    /// warming newly committed segments before searches see them.
+    ///
+    /// The task stops once the index is shut down.
+    pub fn spawn_refresh(&self, interval: Duration) -> JoinHandle<()> {
+        self.reader.spawn_refresh(interval)
+    }
+
+    /// Gets the generation of the documents currently visible to searches,
+    /// see `QueryResults::generation`.
+    pub fn generation(&self) -> u64 {
+        self.reader.generation()
+    }
+
+    /// Checks which of the given document ids exist in the index,
+    /// returning a flag for each id in the same order.
+    pub async fn exists(&self, ids: &[u64]) -> Result<Vec<bool>> {
+        self.reader.exists(ids).await
+    }
+
     /// Submits a document to be processed by the index writer.
     pub async fn add_document(&self, mut document: structures::Document) -> Result<()> {
-        let field = self.schema.get_field("_id").ok_or_else(|| {
+        let field = self.schema.get_field(&self.id_field).ok_or_else(|| {
             Error::msg(
                 "system has not correctly initialised this schema,\
                  are you upgrading from a older version? If yes, you need to re-create the schema.",
@@ -314,7 +412,7 @@ impl IndexHandler {
             helpers::correct_doc_fields(&mut document, self.indexed_fields());
         }
 
-        let mut doc = document.parse_into_document(&self.schema)?;
+        let mut doc = document.parse_into_document(&self.schema, &self.decimal_fields, &self.vector_fields)?;
 
         let id = uuid::Uuid::new_v4();
         doc.add_u64(field, hash(&id));
@@ -332,7 +430,7 @@ impl IndexHandler {
     ///
     /// If fast fuzzy is not enabled however, this just calls add_docs in a loop.
     pub async fn add_many_documents(&self, documents: Vec<structures::Document>) -> Result<()> {
-        let field = self.schema.get_field("_id").ok_or_else(|| {
+        let field = self.schema.get_field(&self.id_field).ok_or_else(|| {
             Error::msg(
                 "system has not correctly initialised this schema,\
                  are you upgrading from a older version? If yes, you need to re-create the schema.",
@@ -355,18 +453,22 @@ impl IndexHandler {
         );
 
         let fields = Arc::new(self.indexed_fields().clone());
+        let decimal_fields = self.decimal_fields.clone();
+        let vector_fields = self.vector_fields.clone();
         let schema = self.schema.clone();
         let (tx, rx) = crossbeam::channel::unbounded();
         let handles: Vec<JoinHandle<Result<Vec<Document>>>> = (0..num_cpus::get())
             .map(|_| {
                 let fields = fields.clone();
+                let decimal_fields = decimal_fields.clone();
+                let vector_fields = vector_fields.clone();
                 let schema = schema.clone();
                 let receiver = rx.clone();
                 tokio::task::spawn_blocking(move || -> Result<Vec<Document>> {
                     let mut processed_documents = vec![];
                     while let Ok(mut doc) = receiver.recv() {
                         helpers::correct_doc_fields(&mut doc, fields.as_ref());
-                        let doc = doc.parse_into_document(&schema)?;
+                        let doc = doc.parse_into_document(&schema, &decimal_fields, &vector_fields)?;
                         processed_documents.push(doc);
                     }
 
@@ -432,11 +534,138 @@ impl IndexHandler {
         self.writer.send_op(writer::WriterOp::Rollback).await
     }
 
+    /// Sets the sink which every completed search on this index
+    /// is reported to.
+    pub fn set_query_logger(&self, logger: Arc<dyn QueryLogger>) {
+        self.reader.set_query_logger(logger)
+    }
+
+    /// Sets the preprocessor every search payload on this index is
+    /// passed through before it's parsed, see `QueryPreprocessor`.
+    pub fn set_query_preprocessor(&self, preprocessor: Arc<dyn QueryPreprocessor>) {
+        self.reader.set_query_preprocessor(preprocessor)
+    }
+
+    /// Registers a custom query builder which can be used by
+    /// setting `custom_query` to the given name in a search.
+    ///
+    /// The builder is given the index, the weighted search fields and
+    /// the query string if one was given.
+    pub fn register_query<F>(&self, name: &str, builder: F)
+    where
+        F: Fn(&Index, &[(Field, Score)], Option<&str>) -> Result<Box<dyn Query>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.reader.register_query(name.to_string(), Arc::new(builder))
+    }
+
     /// Searches the index with the given query.
     pub async fn search(&self, payload: QueryPayload) -> Result<reader::QueryResults> {
         self.reader.search(payload).await
     }
 
+    /// Searches the index with the given query, returning `None` without
+    /// waiting if the index is already at its concurrency limit.
+    ///
+    /// This allows callers to shed load rather than queueing searches.
+    pub async fn try_search(&self, payload: QueryPayload) -> Result<Option<reader::QueryResults>> {
+        self.reader.try_search(payload).await
+    }
+
+    /// Takes a point in time view of the index which can later be
+    /// compared against the current results with `diff`.
+    ///
+    /// The snapshot can also be searched and have documents fetched from
+    /// it, so documents returned by a search can be fetched even if they've
+    /// since been deleted.
+    ///
+    /// Each snapshot pins one of the reader's searchers, only a limited
+    /// amount of snapshots can be alive at once and taking a snapshot
+    /// errors once that limit has been reached.
+    pub fn snapshot(&self) -> Result<reader::SearcherSnapshot> {
+        self.reader.take_snapshot()
+    }
+
+    /// Searches the given snapshot of the index rather than the
+    /// latest searcher.
+    pub async fn search_snapshot(
+        &self,
+        payload: QueryPayload,
+        snapshot: &reader::SearcherSnapshot,
+    ) -> Result<reader::QueryResults> {
+        self.reader.search_snapshot(payload, snapshot.clone()).await
+    }
+
+    /// Gets a document with a given document address from the given
+    /// snapshot of the index rather than the latest searcher.
+    ///
+    /// This uses a concurrency permit while completing the operation.
+    pub async fn get_doc_snapshot(
+        &self,
+        doc_address: u64,
+        snapshot: &reader::SearcherSnapshot,
+    ) -> Result<QueryHit> {
+        let doc = self.reader.get_doc_snapshot(doc_address, snapshot.clone()).await?;
+        self.to_hit(doc)
+    }
+
+    /// Runs the query against both the current index and the snapshot
+    /// returning which documents were added, removed or changed rank.
+    pub async fn diff(
+        &self,
+        payload: QueryPayload,
+        snapshot: reader::SearcherSnapshot,
+    ) -> Result<reader::ResultsDiff> {
+        self.reader.diff(payload, snapshot).await
+    }
+
+    /// Parses the query of the payload without searching, returning
+    /// the debug representation of the query the engine built.
+    pub async fn explain_query(&self, payload: QueryPayload) -> Result<String> {
+        self.reader.explain_query(payload).await
+    }
+
+    /// Tokenizes the text with the tokenizer of the given field, returning
+    /// the terms the text would be indexed as.
+    pub fn analyze(&self, field: &str, text: &str) -> Result<Vec<String>> {
+        self.reader.analyze(field, text)
+    }
+
+    /// Estimates how expensive searching the given payload would be
+    /// without running the search.
+    ///
+    /// This allows pathological queries to be rejected or deprioritized
+    /// before they take up a reader thread.
+    pub async fn estimate_cost(&self, payload: &QueryPayload) -> Result<reader::QueryCost> {
+        self.reader.estimate_cost(payload).await
+    }
+
+    /// Gets the ids of the segments currently visible to searches.
+    pub fn segments(&self) -> Vec<String> {
+        self.reader.segments()
+    }
+
+    /// Searches a single segment of the index by it's id, returning the
+    /// top hits and statistics of that segment.
+    ///
+    /// This is intended for diagnosing issues isolated to one segment and
+    /// is not part of the normal search path, see `segments` for the ids
+    /// of the segments.
+    pub async fn search_segment(&self, payload: QueryPayload, segment_id: &str) -> Result<reader::SegmentResults> {
+        self.reader.search_segment(payload, segment_id).await
+    }
+
+    /// Searches the index with the given query streaming the hits
+    /// as newline delimited JSON.
+    pub async fn search_stream(
+        &self,
+        payload: QueryPayload,
+    ) -> Result<impl Stream<Item = Result<Bytes>>> {
+        self.reader.search_stream(payload).await
+    }
+
     /// Clears all documents from the index and commits.
     pub async fn clear_and_commit(&self) -> Result<()> {
         self.clear_documents().await?;
@@ -0,0 +1,604 @@
+use std::collections::BTreeSet;
+
+use anyhow::{Error, Result};
+use tantivy::fastfield::MultiValuedFastFieldReader;
+use tantivy::fieldnorm::FieldNormReader;
+use tantivy::postings::{Postings, SegmentPostings};
+use tantivy::query::{ConstScorer, EmptyScorer, Explanation, Query, Scorer, Weight};
+use tantivy::schema::{Cardinality, Field, FieldType, IndexRecordOption, Schema};
+use tantivy::{DocId, DocSet, Score, Searcher, SegmentReader, TantivyError, Term, TERMINATED};
+
+use crate::structures::Bm25Params;
+
+/// Gets the fast field cardinality of a given field type if the
+/// field is a fast field.
+pub(super) fn fast_field_cardinality(field_type: &FieldType) -> Option<Cardinality> {
+    match field_type {
+        FieldType::U64(opts)
+        | FieldType::I64(opts)
+        | FieldType::F64(opts)
+        | FieldType::Date(opts) => opts.get_fastfield_cardinality(),
+        _ => None,
+    }
+}
+
+/// A simple doc set over a sorted and de-duplicated set of doc ids.
+pub(super) struct VecDocSet {
+    docs: Vec<DocId>,
+    cursor: usize,
+}
+
+impl VecDocSet {
+    pub(super) fn new(mut docs: Vec<DocId>) -> Self {
+        docs.sort_unstable();
+        docs.dedup();
+
+        Self { docs, cursor: 0 }
+    }
+}
+
+impl DocSet for VecDocSet {
+    fn advance(&mut self) -> DocId {
+        self.cursor += 1;
+        self.doc()
+    }
+
+    fn doc(&self) -> DocId {
+        self.docs.get(self.cursor).copied().unwrap_or(TERMINATED)
+    }
+
+    fn size_hint(&self) -> u32 {
+        self.docs.len() as u32
+    }
+}
+
+/// How the existence of a value is checked for a given field.
+#[derive(Debug, Copy, Clone)]
+enum ExistsStrategy {
+    /// Every term of the field's inverted index is walked, this reads
+    /// every posting list of the field so it's proportional to the
+    /// total amount of values the field has.
+    Indexed,
+
+    /// The number of values in the multi-value fast field is checked.
+    MultiValueFastField,
+}
+
+/// Matches any document which has at least one value for the given field.
+///
+/// Indexed fields are checked via the inverted index and multi-value fast
+/// fields are checked via their column. Single value fast fields store a
+/// default value for documents without one so they can't be checked and
+/// are rejected along with fields which are neither.
+#[derive(Debug, Clone)]
+pub(super) struct ExistsQuery {
+    field: Field,
+    strategy: ExistsStrategy,
+}
+
+impl ExistsQuery {
+    pub(super) fn new(schema: &Schema, field: Field) -> Result<Self> {
+        let entry = schema.get_field_entry(field);
+
+        let strategy = if entry.is_indexed() {
+            ExistsStrategy::Indexed
+        } else {
+            match fast_field_cardinality(entry.field_type()) {
+                Some(Cardinality::MultiValues) => ExistsStrategy::MultiValueFastField,
+                Some(Cardinality::SingleValue) => {
+                    return Err(Error::msg(format!(
+                        "field {:?} must be indexed to check existence, single value fast fields have a value for every document",
+                        entry.name(),
+                    )))
+                },
+                None => {
+                    return Err(Error::msg(format!(
+                        "field {:?} must be either indexed or a multi-value fast field to check existence",
+                        entry.name(),
+                    )))
+                },
+            }
+        };
+
+        Ok(Self { field, strategy })
+    }
+}
+
+impl Query for ExistsQuery {
+    fn weight(&self, _searcher: &Searcher, _scoring_enabled: bool) -> tantivy::Result<Box<dyn Weight>> {
+        Ok(Box::new(ExistsWeight {
+            field: self.field,
+            strategy: self.strategy,
+        }))
+    }
+}
+
+struct ExistsWeight {
+    field: Field,
+    strategy: ExistsStrategy,
+}
+
+impl ExistsWeight {
+    fn matching_docs(&self, reader: &SegmentReader) -> tantivy::Result<Vec<DocId>> {
+        let mut docs = vec![];
+
+        match self.strategy {
+            ExistsStrategy::Indexed => {
+                let inverted_index = reader.inverted_index(self.field)?;
+                let mut terms = inverted_index.terms().stream()?;
+                while terms.advance() {
+                    let mut postings = inverted_index
+                        .read_postings_from_terminfo(terms.value(), IndexRecordOption::Basic)?;
+
+                    let mut doc = postings.doc();
+                    while doc != TERMINATED {
+                        docs.push(doc);
+                        doc = postings.advance();
+                    }
+                }
+            },
+            ExistsStrategy::MultiValueFastField => {
+                let column: MultiValuedFastFieldReader<u64> =
+                    reader.fast_fields().u64s_lenient(self.field)?;
+
+                let mut values = vec![];
+                for doc in 0..reader.max_doc() {
+                    column.get_vals(doc, &mut values);
+                    if !values.is_empty() {
+                        docs.push(doc);
+                    }
+                }
+            },
+        }
+
+        Ok(docs)
+    }
+}
+
+impl Weight for ExistsWeight {
+    fn scorer(&self, reader: &SegmentReader, boost: Score) -> tantivy::Result<Box<dyn Scorer>> {
+        let docs = VecDocSet::new(self.matching_docs(reader)?);
+
+        Ok(Box::new(ConstScorer::new(docs, boost)))
+    }
+
+    fn explain(&self, reader: &SegmentReader, doc: DocId) -> tantivy::Result<Explanation> {
+        let mut scorer = self.scorer(reader, 1.0)?;
+        if scorer.seek(doc) != doc {
+            return Err(TantivyError::InvalidArgument(format!(
+                "document {} does not have a value for the field",
+                doc
+            )));
+        }
+
+        Ok(Explanation::new("ExistsQuery", 1.0))
+    }
+}
+
+/// Matches the same documents as the inner query but gives every
+/// match the same score, ignoring term frequencies and field lengths.
+#[derive(Debug)]
+pub(super) struct ConstantScoreQuery {
+    query: Box<dyn Query>,
+    score: Score,
+}
+
+impl ConstantScoreQuery {
+    pub(super) fn new(query: Box<dyn Query>, score: Score) -> Self {
+        Self { query, score }
+    }
+}
+
+impl Clone for ConstantScoreQuery {
+    fn clone(&self) -> Self {
+        Self {
+            query: self.query.box_clone(),
+            score: self.score,
+        }
+    }
+}
+
+impl Query for ConstantScoreQuery {
+    fn weight(&self, searcher: &Searcher, _scoring_enabled: bool) -> tantivy::Result<Box<dyn Weight>> {
+        Ok(Box::new(ConstantScoreWeight {
+            weight: self.query.weight(searcher, false)?,
+            score: self.score,
+        }))
+    }
+
+    fn query_terms(&self, terms: &mut BTreeSet<Term>) {
+        self.query.query_terms(terms)
+    }
+}
+
+struct ConstantScoreWeight {
+    weight: Box<dyn Weight>,
+    score: Score,
+}
+
+impl Weight for ConstantScoreWeight {
+    fn scorer(&self, reader: &SegmentReader, boost: Score) -> tantivy::Result<Box<dyn Scorer>> {
+        let scorer = self.weight.scorer(reader, boost)?;
+
+        Ok(Box::new(ConstScorer::new(scorer, self.score * boost)))
+    }
+
+    fn explain(&self, reader: &SegmentReader, doc: DocId) -> tantivy::Result<Explanation> {
+        let mut scorer = self.scorer(reader, 1.0)?;
+        if scorer.seek(doc) != doc {
+            return Err(TantivyError::InvalidArgument(format!(
+                "document {} does not match the query",
+                doc
+            )));
+        }
+
+        Ok(Explanation::new("ConstantScoreQuery", self.score))
+    }
+}
+
+/// Matches documents containing the term scored with BM25 using the
+/// given parameters rather than tantivy's defaults.
+#[derive(Debug, Clone)]
+pub(super) struct Bm25TermQuery {
+    term: Term,
+    params: Bm25Params,
+}
+
+impl Bm25TermQuery {
+    pub(super) fn new(term: Term, params: Bm25Params) -> Self {
+        Self { term, params }
+    }
+}
+
+impl Query for Bm25TermQuery {
+    fn weight(&self, searcher: &Searcher, _scoring_enabled: bool) -> tantivy::Result<Box<dyn Weight>> {
+        let field = self.term.field();
+
+        let mut total_num_tokens = 0u64;
+        let mut total_num_docs = 0u64;
+        for reader in searcher.segment_readers() {
+            total_num_tokens += reader.inverted_index(field)?.total_num_tokens();
+            total_num_docs += reader.max_doc() as u64;
+        }
+
+        let average_fieldnorm = if total_num_docs == 0 {
+            0.0
+        } else {
+            total_num_tokens as Score / total_num_docs as Score
+        };
+
+        // The document frequency includes deleted documents so it's compared
+        // against every document rather than only the live ones.
+        let doc_freq = searcher.doc_freq(&self.term)? as Score;
+        let max_doc = total_num_docs as Score;
+        let idf = (1.0 + (max_doc - doc_freq + 0.5) / (doc_freq + 0.5)).ln();
+
+        Ok(Box::new(Bm25TermWeight {
+            term: self.term.clone(),
+            params: self.params,
+            idf,
+            average_fieldnorm,
+        }))
+    }
+
+    fn query_terms(&self, terms: &mut BTreeSet<Term>) {
+        terms.insert(self.term.clone());
+    }
+}
+
+struct Bm25TermWeight {
+    term: Term,
+    params: Bm25Params,
+    idf: Score,
+    average_fieldnorm: Score,
+}
+
+impl Weight for Bm25TermWeight {
+    fn scorer(&self, reader: &SegmentReader, boost: Score) -> tantivy::Result<Box<dyn Scorer>> {
+        let field = self.term.field();
+        let postings = reader
+            .inverted_index(field)?
+            .read_postings(&self.term, IndexRecordOption::WithFreqs)?;
+
+        let postings = match postings {
+            None => return Ok(Box::new(EmptyScorer)),
+            Some(postings) => postings,
+        };
+
+        Ok(Box::new(Bm25TermScorer {
+            postings,
+            fieldnorms: reader.get_fieldnorms_reader(field)?,
+            params: self.params,
+            weight: self.idf * boost,
+            average_fieldnorm: self.average_fieldnorm,
+        }))
+    }
+
+    fn explain(&self, reader: &SegmentReader, doc: DocId) -> tantivy::Result<Explanation> {
+        let mut scorer = self.scorer(reader, 1.0)?;
+        if scorer.seek(doc) != doc {
+            return Err(TantivyError::InvalidArgument(format!(
+                "document {} does not contain the term",
+                doc
+            )));
+        }
+
+        Ok(Explanation::new("Bm25TermQuery", scorer.score()))
+    }
+}
+
+struct Bm25TermScorer {
+    postings: SegmentPostings,
+    fieldnorms: FieldNormReader,
+    params: Bm25Params,
+    weight: Score,
+    average_fieldnorm: Score,
+}
+
+impl DocSet for Bm25TermScorer {
+    fn advance(&mut self) -> DocId {
+        self.postings.advance()
+    }
+
+    fn seek(&mut self, target: DocId) -> DocId {
+        self.postings.seek(target)
+    }
+
+    fn doc(&self) -> DocId {
+        self.postings.doc()
+    }
+
+    fn size_hint(&self) -> u32 {
+        self.postings.size_hint()
+    }
+}
+
+impl Scorer for Bm25TermScorer {
+    fn score(&mut self) -> Score {
+        let term_freq = self.postings.term_freq() as Score;
+        let fieldnorm = self.fieldnorms.fieldnorm(self.doc()) as Score;
+
+        let length_ratio = if self.average_fieldnorm > 0.0 {
+            fieldnorm / self.average_fieldnorm
+        } else {
+            1.0
+        };
+
+        let Bm25Params { k1, b } = self.params;
+        let norm = k1 * (1.0 - b + b * length_ratio);
+
+        self.weight * term_freq * (k1 + 1.0) / (term_freq + norm)
+    }
+}
+
+/// Matches the documents which contain any one of the terms at least
+/// `min_freq` times, e.g. to filter out incidental mentions.
+///
+/// The fields of the terms must be indexed with term frequencies.
+#[derive(Debug, Clone)]
+pub(super) struct MinTermFreqQuery {
+    terms: Vec<Term>,
+    min_freq: u32,
+}
+
+impl MinTermFreqQuery {
+    pub(super) fn new(terms: Vec<Term>, min_freq: u32) -> Self {
+        Self { terms, min_freq }
+    }
+}
+
+impl Query for MinTermFreqQuery {
+    fn weight(&self, searcher: &Searcher, _scoring_enabled: bool) -> tantivy::Result<Box<dyn Weight>> {
+        for term in self.terms.iter() {
+            let entry = searcher.schema().get_field_entry(term.field());
+            let has_freqs = match entry.field_type() {
+                FieldType::Str(options) => options
+                    .get_indexing_options()
+                    .map(|indexing| indexing.index_option().has_freq())
+                    .unwrap_or(false),
+                _ => false,
+            };
+
+            if !has_freqs {
+                return Err(TantivyError::SchemaError(format!(
+                    "field {:?} must be a text field indexed with term frequencies",
+                    entry.name(),
+                )));
+            }
+        }
+
+        Ok(Box::new(MinTermFreqWeight {
+            terms: self.terms.clone(),
+            min_freq: self.min_freq,
+        }))
+    }
+
+    fn query_terms(&self, terms: &mut BTreeSet<Term>) {
+        terms.extend(self.terms.iter().cloned());
+    }
+}
+
+struct MinTermFreqWeight {
+    terms: Vec<Term>,
+    min_freq: u32,
+}
+
+impl MinTermFreqWeight {
+    fn matching_docs(&self, reader: &SegmentReader) -> tantivy::Result<Vec<DocId>> {
+        let mut docs = vec![];
+
+        for term in self.terms.iter() {
+            let postings = reader
+                .inverted_index(term.field())?
+                .read_postings(term, IndexRecordOption::WithFreqs)?;
+
+            let mut postings = match postings {
+                None => continue,
+                Some(postings) => postings,
+            };
+
+            let mut doc = postings.doc();
+            while doc != TERMINATED {
+                if postings.term_freq() >= self.min_freq {
+                    docs.push(doc);
+                }
+                doc = postings.advance();
+            }
+        }
+
+        Ok(docs)
+    }
+}
+
+impl Weight for MinTermFreqWeight {
+    fn scorer(&self, reader: &SegmentReader, boost: Score) -> tantivy::Result<Box<dyn Scorer>> {
+        let docs = VecDocSet::new(self.matching_docs(reader)?);
+
+        Ok(Box::new(ConstScorer::new(docs, boost)))
+    }
+
+    fn explain(&self, reader: &SegmentReader, doc: DocId) -> tantivy::Result<Explanation> {
+        let mut scorer = self.scorer(reader, 1.0)?;
+        if scorer.seek(doc) != doc {
+            return Err(TantivyError::InvalidArgument(format!(
+                "document {} does not contain any of the terms often enough",
+                doc
+            )));
+        }
+
+        Ok(Explanation::new("MinTermFreqQuery", 1.0))
+    }
+}
+
+/// Matches any document matching one of the queries, scoring it by the
+/// best scoring query plus the tie breaker times the scores of the others.
+///
+/// A tie breaker of 0 only considers the best match, a tie breaker of 1
+/// sums the scores like a boolean query.
+#[derive(Debug)]
+pub(super) struct DisMaxQuery {
+    queries: Vec<Box<dyn Query>>,
+    tie_breaker: Score,
+}
+
+impl DisMaxQuery {
+    pub(super) fn new(queries: Vec<Box<dyn Query>>, tie_breaker: Score) -> Self {
+        Self { queries, tie_breaker }
+    }
+}
+
+impl Clone for DisMaxQuery {
+    fn clone(&self) -> Self {
+        Self {
+            queries: self.queries.iter().map(|query| query.box_clone()).collect(),
+            tie_breaker: self.tie_breaker,
+        }
+    }
+}
+
+impl Query for DisMaxQuery {
+    fn weight(&self, searcher: &Searcher, scoring_enabled: bool) -> tantivy::Result<Box<dyn Weight>> {
+        let weights = self
+            .queries
+            .iter()
+            .map(|query| query.weight(searcher, scoring_enabled))
+            .collect::<tantivy::Result<Vec<_>>>()?;
+
+        Ok(Box::new(DisMaxWeight {
+            weights,
+            tie_breaker: self.tie_breaker,
+        }))
+    }
+
+    fn query_terms(&self, terms: &mut BTreeSet<Term>) {
+        for query in self.queries.iter() {
+            query.query_terms(terms);
+        }
+    }
+}
+
+struct DisMaxWeight {
+    weights: Vec<Box<dyn Weight>>,
+    tie_breaker: Score,
+}
+
+impl Weight for DisMaxWeight {
+    fn scorer(&self, reader: &SegmentReader, boost: Score) -> tantivy::Result<Box<dyn Scorer>> {
+        let scorers = self
+            .weights
+            .iter()
+            .map(|weight| weight.scorer(reader, boost))
+            .collect::<tantivy::Result<Vec<_>>>()?;
+
+        let doc = scorers.iter().map(|scorer| scorer.doc()).min().unwrap_or(TERMINATED);
+
+        Ok(Box::new(DisMaxScorer {
+            scorers,
+            tie_breaker: self.tie_breaker,
+            doc,
+        }))
+    }
+
+    fn explain(&self, reader: &SegmentReader, doc: DocId) -> tantivy::Result<Explanation> {
+        let mut scorer = self.scorer(reader, 1.0)?;
+        if scorer.seek(doc) != doc {
+            return Err(TantivyError::InvalidArgument(format!(
+                "document {} does not match any of the queries",
+                doc
+            )));
+        }
+
+        Ok(Explanation::new("DisMaxQuery", scorer.score()))
+    }
+}
+
+struct DisMaxScorer {
+    scorers: Vec<Box<dyn Scorer>>,
+    tie_breaker: Score,
+    doc: DocId,
+}
+
+impl DocSet for DisMaxScorer {
+    fn advance(&mut self) -> DocId {
+        if self.doc == TERMINATED {
+            return TERMINATED;
+        }
+
+        let mut next = TERMINATED;
+        for scorer in self.scorers.iter_mut() {
+            let doc = if scorer.doc() == self.doc {
+                scorer.advance()
+            } else {
+                scorer.doc()
+            };
+            next = next.min(doc);
+        }
+
+        self.doc = next;
+        next
+    }
+
+    fn doc(&self) -> DocId {
+        self.doc
+    }
+
+    fn size_hint(&self) -> u32 {
+        self.scorers.iter().map(|scorer| scorer.size_hint()).max().unwrap_or(0)
+    }
+}
+
+impl Scorer for DisMaxScorer {
+    fn score(&mut self) -> Score {
+        let mut max: Score = 0.0;
+        let mut sum: Score = 0.0;
+        for scorer in self.scorers.iter_mut() {
+            if scorer.doc() == self.doc {
+                let score = scorer.score();
+                max = max.max(score);
+                sum += score;
+            }
+        }
+
+        max + self.tie_breaker * (sum - max)
+    }
+}
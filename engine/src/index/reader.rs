@@ -1,57 +1,272 @@
+use std::cell::Cell;
+use std::collections::BTreeSet;
+use std::ops::{Bound, Deref};
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::{Error, Result};
 use serde::Serialize;
-use tantivy::collector::{Count, TopDocs};
+use tantivy::collector::{Collector, Count, TopDocs};
 use tantivy::query::{
     BooleanQuery,
     BoostQuery,
     EmptyQuery,
+    Explanation,
     FuzzyTermQuery,
     MoreLikeThisQuery,
     Occur,
     Query,
     QueryParser,
+    RangeQuery,
     TermQuery,
 };
-use tantivy::schema::{Field, FieldType, IndexRecordOption, NamedFieldDocument, Schema, Value};
-use tantivy::{DocAddress, Executor, IndexReader, LeasedItem, Score, Searcher, Term};
-use tokio::sync::{oneshot, Semaphore};
-use hashbrown::HashMap;
+use tantivy::schema::{Cardinality, Field, FieldType, IndexRecordOption, NamedFieldDocument, Schema, Type, Value};
+use tantivy::tokenizer::{Language, RawTokenizer, Stemmer, TextAnalyzer, TokenizerManager};
+use tantivy::{u64_to_f64, u64_to_i64, DocAddress, DocId, DocSet, Executor, IndexReader, LeasedItem, Score, Searcher, SegmentId, SegmentReader, Term, TERMINATED};
+use tokio::sync::{mpsc, oneshot, OwnedSemaphorePermit, Semaphore, TryAcquireError};
+use tokio::task::JoinHandle;
+use bytes::Bytes;
+use futures_util::stream::{self, Stream};
+use hashbrown::{HashMap, HashSet};
+use parking_lot::RwLock;
 
 
-use crate::correction::{self, correct_sentence};
-use crate::structures::{QueryMode, QueryPayload};
+use crate::correction::{self, correct_sentence_words, correct_word_candidates, CorrectionVerbosity};
+use crate::structures::{
+    format_decimal,
+    Bm25Params,
+    value_to_term,
+    DocumentValue,
+    ExecutorKind,
+    FieldCombination,
+    HighlightOptions,
+    HybridOptions,
+    MandatoryFilter,
+    MapParseMode,
+    MapQueryValue,
+    QueryMode,
+    QueryPayload,
+    RangeFilter,
+    ReloadMode,
+    resolve_relative_date,
+    SortOrder,
+};
+use crate::index::collectors::{
+    CappedCount,
+    DateHistogram,
+    Deadline,
+    DocBudget,
+    FieldValueBoost,
+    GroupCollector,
+    MultiSortCollector,
+    NumericColumn,
+    SearchLimits,
+    SegmentStats,
+    ShuffledTies,
+    SortField,
+    SortValue,
+};
 use crate::index::executor::ExecutorPool;
-use std::borrow::Borrow;
+use crate::index::explain::{ScoreExplainer, TopHitsExplainer};
+use crate::index::fusion;
+use crate::index::fuzzy_matches::{FuzzyMatcher, FuzzyTermMatch};
+use crate::index::highlight::{Highlighter, MatchPosition};
+use crate::index::queries::{fast_field_cardinality, Bm25TermQuery, ConstantScoreQuery, DisMaxQuery, ExistsQuery, MinTermFreqQuery};
+use crate::index::refine::{MatchRecorder, ResultCache};
+use crate::index::term_vectors::{TermVectorExtractor, TermVectors};
+use crate::index::vector::VectorQuery;
+use crate::helpers::hash;
+use crate::preprocess::{NoOpPreprocessor, QueryPreprocessor};
+use crate::query_log::{NoOpQueryLogger, QueryLogRecord, QueryLogger};
+use std::borrow::{Borrow, Cow};
 
 /// Attempts to get a document otherwise sending an error
 /// back to the resolve channel.
 macro_rules! try_get_doc {
     ($resolve:expr, $searcher:expr, $doc:expr, $executor:expr) => {{
-        let res = $searcher.search_with_executor(
-            &TermQuery::new($doc, IndexRecordOption::Basic),
-            &TopDocs::with_limit(1),
-            $executor,
-        );
-
-        let res: Vec<(f32, DocAddress)> = match res {
+        match lookup_doc(&$searcher, $doc, None, $executor) {
             Err(e) => {
-                let _ = $resolve.send(Err(Error::from(e)));
+                let _ = $resolve.send(Err(e));
                 return;
             },
-            Ok(res) => res,
-        };
+            Ok(doc) => doc,
+        }
+    }};
+}
+
+/// Gets the address of the document with the given id term.
+///
+/// If a restriction is given the document must also match it, so a
+/// reference document can't be looked up from outside the restriction.
+fn lookup_doc(
+    searcher: &Searcher,
+    doc: Term,
+    restriction: Option<&Box<dyn Query>>,
+    executor: &Executor,
+) -> Result<DocAddress> {
+    let mut query: Box<dyn Query> = Box::new(TermQuery::new(doc, IndexRecordOption::Basic));
+    if let Some(restriction) = restriction {
+        query = with_filters(query, std::slice::from_ref(restriction));
+    }
+
+    let res: Vec<(f32, DocAddress)> = searcher.search_with_executor(
+        query.as_ref(),
+        &TopDocs::with_limit(1),
+        executor,
+    )?;
+
+    if res.len() == 0 {
+        return Err(Error::msg("no document exists with this id"));
+    }
 
-        if res.len() == 0 {
-            let _ = $resolve.send(Err(Error::msg("no document exists with this id")));
-            return;
+    Ok(res[0].1)
+}
+
+/// Gets the ids of the segments visible to the searcher.
+fn segment_ids(searcher: &Searcher) -> HashSet<SegmentId> {
+    searcher
+        .segment_readers()
+        .iter()
+        .map(|reader| reader.segment_id())
+        .collect()
+}
+
+/// Warms the committed segments which were not in the `previous`
+/// segments and then reloads the reader, returning the now visible
+/// segments and how many were warmed.
+///
+/// Warming opens each new segment and the inverted index of each search
+/// field before the reader is reloaded, so their files are already paged
+/// in when the first search against them runs.
+fn refresh_reader(
+    index: &tantivy::Index,
+    reader: &RwLock<IndexReader>,
+    search_fields: &[(Field, Score)],
+    previous: HashSet<SegmentId>,
+) -> Result<(HashSet<SegmentId>, usize)> {
+    let mut warmed = 0;
+    for segment in index.searchable_segments()? {
+        if previous.contains(&segment.id()) {
+            continue;
         }
 
-        res[0].1
-    }};
+        let segment = SegmentReader::open(&segment)?;
+        for (field, _) in search_fields {
+            segment.inverted_index(*field)?;
+        }
+        warmed += 1;
+    }
+
+    let reader = reader.read();
+    reader.reload()?;
+
+    Ok((segment_ids(&reader.searcher()), warmed))
+}
+
+/// Identifies the segments and deletes visible to the searcher.
+///
+/// Two searchers have the same generation only if they see the same
+/// documents, this is not ordered.
+fn searcher_generation(searcher: &Searcher) -> u64 {
+    let mut segments: Vec<(String, u32)> = searcher
+        .segment_readers()
+        .iter()
+        .map(|reader| (reader.segment_id().uuid_string(), reader.num_deleted_docs()))
+        .collect();
+    segments.sort_unstable();
+
+    hash(&segments)
 }
 
+/// Gets the addresses of the live documents with any of the given ids.
+///
+/// This looks the ids up in the term dictionary of each segment directly,
+/// any ids which do not exist are missing from the returned map.
+fn lookup_docs(searcher: &Searcher, field: Field, ids: &[u64]) -> Result<HashMap<u64, DocAddress>> {
+    let mut addresses = HashMap::with_capacity(ids.len());
+    for (segment_ord, reader) in searcher.segment_readers().iter().enumerate() {
+        let inverted_index = reader.inverted_index(field)?;
+
+        for id in ids.iter() {
+            if addresses.contains_key(id) {
+                continue;
+            }
+
+            let term = Term::from_field_u64(field, *id);
+            let mut postings = match inverted_index.read_postings(&term, IndexRecordOption::Basic)? {
+                None => continue,
+                Some(postings) => postings,
+            };
+
+            // Deleted documents remain in the postings until merged.
+            let mut doc = postings.doc();
+            while doc != TERMINATED {
+                if !reader.is_deleted(doc) {
+                    addresses.insert(*id, DocAddress::new(segment_ord as u32, doc));
+                    break;
+                }
+
+                doc = postings.advance();
+            }
+        }
+    }
+
+    Ok(addresses)
+}
+
+/// Builds a reader for the index with the given amount of searchers.
+fn build_reader(index: &tantivy::Index, num_searchers: usize, policy: ReloadMode) -> Result<IndexReader> {
+    let reader = index
+        .reader_builder()
+        .num_searchers(num_searchers)
+        .reload_policy(policy.into())
+        .try_into()?;
+
+    Ok(reader)
+}
+
+/// A user registered function which builds a query from the index,
+/// the weighted search fields and the query string if any.
+pub(super) type CustomQueryBuilder = Arc<
+    dyn Fn(&tantivy::Index, &[(Field, Score)], Option<&str>) -> Result<Box<dyn Query>>
+        + Send
+        + Sync,
+>;
+
+/// The reserved sort key which explicitly orders hits by their relevance score.
+const SCORE_SORT_KEY: &str = "_score";
+
+/// The bounds on the length of the words a more like this query considers.
+const MLT_MIN_WORD_LENGTH: usize = 2;
+const MLT_MAX_WORD_LENGTH: usize = 18;
+
+/// The factor applied to the boost of a word's synonyms.
+const SYNONYM_BOOST: Score = 0.8;
+
+/// The maximum amount of hits of a single group, including the
+/// collapsed hit and it's inner hits.
+const MAX_GROUP_HITS: usize = 100;
+
+/// The amount of serialized hits buffered ahead of a streaming consumer.
+const STREAM_BUFFER_SIZE: usize = 32;
+
+/// The maximum amount of snapshots taken with `take_snapshot` which can
+/// be alive at once, the reader has this many searchers on top of one per
+/// concurrent search so snapshots never starve searches of a searcher.
+const MAX_SNAPSHOTS: usize = 8;
+
+/// The maximum amount of query cost estimates which can run at once, the
+/// reader has this many searchers on top of the snapshots' searchers so
+/// estimates never wait on a searcher held by a search.
+const MAX_ESTIMATES: usize = 2;
+
+/// The amount of searchers the reader has on top of one per concurrent search.
+const RESERVED_SEARCHERS: usize = MAX_SNAPSHOTS + MAX_ESTIMATES;
+
+/// The error returned when a worker drops the resolver without
+/// sending a result, this only happens if the worker panicked.
+static WORKER_PANICKED: &str = "the search worker panicked while handling the request";
+
 #[derive(Debug)]
 enum Either<A, B> {
     Left(A),
@@ -75,12 +290,22 @@ enum Either<A, B> {
 /// This system has the potential to spawn and incredibly large amount of
 /// threads, when setting the `max_concurrency` and `reader_threads` the total
 /// will result in `max_concurrency` * `reader_threads` threads spawned.
+///
+/// If `overflow_concurrency` is set, the executor pool can grow by that many
+/// single-threaded executors under load, these do not spawn reader threads
+/// but the worker thread pool is sized to `max_concurrency` + `overflow_concurrency`.
 pub(super) struct IndexReaderHandler {
     /// The name of the index the handler belongs to.
     name: String,
 
     /// The internal tantivy index reader.
-    reader: IndexReader,
+    ///
+    /// This is replaced whenever the reload policy is changed, it's shared
+    /// with the background refresh task if one is running.
+    reader: Arc<RwLock<IndexReader>>,
+
+    /// The index the reader belongs to, used to rebuild the reader.
+    index: tantivy::Index,
 
     /// The reader thread pool executors.
     ///
@@ -92,9 +317,16 @@ pub(super) struct IndexReaderHandler {
     executor_pool: ExecutorPool,
 
     /// A concurrency semaphore.
-    limiter: Semaphore,
+    limiter: Arc<Semaphore>,
+
+    /// Limits the amount of snapshots which can be alive at once.
+    snapshot_limiter: Arc<Semaphore>,
 
-    /// The maximum concurrency of searches at one time.
+    /// Limits the amount of query cost estimates running at once.
+    estimate_limiter: Semaphore,
+
+    /// The maximum concurrency of searches at one time including
+    /// any overflow searches.
     max_concurrency: usize,
 
     /// The execution thread pool.
@@ -119,260 +351,2043 @@ pub(super) struct IndexReaderHandler {
     ///
     /// This only applies to the fast-fuzzy query system.
     strip_stop_words: bool,
+
+    /// The sink every completed search is reported to.
+    query_logger: RwLock<Arc<dyn QueryLogger>>,
+
+    /// The hook every search payload is passed through before it's parsed.
+    preprocessor: RwLock<Arc<dyn QueryPreprocessor>>,
+
+    /// The set of decimal fields and their scale.
+    decimal_fields: Arc<HashMap<String, u32>>,
+
+    /// The set of vector fields and their dimensions.
+    vector_fields: Arc<HashMap<String, usize>>,
+
+    /// The registered custom query builders by name.
+    custom_queries: RwLock<HashMap<String, CustomQueryBuilder>>,
+
+    /// The maximum amount of fuzzy terms a fuzzy query can generate.
+    ///
+    /// This protects the readers from long queries which would otherwise
+    /// generate a fuzzy automaton for every word of every search field.
+    max_fuzzy_terms: Option<usize>,
+
+    /// The name of the private field holding each document's id.
+    id_field: Arc<String>,
+
+    /// The names of the `_<hash>` fields the fast fuzzy system adds for
+    /// each of the corrected text fields.
+    private_fields: Arc<HashSet<String>>,
+
+    /// The custom BM25 parameters used to score term queries if any.
+    bm25: Option<Bm25Params>,
+
+    /// The cached results of searches which can be refined.
+    result_cache: Arc<ResultCache>,
+
+    /// Whether or not hits missing a valid id are skipped and logged
+    /// rather than failing the whole search.
+    skip_malformed_docs: bool,
 }
 
 impl IndexReaderHandler {
     /// Creates a new reader handler from an existing tantivy index reader.
     ///
     /// This will spawn a thread pool with `n` amount of threads equal
-    /// to the set `max_concurrency`.
+    /// to the set `max_concurrency` + `overflow_concurrency`.
     pub(super) fn create(
         index_name: String,
         max_concurrency: usize,
-        reader: IndexReader,
+        overflow_concurrency: usize,
+        index: &tantivy::Index,
+        reload_policy: ReloadMode,
         reader_threads: usize,
         parser: QueryParser,
         search_fields: Vec<(Field, Score)>,
         schema_copy: Schema,
         use_fast_fuzzy: bool,
         strip_stop_words: bool,
+        decimal_fields: Arc<HashMap<String, u32>>,
+        vector_fields: Arc<HashMap<String, usize>>,
+        max_fuzzy_terms: Option<usize>,
+        id_field: String,
+        indexed_text_fields: &[String],
+        bm25: Option<Bm25Params>,
+        skip_malformed_docs: bool,
     ) -> Result<Self> {
         if use_fast_fuzzy {
             warn!("[ READER @ {} ] 'Normal' queries will behave differently with TEXT type fields due to fast-fuzzy.", &index_name);
         }
 
-        let limiter = Semaphore::new(max_concurrency);
+        if let Some(params) = bm25 {
+            warn!(
+                "[ READER @ {} ] the BM25 parameters {:?} only apply to {} queries, other queries use tantivy's defaults",
+                &index_name,
+                params,
+                if use_fast_fuzzy { "'FastFuzzy' and 'CaseSensitive'" } else { "'CaseSensitive'" },
+            );
+        }
+
+        let total_concurrency = max_concurrency + overflow_concurrency;
+        let limiter = Arc::new(Semaphore::new(total_concurrency));
+
+        let reader = build_reader(index, total_concurrency + RESERVED_SEARCHERS, reload_policy)?;
+        info!(
+            "[ READER @ {} ] index reader has been allocated with {} searchers and {:?} reloading",
+            &index_name, total_concurrency + RESERVED_SEARCHERS, reload_policy
+        );
 
         let name = index_name.clone();
+        let panic_name = index_name.clone();
         let thread_pool = {
+            // Rayon aborts the process on a panic by default, instead we log it,
+            // unwinding drops the task's resolver and executor handle which rejects
+            // the waiting request and returns the executor to the pool.
             rayon::ThreadPoolBuilder::new()
-                .num_threads(max_concurrency)
+                .num_threads(total_concurrency)
                 .thread_name(move |n| format!("index-{}-worker-{}", name.clone(), n))
+                .panic_handler(move |_| {
+                    error!("[ READER @ {} ] worker panicked while handling a task", &panic_name)
+                })
                 .build()?
         };
 
         let executor_pool = ExecutorPool::create(
             &index_name,
             max_concurrency,
+            total_concurrency,
             reader_threads,
         )?;
 
         Ok(Self {
             name: index_name,
-            reader,
+            reader: Arc::new(RwLock::new(reader)),
+            index: index.clone(),
             executor_pool,
             limiter,
-            max_concurrency,
+            snapshot_limiter: Arc::new(Semaphore::new(MAX_SNAPSHOTS)),
+            estimate_limiter: Semaphore::new(MAX_ESTIMATES),
+            max_concurrency: total_concurrency,
             thread_pool,
             parser: Arc::new(parser),
             search_fields: Arc::new(search_fields),
             schema: schema_copy,
             use_fast_fuzzy,
             strip_stop_words,
+            query_logger: RwLock::new(Arc::new(NoOpQueryLogger)),
+            preprocessor: RwLock::new(Arc::new(NoOpPreprocessor)),
+            decimal_fields,
+            vector_fields,
+            custom_queries: RwLock::new(HashMap::new()),
+            max_fuzzy_terms,
+            id_field: Arc::new(id_field),
+            private_fields: Arc::new(private_field_names(indexed_text_fields)),
+            bm25,
+            result_cache: Arc::new(ResultCache::new()),
+            skip_malformed_docs,
+        })
+    }
+
+    /// Rebuilds the reader with the given reload policy.
+    ///
+    /// Any searchers already leased from the previous reader remain
+    /// valid until they're dropped.
+    pub(super) fn set_reload_policy(&self, policy: ReloadMode) -> Result<()> {
+        let reader = build_reader(&self.index, self.max_concurrency + RESERVED_SEARCHERS, policy)?;
+        *self.reader.write() = reader;
+
+        info!("[ READER @ {} ] reload policy changed to {:?}", &self.name, policy);
+        Ok(())
+    }
+
+    /// Reloads the reader so new searches see the latest commit,
+    /// returning the generation of the reloaded searcher.
+    pub(super) fn reload(&self) -> Result<u64> {
+        let reader = self.reader.read();
+        reader.reload()?;
+
+        Ok(searcher_generation(&reader.searcher()))
+    }
+
+    /// Spawns a background task reloading the reader every `interval`,
+    /// warming any newly committed segments before the reload so the
+    /// first searches against them aren't cold.
+    ///
+    /// The task holds a search permit while refreshing so it stops once
+    /// the reader has been shut down, the returned handle can also be
+    /// aborted to stop it early.
+    pub(super) fn spawn_refresh(&self, interval: Duration) -> JoinHandle<()> {
+        let name = self.name.clone();
+        let index = self.index.clone();
+        let reader = self.reader.clone();
+        let limiter = self.limiter.clone();
+        let search_fields = self.search_fields.clone();
+
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            let mut segments = segment_ids(&reader.read().searcher());
+
+            // The first tick completes immediately.
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+
+                let permit = match limiter.clone().acquire_owned().await {
+                    Ok(permit) => permit,
+                    Err(_) => break,
+                };
+
+                let index = index.clone();
+                let reader = reader.clone();
+                let search_fields = search_fields.clone();
+                // The segments are kept if the refresh fails so the next refresh
+                // doesn't warm every segment again.
+                let previous = segments.clone();
+                let refreshed = tokio::task::spawn_blocking(move || {
+                    let _permit = permit;
+                    refresh_reader(&index, &reader, &search_fields, previous)
+                })
+                .await;
+
+                match refreshed {
+                    Ok(Ok((current, warmed))) => {
+                        if warmed > 0 {
+                            debug!("[ READER @ {} ] refreshed reader warming {} new segments", &name, warmed);
+                        }
+                        segments = current;
+                    },
+                    Ok(Err(e)) => warn!("[ READER @ {} ] failed to refresh reader: {}", &name, e),
+                    Err(e) => warn!("[ READER @ {} ] refresh task panicked: {}", &name, e),
+                }
+            }
+
+            debug!("[ READER @ {} ] reader has shutdown, stopping refresh", &name);
+        })
+    }
+
+    /// Gets the ids of the segments visible to new searches.
+    pub(super) fn segments(&self) -> Vec<String> {
+        self.reader
+            .read()
+            .searcher()
+            .segment_readers()
+            .iter()
+            .map(|reader| reader.segment_id().uuid_string())
+            .collect()
+    }
+
+    /// Gets the generation of the searcher new searches would use.
+    pub(super) fn generation(&self) -> u64 {
+        searcher_generation(&self.reader.read().searcher())
+    }
+
+    /// Replaces the query logger used to report completed searches.
+    pub(super) fn set_query_logger(&self, logger: Arc<dyn QueryLogger>) {
+        *self.query_logger.write() = logger;
+    }
+
+    /// Replaces the preprocessor every search payload is passed through.
+    pub(super) fn set_query_preprocessor(&self, preprocessor: Arc<dyn QueryPreprocessor>) {
+        *self.preprocessor.write() = preprocessor;
+    }
+
+    /// Passes the payload through the preprocessor, rejecting the
+    /// search if the preprocessor does.
+    fn preprocess(&self, payload: &mut QueryPayload) -> Result<()> {
+        let preprocessor = self.preprocessor.read().clone();
+        preprocessor.preprocess(&self.name, payload).map_err(|e| {
+            debug!("[ SEARCH @ {} ] preprocessor rejected search: {}", &self.name, e);
+            e
         })
     }
 
+    /// Registers a custom query builder under the given name replacing
+    /// any existing builder with the same name.
+    pub(super) fn register_query(&self, name: String, builder: CustomQueryBuilder) {
+        self.custom_queries.write().insert(name, builder);
+    }
+
     /// Gets a document with a given address.
     ///
     /// This counts as a concurrent action.
     pub(super) async fn get_doc(&self, doc_address: u64) -> Result<NamedFieldDocument> {
-        let _permit = self.limiter.acquire().await?;
+        // The searcher is only taken once the permit has been acquired.
+        let permit = self.limiter.clone().acquire_owned().await?;
+        self.fetch_doc(doc_address, permit, self.snapshot()).await
+    }
 
+    /// Gets a document with a given address from the given snapshot of
+    /// the index, e.g. a document returned by searching the same snapshot.
+    ///
+    /// This counts as a concurrent action.
+    pub(super) async fn get_doc_snapshot(
+        &self,
+        doc_address: u64,
+        searcher: SearcherSnapshot,
+    ) -> Result<NamedFieldDocument> {
+        let permit = self.limiter.clone().acquire_owned().await?;
+        self.fetch_doc(doc_address, permit, searcher).await
+    }
+
+    /// Fetches the document with the given id from the searcher on the thread pool.
+    async fn fetch_doc(
+        &self,
+        doc_address: u64,
+        permit: OwnedSemaphorePermit,
+        searcher: SearcherSnapshot,
+    ) -> Result<NamedFieldDocument> {
         let (resolve, waiter) = oneshot::channel();
-        let searcher = self.reader.searcher();
-        let executor = self.executor_pool.acquire()?;
+        let executor = self.executor_pool.acquire().await?;
         let field = self
             .schema
-            .get_field("_id")
+            .get_field(&self.id_field)
             .ok_or_else(|| Error::msg("missing a required private field, this is a bug."))?;
 
         self.thread_pool.spawn(move || {
+            // The executor is returned to the pool before the permit is released.
+            let _permit = permit;
+            let executor = executor;
+
             let term = Term::from_field_u64(field, doc_address);
             let doc = try_get_doc!(resolve, searcher, term, executor.borrow());
             let doc = searcher.doc(doc).map_err(Error::from);
             let _ = resolve.send(doc);
         });
 
-        let result = waiter.await??;
-        let doc = self.schema.to_named_doc(&result);
+        let result = waiter.await.map_err(|_| Error::msg(WORKER_PANICKED))??;
+        let mut doc = self.schema.to_named_doc(&result);
+        format_decimal_doc_values(&mut doc, &self.decimal_fields);
+        strip_private_fields(&mut doc, &self.private_fields);
 
         Ok(doc)
     }
 
-    /// Shuts down the thread pools and acquires all permits
-    /// shutting the index down.
+    /// Gets the single document whose given field matches the value exactly.
     ///
-    /// Thread pools are shutdown asynchronously via Rayon's handling.
-    pub(super) async fn shutdown(&self) -> Result<()> {
-        // Wait till all searches have been completed.
-        let _ = self
-            .limiter
-            .acquire_many(self.max_concurrency as u32)
-            .await?;
-        self.limiter.close();
+    /// The value is parsed according to the field's type in the schema, text
+    /// fields are matched against the raw value so this is only reliable for
+    /// fields which are not tokenized e.g. `string` fields.
+    ///
+    /// This counts as a concurrent action.
+    pub(super) async fn get_doc_by(&self, field: &str, value: &str) -> Result<NamedFieldDocument> {
+        let permit = self.limiter.clone().acquire_owned().await?;
 
-        self.executor_pool.shutdown();
+        let field_id = self
+            .schema
+            .get_field(field)
+            .ok_or_else(|| Error::msg(format!("unknown field {:?}", field)))?;
+        let entry = self.schema.get_field_entry(field_id);
+        if !entry.is_indexed() {
+            return Err(Error::msg(format!(
+                "field {:?} is not indexed and cannot be used to lookup documents",
+                field
+            )));
+        }
 
-        Ok(())
+        // Decimal values are parsed exactly when they're scaled.
+        let scale = self.decimal_fields.get(field).copied();
+        let value = match scale {
+            Some(_) => DocumentValue::Text(value.to_string()),
+            None => parse_field_value(entry.field_type(), value)?,
+        };
+        let term = value_to_term(field_id, entry.field_type(), scale, value)?;
+
+        let (resolve, waiter) = oneshot::channel();
+        let searcher = self.reader.read().searcher();
+        let executor = self.executor_pool.acquire().await?;
+
+        self.thread_pool.spawn(move || {
+            // The executor is returned to the pool before the permit is released.
+            let _permit = permit;
+            let executor = executor;
+
+            let run = || -> Result<tantivy::Document> {
+                let res: Vec<(f32, DocAddress)> = searcher.search_with_executor(
+                    &TermQuery::new(term, IndexRecordOption::Basic),
+                    &TopDocs::with_limit(2),
+                    executor.borrow(),
+                )?;
+
+                match res.as_slice() {
+                    [] => Err(Error::msg("no document exists with this value")),
+                    [(_, address)] => Ok(searcher.doc(*address)?),
+                    _ => Err(Error::msg(
+                        "more than one document exists with this value, the field is not unique",
+                    )),
+                }
+            };
+
+            let _ = resolve.send(run());
+        });
+
+        let result = waiter.await.map_err(|_| Error::msg(WORKER_PANICKED))??;
+        let mut doc = self.schema.to_named_doc(&result);
+        format_decimal_doc_values(&mut doc, &self.decimal_fields);
+        strip_private_fields(&mut doc, &self.private_fields);
+
+        Ok(doc)
     }
 
-    /// Searches the index with a given query.
+    /// Checks which of the given document ids exist in the index, returning
+    /// a flag for each id in the same order.
     ///
-    /// The index will use fuzzy matching based on levenshtein distance
-    /// if set to true.
-    pub(super) async fn search(&self, payload: QueryPayload) -> Result<QueryResults> {
+    /// This looks the ids up in the term dictionary of each segment directly
+    /// which avoids running a search or retrieving any stored fields.
+    ///
+    /// This counts as a concurrent action.
+    pub(super) async fn exists(&self, ids: &[u64]) -> Result<Vec<bool>> {
         let _permit = self.limiter.acquire().await?;
 
+        let ids = ids.to_vec();
         let (resolve, waiter) = oneshot::channel();
+        let searcher = self.reader.read().searcher();
+        let field = self
+            .schema
+            .get_field(&self.id_field)
+            .ok_or_else(|| Error::msg("missing a required private field, this is a bug."))?;
 
-        let doc_id = match (self.schema.get_field("_id"), payload.document) {
-            (None, _) => Err(Error::msg(
-                "missing a required private field, this is a bug.",
-            )),
-            (_, None) => Ok(None),
-            (Some(field), Some(doc_id)) => Ok(Some(Term::from_field_u64(field, doc_id))),
-        }?;
+        self.thread_pool.spawn(move || {
+            let run = || -> Result<Vec<bool>> {
+                let addresses = lookup_docs(&searcher, field, &ids)?;
+                Ok(ids.iter().map(|id| addresses.contains_key(id)).collect())
+            };
 
-        let order_by = if let Some(ref field) = payload.order_by {
-            // We choose to ignore the order by if the field doesnt exist.
-            // While this may be surprising to be at first as long as it's
-            // document this should be fine.
-            self.schema.get_field(field)
-        } else {
-            None
-        };
+            let _ = resolve.send(run());
+        });
 
-        let schema = self.schema.clone();
-        let parser = self.parser.clone();
-        let limit = payload.limit;
-        let offset = payload.offset;
-        let mode = payload.mode;
-        let use_fast_fuzzy = self.use_fast_fuzzy && correction::enabled();
+        waiter.await.map_err(|_| Error::msg(WORKER_PANICKED))?
+    }
 
-        let strip_stop_words = self.strip_stop_words;
-        let search_fields = self.search_fields.clone();
-        let searcher = self.reader.searcher();
-        let executor = self.executor_pool.acquire()?;
+    /// Gets the documents with the given ids, returning them in the same
+    /// order as the ids with `None` for any ids which do not exist.
+    ///
+    /// This counts as a concurrent action.
+    pub(super) async fn get_docs(&self, ids: &[u64]) -> Result<Vec<Option<NamedFieldDocument>>> {
+        let _permit = self.limiter.acquire().await?;
+
+        let ids = ids.to_vec();
+        let (resolve, waiter) = oneshot::channel();
+        let searcher = self.reader.read().searcher();
+        let field = self
+            .schema
+            .get_field(&self.id_field)
+            .ok_or_else(|| Error::msg("missing a required private field, this is a bug."))?;
 
-        let start = std::time::Instant::now();
         self.thread_pool.spawn(move || {
-            let ref_document = match doc_id {
-                None => None,
-                Some(doc) => {
-                    let doc = try_get_doc!(resolve, searcher, doc, executor.borrow());
-                    Some(doc)
-                },
-            };
+            let run = || -> Result<HashMap<u64, tantivy::Document>> {
+                let addresses = lookup_docs(&searcher, field, &ids)?;
 
-            let query = match parse_query(
-                searcher.index(),
-                parser,
-                search_fields,
-                match (payload.query.is_some(), payload.map.is_empty()) {
-                    (true, _) => Some(Either::Left(payload.query.unwrap())),
-                    (_, false) => Some(Either::Right(payload.map)),
-                    _ => None
-                },
-                ref_document,
-                payload.mode,
-                use_fast_fuzzy,
-                strip_stop_words,
-            ) {
-                Err(e) => {
-                    info!("rejecting parse");
-                    let _ = resolve.send(Err(e));
-                    return;
-                },
-                Ok(q) => q,
+                let mut docs = HashMap::with_capacity(addresses.len());
+                for (id, address) in addresses {
+                    docs.insert(id, searcher.doc(address)?);
+                }
+
+                Ok(docs)
             };
 
-            let res = search(query, searcher, executor.borrow(), limit, offset, schema, order_by);
-            let _ = resolve.send(res);
+            let _ = resolve.send(run().map(|docs| (ids, docs)));
         });
 
-        let mut res = waiter.await??;
-        let time_taken = start.elapsed();
-        info!(
-            "[ SEARCH @ {} ] took {:?} with limit={}, mode={} and {} results total",
-            &self.name,
-            time_taken,
-            limit,
-            if let QueryMode::Fuzzy = mode {
-                if use_fast_fuzzy {
-                    "FastFuzzy".to_string()
-                } else {
-                    "Fuzzy".to_string()
-                }
-            } else {
-                format!("{:?}", mode)
-            },
-            res.count
-        );
+        let (ids, docs) = waiter.await.map_err(|_| Error::msg(WORKER_PANICKED))??;
 
-        res.time_taken = time_taken.as_secs_f32();
+        // The documents are retrieved in segment order so they're
+        // reordered to match the order of the given ids.
+        let ordered = ids
+            .iter()
+            .map(|id| {
+                docs.get(id).map(|doc| {
+                    let mut doc = self.schema.to_named_doc(doc);
+                    format_decimal_doc_values(&mut doc, &self.decimal_fields);
+                    strip_private_fields(&mut doc, &self.private_fields);
+                    doc
+                })
+            })
+            .collect();
 
-        Ok(res)
+        Ok(ordered)
     }
-}
 
-/// Generates a query from any of the 3 possible systems to
-/// query documents.
-fn parse_query(
-    index: &tantivy::Index,
-    parser: Arc<QueryParser>,
-    search_fields: Arc<Vec<(Field, Score)>>,
-    query: Option<Either<String, HashMap<String, String>>>,
-    ref_document: Option<DocAddress>,
-    mode: QueryMode,
-    use_fast_fuzzy: bool,
-    strip_stop_words: bool,
-) -> Result<Box<dyn Query>> {
-    let start = std::time::Instant::now();
-    let out = match (mode, &query, ref_document) {
-        (QueryMode::Normal, None, _) => Err(Error::msg(
-            "query mode was `Normal` but query string is `None`",
-        )),
-        (QueryMode::Normal, Some(Either::Left(query)), _) => Ok(parser.parse_query(query)?),
-        (QueryMode::Normal, Some(Either::Right(query)), _) => {
-            let queries = query.iter().map(|(field, query)| {
-                let field = match index.schema().get_field(field) {
-                    Some(f) => vec![f],
-                    None => {
-                        return Ok(None)
-                    }
-                };
+    /// Builds the filter from the mandatory filters of the payload which
+    /// every document must match if there are any.
+    fn parse_mandatory_filters(&self, filters: &[MandatoryFilter]) -> Result<Option<Box<dyn Query>>> {
+        let mut clauses = Vec::with_capacity(filters.len());
+        for filter in filters {
+            let field = self.schema.get_field(&filter.field).ok_or_else(|| {
+                Error::msg(format!("mandatory filter field {:?} does not exist", &filter.field))
+            })?;
 
-                let mut parser = QueryParser::for_index(index, field);
-                parser.set_conjunction_by_default();
-                match parser.parse_query(query) {
-                    Ok(q) => Ok(Some(q)),
-                    Err(err) => Err(anyhow::Error::new(err))
-                }
-            })
-            .filter_map(|s| s.transpose())
-            .collect::<Result<Vec<_>, _>>()?;
-            Ok(Box::new(BooleanQuery::intersection(queries)) as Box<dyn Query>)
-        },
-        (QueryMode::Fuzzy, None, _) => Err(Error::msg(
-            "query mode was `Fuzzy` but query string is `None`",
-        )),
-        (QueryMode::Fuzzy, Some(Either::Left(query)), _) => {
-            let qry = if use_fast_fuzzy {
-                parse_fast_fuzzy_query(query, search_fields, strip_stop_words)?
-            } else {
-                parse_fuzzy_query(query, search_fields)
-            };
-            Ok(qry)
-        },
-        (QueryMode::Fuzzy, Some(Either::Right(_)), _) => Err(Error::msg(
-            "query mode was `Fuzzy` but query string is `None`",
-        )),
-        (QueryMode::MoreLikeThis, _, None) => Err(Error::msg(
-            "query mode was `MoreLikeThis` but reference document is `None`",
-        )),
-        (QueryMode::MoreLikeThis, _, Some(ref_document)) => Ok(parse_more_like_this(ref_document)?),
+            let entry = self.schema.get_field_entry(field);
+            if !entry.is_indexed() {
+                return Err(Error::msg(format!(
+                    "mandatory filter field {:?} must be indexed",
+                    &filter.field
+                )));
+            }
 
-    };
+            let scale = self.decimal_fields.get(&filter.field).copied();
+            let term = value_to_term(field, entry.field_type(), scale, filter.value.clone())?;
+            let query: Box<dyn Query> = Box::new(TermQuery::new(term, IndexRecordOption::Basic));
+            clauses.push((Occur::Must, query));
+        }
+
+        let filter = match clauses.len() {
+            0 => None,
+            1 => clauses.pop().map(|(_, query)| query),
+            _ => Some(Box::new(BooleanQuery::new(clauses)) as Box<dyn Query>),
+        };
+
+        Ok(filter)
+    }
+
+    /// Runs the tokenizer the given field is indexed with over the text,
+    /// returning the tokens in order.
+    ///
+    /// These are the terms the text would be indexed as, which is useful
+    /// for finding out why a query doesn't match.
+    pub(super) fn analyze(&self, field: &str, text: &str) -> Result<Vec<String>> {
+        let field = self.schema.get_field(field).ok_or_else(|| {
+            Error::msg(format!("field {:?} does not exist", field))
+        })?;
+
+        let entry = self.schema.get_field_entry(field);
+        if !matches!(entry.field_type(), FieldType::Str(options) if options.get_indexing_options().is_some()) {
+            return Err(Error::msg(format!("field {:?} must be an indexed text field", entry.name())));
+        }
+
+        let analyzer = self.index.tokenizer_for_field(field)?;
+        let mut tokens = Vec::new();
+        analyzer.token_stream(text).process(&mut |token| {
+            tokens.push(token.text.clone());
+        });
+
+        Ok(tokens)
+    }
+
+    /// Resolves the values documents are excluded by to their terms, each
+    /// value is parsed according to the type of it's field.
+    fn parse_exclusions(&self, exclude: &HashMap<String, Vec<String>>) -> Result<Vec<Term>> {
+        let mut terms = Vec::new();
+        for (name, values) in exclude {
+            let field = self.schema.get_field(name).ok_or_else(|| {
+                Error::msg(format!("exclusion field {:?} does not exist", name))
+            })?;
+
+            let entry = self.schema.get_field_entry(field);
+            if !entry.is_indexed() {
+                return Err(Error::msg(format!("exclusion field {:?} must be indexed", name)));
+            }
+
+            let scale = self.decimal_fields.get(name).copied();
+            for value in values {
+                let invalid = || {
+                    Error::msg(format!("exclusion value {:?} is not a valid value of field {:?}", value, name))
+                };
+
+                let value = match entry.field_type() {
+                    _ if scale.is_some() => DocumentValue::Text(value.clone()),
+                    FieldType::Str(_) if is_raw_text_field(&self.index, field) => DocumentValue::Text(value.clone()),
+                    FieldType::Str(_) => {
+                        return Err(Error::msg(format!(
+                            "exclusion field {:?} is tokenized, only string fields can be excluded by value",
+                            name
+                        )));
+                    },
+                    FieldType::U64(_) => DocumentValue::U64(value.parse().map_err(|_| invalid())?),
+                    FieldType::I64(_) => DocumentValue::I64(value.parse().map_err(|_| invalid())?),
+                    FieldType::F64(_) => DocumentValue::F64(value.parse().map_err(|_| invalid())?),
+                    FieldType::Date(_) => match value.parse::<i64>() {
+                        Ok(timestamp) => DocumentValue::I64(timestamp),
+                        Err(_) => DocumentValue::Datetime(value.parse().map_err(|_| invalid())?),
+                    },
+                    _ => return Err(Error::msg(format!("field {:?} can not be excluded by value", name))),
+                };
+
+                terms.push(value_to_term(field, entry.field_type(), scale, value)?);
+            }
+        }
+
+        Ok(terms)
+    }
+
+    /// Resolves the fields a more like this query is restricted to.
+    ///
+    /// The terms are derived by re-analyzing the stored text of the
+    /// reference document so each field must be an indexed and stored
+    /// text field.
+    fn parse_more_like_this_fields(&self, names: &[String]) -> Result<Vec<Field>> {
+        if names.is_empty() {
+            return Err(Error::msg("more like this fields must not be empty"));
+        }
+
+        let mut fields = Vec::with_capacity(names.len());
+        for name in names {
+            let field = self.schema.get_field(name).ok_or_else(|| {
+                Error::msg(format!("more like this field {:?} does not exist", name))
+            })?;
+
+            let entry = self.schema.get_field_entry(field);
+            let is_indexed_text = matches!(
+                entry.field_type(),
+                FieldType::Str(options) if options.get_indexing_options().is_some()
+            );
+            if !is_indexed_text || !entry.is_stored() {
+                return Err(Error::msg(format!(
+                    "more like this field {:?} must be an indexed and stored text field",
+                    name
+                )));
+            }
+
+            fields.push(field);
+        }
+
+        Ok(fields)
+    }
+
+    /// Builds the filter requiring a document to contain one of the words
+    /// of the query at least `min_freq` times in one of the search fields.
+    ///
+    /// The words of `Normal` queries are the search field terms of the parsed
+    /// query which aren't excluded, otherwise each word is analyzed with the
+    /// tokenizer of each search field. The search fields must be indexed with
+    /// term frequencies.
+    fn parse_min_term_freq(&self, query: &str, mode: QueryMode, min_freq: u32) -> Result<MinTermFreqQuery> {
+        self.check_term_freqs()?;
+
+        // The query syntax is parsed so only the words being searched for are counted.
+        if let QueryMode::Normal = mode {
+            let mut terms = BTreeSet::new();
+            positive_terms(self.parser.parse_query(query)?.as_ref(), &mut terms);
+
+            let terms = terms
+                .into_iter()
+                .filter(|term| self.search_fields.iter().any(|(field, _)| term.field() == *field))
+                .collect();
+
+            return Ok(MinTermFreqQuery::new(terms, min_freq));
+        }
+
+        let mut terms = vec![];
+        for (field, _) in self.search_fields.iter() {
+            let analyzer = self.index.tokenizer_for_field(*field)?;
+            analyzer.token_stream(query).process(&mut |token| {
+                terms.push(Term::from_field_text(*field, &token.text));
+            });
+        }
+
+        Ok(MinTermFreqQuery::new(terms, min_freq))
+    }
+
+    /// Checks every search field is indexed with term frequencies.
+    fn check_term_freqs(&self) -> Result<()> {
+        for (field, _) in self.search_fields.iter() {
+            let entry = self.schema.get_field_entry(*field);
+            let has_freqs = match entry.field_type() {
+                FieldType::Str(options) => options
+                    .get_indexing_options()
+                    .map(|indexing| indexing.index_option().has_freq())
+                    .unwrap_or(false),
+                _ => false,
+            };
+
+            if !has_freqs {
+                return Err(Error::msg(format!(
+                    "min_term_freq requires the search fields to be indexed with term frequencies, {:?} is not",
+                    entry.name()
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Builds a range query from a given range filter.
+    ///
+    /// Decimal fields have their bounds scaled so they can be compared exactly.
+    fn parse_range(&self, range: RangeFilter) -> Result<RangeQuery> {
+        let field = self.schema.get_field(&range.field).ok_or_else(|| {
+            Error::msg(format!("range filter field {:?} does not exist", &range.field))
+        })?;
+
+        let entry = self.schema.get_field_entry(field);
+        if !entry.is_indexed() {
+            return Err(Error::msg(format!(
+                "range filter field {:?} must be indexed",
+                &range.field
+            )));
+        }
+
+        let field_type = entry.field_type();
+        let scale = self.decimal_fields.get(&range.field).copied();
+
+        // Both bounds are resolved against the same time so they're consistent.
+        let now = chrono::Utc::now();
+        let to_bound = |value: Option<DocumentValue>| -> Result<Bound<Term>> {
+            let value = match (field_type, value) {
+                (_, None) => return Ok(Bound::Unbounded),
+                (FieldType::Date(_), Some(DocumentValue::Text(expr))) => {
+                    DocumentValue::Datetime(resolve_relative_date(&expr, now)?)
+                },
+                (_, Some(value)) => value,
+            };
+
+            Ok(Bound::Included(value_to_term(field, field_type, scale, value)?))
+        };
+
+        let lower = to_bound(range.gte)?;
+        let upper = to_bound(range.lte)?;
+
+        Ok(RangeQuery::new_term_bounds(field, field_type.value_type(), &lower, &upper))
+    }
+
+    /// Shuts down the thread pools and acquires all permits
+    /// shutting the index down.
+    ///
+    /// Thread pools are shutdown asynchronously via Rayon's handling.
+    pub(super) async fn shutdown(&self) -> Result<()> {
+        // Wait till all searches have been completed.
+        let _ = self
+            .limiter
+            .acquire_many(self.max_concurrency as u32)
+            .await?;
+        self.limiter.close();
+        self.snapshot_limiter.close();
+        self.estimate_limiter.close();
+
+        self.executor_pool.shutdown();
+
+        Ok(())
+    }
+
+    /// Gets the capabilities of every public field of the schema, keyed
+    /// by the field name.
+    ///
+    /// This allows clients to only offer sorting on fields which can be
+    /// sorted, rather than discovering it from a failed search.
+    pub(super) fn field_capabilities(&self) -> HashMap<String, FieldCaps> {
+        let mut capabilities = HashMap::new();
+        for (_, entry) in self.schema.fields() {
+            let name = entry.name();
+            if self.private_fields.contains(name) {
+                continue;
+            }
+
+            let field_type = if self.decimal_fields.contains_key(name) {
+                "decimal"
+            } else if self.vector_fields.contains_key(name) {
+                "vector"
+            } else {
+                match entry.field_type() {
+                    FieldType::Str(opts) => match opts.get_indexing_options() {
+                        Some(indexing) if indexing.tokenizer() == "raw" => "string",
+                        _ => "text",
+                    },
+                    FieldType::U64(_) => "u64",
+                    FieldType::I64(_) => "i64",
+                    FieldType::F64(_) => "f64",
+                    FieldType::Date(_) => "date",
+                    FieldType::HierarchicalFacet(_) => "facet",
+                    FieldType::Bytes(_) => "bytes",
+                }
+            };
+
+            // Only single value fast fields can be used to order results.
+            let fast = entry.is_fast();
+            let sortable = !self.vector_fields.contains_key(name)
+                && matches!(fast_field_cardinality(entry.field_type()), Some(Cardinality::SingleValue));
+
+            capabilities.insert(name.to_string(), FieldCaps {
+                field_type,
+                indexed: entry.is_indexed(),
+                stored: entry.is_stored(),
+                fast,
+                sortable,
+            });
+        }
+
+        capabilities
+    }
+
+    /// Searches the index with a given query.
+    ///
+    /// The index will use fuzzy matching based on levenshtein distance
+    /// if set to true.
+    pub(super) async fn search(&self, payload: QueryPayload) -> Result<QueryResults> {
+        // The searcher is only taken once the permit has been acquired.
+        let permit = self.limiter.clone().acquire_owned().await?;
+        self.run_search(payload, permit, self.snapshot()).await
+    }
+
+    /// Searches the index with a given query if the index is not
+    /// already running it's maximum amount of concurrent actions.
+    ///
+    /// Rather than waiting for a concurrent action to finish, `None`
+    /// is returned immediately if the index is at capacity.
+    pub(super) async fn try_search(&self, payload: QueryPayload) -> Result<Option<QueryResults>> {
+        let permit = match self.limiter.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(TryAcquireError::NoPermits) => {
+                debug!("[ SEARCH @ {} ] rejecting search, the index is at capacity", &self.name);
+                return Ok(None);
+            },
+            Err(TryAcquireError::Closed) => return Err(Error::msg("the index is shutting down")),
+        };
+
+        let (resolve, waiter) = oneshot::channel();
+        self.spawn_search(payload, permit, self.snapshot(), None, move |res| {
+            let _ = resolve.send(res);
+        }).await?;
+
+        waiter.await.map_err(|_| Error::msg(WORKER_PANICKED))?.map(Some)
+    }
+
+    /// Takes a point in time view of the index for a single operation.
+    ///
+    /// This must only be called while holding a concurrency permit, the
+    /// reader has one searcher per permit so this never waits on the pool.
+    fn snapshot(&self) -> SearcherSnapshot {
+        SearcherSnapshot(Arc::new(self.reader.read().searcher()), None)
+    }
+
+    /// Takes a point in time view of the index which can be searched
+    /// repeatedly, even after the reader has been reloaded.
+    ///
+    /// The snapshot occupies one of the reader's searchers until it has
+    /// been dropped or the reader is reloaded, so at most `MAX_SNAPSHOTS`
+    /// can be alive at once, taking any more fails immediately rather than
+    /// waiting for a snapshot to be dropped.
+    pub(super) fn take_snapshot(&self) -> Result<SearcherSnapshot> {
+        let permit = match self.snapshot_limiter.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(TryAcquireError::NoPermits) => {
+                return Err(Error::msg(format!(
+                    "the index already has the maximum of {} snapshots alive, drop an existing snapshot first",
+                    MAX_SNAPSHOTS,
+                )))
+            },
+            Err(TryAcquireError::Closed) => return Err(Error::msg("the index is shutting down")),
+        };
+
+        let searcher = self.reader.read().searcher();
+        Ok(SearcherSnapshot(Arc::new(searcher), Some(Arc::new(permit))))
+    }
+
+    /// Searches the given snapshot of the index with a given query.
+    pub(super) async fn search_snapshot(
+        &self,
+        payload: QueryPayload,
+        searcher: SearcherSnapshot,
+    ) -> Result<QueryResults> {
+        let permit = self.limiter.clone().acquire_owned().await?;
+        self.run_search(payload, permit, searcher).await
+    }
+
+    /// Runs the search on the thread pool and waits for it's results.
+    async fn run_search(
+        &self,
+        payload: QueryPayload,
+        permit: OwnedSemaphorePermit,
+        searcher: SearcherSnapshot,
+    ) -> Result<QueryResults> {
+        let (resolve, waiter) = oneshot::channel();
+        self.spawn_search(payload, permit, searcher, None, move |res| {
+            let _ = resolve.send(res);
+        }).await?;
+
+        waiter.await.map_err(|_| Error::msg(WORKER_PANICKED))?
+    }
+
+    /// Runs the same query against the current searcher and the given
+    /// snapshot, returning which documents were added, removed or moved
+    /// rank in the current results compared to the snapshot.
+    ///
+    /// This is intended for validating relevance changes e.g. before and
+    /// after re-indexing.
+    pub(super) async fn diff(
+        &self,
+        payload: QueryPayload,
+        snapshot: SearcherSnapshot,
+    ) -> Result<ResultsDiff> {
+        let current = self.search(payload.clone()).await?;
+        let previous = self.search_snapshot(payload, snapshot).await?;
+
+        let previous_ranks: HashMap<&str, usize> = previous
+            .hits
+            .iter()
+            .enumerate()
+            .map(|(rank, hit)| (hit.document_id.as_str(), rank))
+            .collect();
+
+        let mut diff = ResultsDiff::default();
+        for (rank, hit) in current.hits.iter().enumerate() {
+            match previous_ranks.get(hit.document_id.as_str()) {
+                None => diff.added.push(hit.document_id.clone()),
+                Some(previous_rank) if *previous_rank != rank => diff.moved.push(RankChange {
+                    document_id: hit.document_id.clone(),
+                    previous_rank: *previous_rank,
+                    rank,
+                }),
+                _ => {},
+            }
+        }
+
+        let current_ids: HashSet<&str> =
+            current.hits.iter().map(|hit| hit.document_id.as_str()).collect();
+        for hit in previous.hits.iter() {
+            if !current_ids.contains(hit.document_id.as_str()) {
+                diff.removed.push(hit.document_id.clone());
+            }
+        }
+
+        Ok(diff)
+    }
+
+    /// Searches the index with a given query streaming each hit as
+    /// a newline delimited JSON object.
+    ///
+    /// Documents are only fetched as the stream is consumed so the hits
+    /// are never buffered in full, if the search fails the error is sent
+    /// as the last item of the stream.
+    ///
+    /// The search holds its concurrency permit until the stream has been
+    /// fully consumed or dropped.
+    pub(super) async fn search_stream(
+        &self,
+        payload: QueryPayload,
+    ) -> Result<impl Stream<Item = Result<Bytes>>> {
+        let permit = self.limiter.clone().acquire_owned().await?;
+
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER_SIZE);
+        let errors = tx.clone();
+        self.spawn_search(payload, permit, self.snapshot(), Some(tx), move |res| {
+            if let Err(e) = res {
+                let _ = errors.blocking_send(Err(e));
+            }
+        }).await?;
+
+        Ok(stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|item| (item, rx))
+        }))
+    }
+
+    /// Parses the query of the payload without executing a search,
+    /// returning the debug representation of the built query.
+    ///
+    /// This counts as a concurrent action.
+    pub(super) async fn explain_query(&self, payload: QueryPayload) -> Result<String> {
+        self.with_parsed_query(payload, |_, query| Ok(format!("{:?}", query)))
+            .await
+    }
+
+    /// Estimates how expensive the search of the given payload would be
+    /// by parsing it's query without executing it.
+    ///
+    /// This doesn't count as a concurrent action so expensive queries can
+    /// be rejected before they occupy a worker, the query is parsed on a
+    /// blocking thread and only the term dictionaries are read to get the
+    /// document frequencies.
+    pub(super) async fn estimate_cost(&self, payload: &QueryPayload) -> Result<QueryCost> {
+        let _permit = self.estimate_limiter.acquire().await?;
+        let mut payload = payload.clone();
+        self.preprocess(&mut payload)?;
+        let inputs = self.query_inputs(&mut payload)?;
+
+        // The estimate permit guarantees one of the reserved searchers is free.
+        let searcher = self.reader.read().searcher();
+        let parser = self.parser.clone();
+        let search_fields = self.search_fields.clone();
+
+        // Parsing can load reference documents, correct words and read the
+        // term dictionaries so it mustn't block the runtime.
+        tokio::task::spawn_blocking(move || {
+            let (query, fuzzy_clauses) = inputs.parse(&searcher, parser, search_fields, &Executor::single_thread())?;
+            QueryCost::estimate(&searcher, query.as_ref(), fuzzy_clauses)
+        })
+        .await?
+    }
+
+    /// Searches a single segment of the index with the query of the
+    /// payload, returning the ids and scores of the top hits along with
+    /// the segment's statistics.
+    ///
+    /// This is a debugging tool for issues isolated to one segment, the
+    /// hits are scored with the statistics of the whole index and only
+    /// the query itself is applied, not the filters of the payload.
+    pub(super) async fn search_segment(&self, payload: QueryPayload, segment_id: &str) -> Result<SegmentResults> {
+        let id_field = self
+            .schema
+            .get_field(&self.id_field)
+            .ok_or_else(|| Error::msg("missing a required private field, this is a bug."))?;
+        let segment_id = segment_id.to_string();
+        let limit = payload.limit;
+
+        self.with_parsed_query(payload, move |searcher, query| {
+            let (segment_ord, segment) = searcher
+                .segment_readers()
+                .iter()
+                .enumerate()
+                .find(|(_, reader)| reader.segment_id().uuid_string() == segment_id)
+                .ok_or_else(|| Error::msg(format!("segment {:?} does not exist", &segment_id)))?;
+
+            // `TopDocs` panics with a limit of 0.
+            let collector = (TopDocs::with_limit(limit.max(1)), Count);
+            let weight = query.weight(searcher, true)?;
+            let fruit = collector.collect_segment(weight.as_ref(), segment_ord as u32, segment)?;
+            let (top_docs, count) = collector.merge_fruits(vec![fruit])?;
+
+            let ids = segment.fast_fields().u64(id_field)?;
+            let hits = top_docs
+                .into_iter()
+                .take(limit)
+                .map(|(score, address)| SegmentHit {
+                    document_id: ids.get(address.doc_id).to_string(),
+                    doc: address.doc_id,
+                    score,
+                })
+                .collect();
+
+            Ok(SegmentResults {
+                segment_id,
+                num_docs: segment.num_docs(),
+                num_deleted_docs: segment.num_deleted_docs(),
+                count,
+                hits,
+            })
+        })
+        .await
+    }
+
+    /// Parses the query of the payload on the thread pool and runs the
+    /// given function with the parsed query, returning it's result.
+    ///
+    /// This counts as a concurrent action.
+    async fn with_parsed_query<T, F>(&self, mut payload: QueryPayload, run: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Searcher, Box<dyn Query>) -> Result<T> + Send + 'static,
+    {
+        let permit = self.limiter.clone().acquire_owned().await?;
+        self.preprocess(&mut payload)?;
+        let inputs = self.query_inputs(&mut payload)?;
+
+        let parser = self.parser.clone();
+        let search_fields = self.search_fields.clone();
+        let searcher = self.reader.read().searcher();
+        let executor = self.executor_pool.acquire_kind(payload.parallelism).await?;
+
+        let (resolve, waiter) = oneshot::channel();
+        self.thread_pool.spawn(move || {
+            // The executor is returned to the pool before the permit is released.
+            let _permit = permit;
+            let executor = executor;
+
+            let res = inputs
+                .parse(&searcher, parser, search_fields, executor.borrow())
+                .and_then(|(query, _)| run(&searcher, query));
+
+            let _ = resolve.send(res);
+        });
+
+        waiter.await.map_err(|_| Error::msg(WORKER_PANICKED))?
+    }
+
+    /// Takes the parts of the payload which are needed to parse
+    /// the query, validating any referenced fields.
+    fn query_inputs(&self, payload: &mut QueryPayload) -> Result<QueryInputs> {
+        let doc_id = match (self.schema.get_field(&self.id_field), payload.document) {
+            (None, _) => Err(Error::msg(
+                "missing a required private field, this is a bug.",
+            )),
+            (_, None) => Ok(None),
+            (Some(field), Some(doc_id)) => Ok(Some(Term::from_field_u64(field, doc_id))),
+        }?;
+
+        let custom_query = match payload.custom_query {
+            None => None,
+            Some(ref name) => Some(self.custom_queries.read().get(name).cloned().ok_or_else(|| {
+                Error::msg(format!("no custom query is registered with the name {:?}", name))
+            })?),
+        };
+
+        let mut constant_fields = HashSet::with_capacity(payload.constant_score_fields.len());
+        for name in payload.constant_score_fields.iter() {
+            constant_fields.insert(self.schema.get_field(name).ok_or_else(|| {
+                Error::msg(format!("constant score field {:?} does not exist", name))
+            })?);
+        }
+
+        // The query vector is used by vector queries and hybrid searches.
+        let uses_vector = payload.hybrid.is_some() || matches!(payload.mode, QueryMode::Vector);
+        let vector = match payload.vector.take() {
+            Some(vector) if uses_vector => {
+                let name = payload
+                    .vector_field
+                    .as_ref()
+                    .ok_or_else(|| Error::msg("a query vector was given but vector field is `None`"))?;
+
+                let dims = self.vector_fields.get(name).ok_or_else(|| {
+                    Error::msg(format!("field {:?} is not a vector field", name))
+                })?;
+
+                if vector.len() != *dims {
+                    return Err(Error::msg(format!(
+                        "vector field {:?} expects {} dimensions but the query vector has {}",
+                        name,
+                        dims,
+                        vector.len()
+                    )));
+                }
+
+                let field = self
+                    .schema
+                    .get_field(name)
+                    .ok_or_else(|| Error::msg(format!("vector field {:?} does not exist", name)))?;
+
+                Some(VectorQuery::new(field, vector, payload.similarity))
+            },
+            _ => None,
+        };
+
+        let mandatory_filter = self.parse_mandatory_filters(&payload.mandatory_filters)?;
+        let exclusions = self.parse_exclusions(&payload.exclude)?;
+        let more_like_this_fields = match payload.more_like_this_fields.take() {
+            None => None,
+            Some(names) => Some(self.parse_more_like_this_fields(&names)?),
+        };
+
+        let dis_max = match payload.field_combination {
+            FieldCombination::Sum => None,
+            FieldCombination::Max if !(0.0..=1.0).contains(&payload.tie_breaker) => {
+                return Err(Error::msg(format!(
+                    "tie breaker must be between 0 and 1, got {}",
+                    payload.tie_breaker
+                )));
+            },
+            FieldCombination::Max => Some(payload.tie_breaker),
+        };
+
+        // The dictionary may still be loading in which case the standard
+        // fuzzy system is used until it's ready.
+        let wants_fast_fuzzy = self.use_fast_fuzzy && !payload.disable_correction && !payload.analyze_query;
+        let use_fast_fuzzy = wants_fast_fuzzy && correction::enabled();
+        if wants_fast_fuzzy && !use_fast_fuzzy {
+            debug!(
+                "[ SEARCH @ {} ] the fast fuzzy dictionary is not loaded, using the standard fuzzy system",
+                &self.name
+            );
+        }
+
+        let correction_verbosity = match payload.correction_verbosity {
+            Some(CorrectionVerbosity::Top) if payload.correction_candidates > 1 => {
+                return Err(Error::msg(
+                    "the `top` correction verbosity only selects a single candidate, use `closest` or `all` for more correction_candidates",
+                ))
+            },
+            Some(verbosity) => verbosity,
+            None if payload.correction_candidates > 1 => CorrectionVerbosity::Closest,
+            None => CorrectionVerbosity::Top,
+        };
+
+        let options = ParseOptions {
+            use_fast_fuzzy,
+            analyze_query: payload.analyze_query,
+            strip_stop_words: self.strip_stop_words,
+            correction_candidates: payload.correction_candidates,
+            correction_verbosity,
+            correction_penalty: payload.correction_penalty,
+            autocomplete: payload.autocomplete,
+            tokenizer_overrides: std::mem::take(&mut payload.tokenizers),
+            custom_query,
+            constant_fields,
+            max_fuzzy_terms: self.max_fuzzy_terms,
+            bm25: self.bm25,
+            stemming: payload.stemming,
+            vector,
+            mandatory_filter,
+            exclusions,
+            term_boosts: std::mem::take(&mut payload.term_boosts)
+                .into_iter()
+                .map(|(term, boost)| (term.to_lowercase(), boost))
+                .collect(),
+            min_fuzzy_len: payload.min_fuzzy_len,
+            synonyms: synonym_map(std::mem::take(&mut payload.synonyms))?,
+            more_like_this_fields,
+            fold_diacritics: payload.fold_diacritics,
+            dis_max,
+        };
+
+        let query = match (payload.query.take(), payload.map.is_empty()) {
+            (Some(query), _) => Some(Either::Left(query)),
+            (None, false) => Some(Either::Right(std::mem::take(&mut payload.map))),
+            _ => None
+        };
+
+        Ok(QueryInputs {
+            doc_id,
+            query,
+            mode: payload.mode,
+            options,
+        })
+    }
+
+    /// Prepares a search and spawns it on the thread pool, the result
+    /// is given to `on_complete` once the search has finished.
+    ///
+    /// If `stream` is set the hits are sent to it rather than
+    /// being collected into the results.
+    async fn spawn_search<F>(
+        &self,
+        mut payload: QueryPayload,
+        permit: OwnedSemaphorePermit,
+        searcher: SearcherSnapshot,
+        stream: Option<mpsc::Sender<Result<Bytes>>>,
+        on_complete: F,
+    ) -> Result<()>
+    where
+        F: FnOnce(Result<QueryResults>) + Send + 'static,
+    {
+        self.preprocess(&mut payload)?;
+
+        let query_string = payload.query.clone();
+        let highlight_query = payload.query.clone();
+        let echo = if payload.echo {
+            Some(QueryEcho::from_payload(&payload))
+        } else {
+            None
+        };
+        let QueryInputs {
+            doc_id,
+            query: query_input,
+            mode,
+            options,
+        } = self.query_inputs(&mut payload)?;
+
+        // The vector half of a hybrid search is restricted like the text query.
+        let options_vector = options
+            .vector
+            .clone()
+            .map(|vector| restrict_query(Box::new(vector), &options));
+
+        let order_by = match payload.order_by.as_deref() {
+            None | Some(SCORE_SORT_KEY) => None,
+
+            // Strict ordering rejects fields which cannot be ordered by
+            // rather than hiding typos behind relevance ordering.
+            Some(field) if payload.strict_order_by => Some(NumericColumn::validate(&self.schema, field)?),
+
+            // We choose to ignore the order by if the field doesnt exist.
+            // While this may be surprising to be at first as long as it's
+            // document this should be fine.
+            Some(field) => self.schema.get_field(field),
+        };
+
+        // The hits of a search can only be ranked one way.
+        let rankings: Vec<&str> = [
+            ("order_by", order_by.is_some()),
+            ("sort", !payload.sort.is_empty()),
+            ("boost_field", payload.boost_field.is_some()),
+            ("recency", payload.recency.is_some()),
+            ("hybrid", payload.hybrid.is_some()),
+            ("group_by", payload.group_by.is_some()),
+            ("shuffle_seed", payload.shuffle_seed.is_some()),
+            ("collapse", payload.collapse.is_some()),
+        ]
+        .iter()
+        .filter(|(_, requested)| *requested)
+        .map(|(name, _)| *name)
+        .collect();
+        if rankings.len() > 1 {
+            return Err(Error::msg(format!(
+                "{} cannot be combined, the hits can only be ranked one way",
+                rankings.join(", ")
+            )));
+        }
+
+        // Every filter must match along side the query.
+        let mut filters: Vec<Box<dyn Query>> = Vec::with_capacity(payload.ranges.len() + 2);
+        if let Some(ref field) = payload.exists {
+            let field = self.schema.get_field(field).ok_or_else(|| {
+                Error::msg(format!("exists filter field {:?} does not exist", field))
+            })?;
+            filters.push(Box::new(ExistsQuery::new(&self.schema, field)?));
+        }
+
+        for range in payload.ranges {
+            filters.push(Box::new(self.parse_range(range)?));
+        }
+
+        if let Some(min_freq) = payload.min_term_freq {
+            if !matches!(mode, QueryMode::Fuzzy | QueryMode::Normal) {
+                return Err(Error::msg("min_term_freq requires the `Fuzzy` or `Normal` query mode"));
+            }
+
+            let query = query_string
+                .as_deref()
+                .ok_or_else(|| Error::msg("min_term_freq requires a query string"))?;
+            filters.push(Box::new(self.parse_min_term_freq(query, mode, min_freq)?));
+        }
+
+        if let Some(ref token) = payload.within {
+            filters.push(Box::new(self.result_cache.within(token, &searcher)?));
+        }
+
+        let ranking = if let Some(field) = order_by {
+            Ranking::OrderBy(field)
+        } else if let Some(ref name) = payload.boost_field {
+            Ranking::Boost(FieldValueBoost::new(
+                NumericColumn::validate(&self.schema, name)?,
+                payload.max_field_boost,
+            ))
+        } else if let Some(recency) = payload.recency {
+            if !matches!(mode, QueryMode::MoreLikeThis) {
+                return Err(Error::msg("recency boosts require the `MoreLikeThis` query mode"));
+            }
+
+            let field = NumericColumn::validate(&self.schema, &recency.field)?;
+            if self.schema.get_field_entry(field).field_type().value_type() != Type::Date {
+                return Err(Error::msg(format!(
+                    "recency field {:?} must be a date field",
+                    &recency.field
+                )));
+            }
+
+            if recency.half_life == 0 {
+                return Err(Error::msg("recency half_life must be greater than 0"));
+            }
+
+            Ranking::Boost(FieldValueBoost::recency(
+                field,
+                chrono::Utc::now().timestamp(),
+                recency.half_life,
+                recency.weight.max(0.0),
+            ))
+        } else if let Some(hybrid) = payload.hybrid {
+            if matches!(mode, QueryMode::Vector) {
+                return Err(Error::msg("hybrid searches require a text query mode, not `Vector`"));
+            }
+
+            if !(0.0..=1.0).contains(&hybrid.vector_weight) {
+                return Err(Error::msg("hybrid vector_weight must be between 0 and 1"));
+            }
+
+            let vector = options_vector.ok_or_else(|| {
+                Error::msg("hybrid searches require a query vector and vector field")
+            })?;
+
+            Ranking::Hybrid(HybridSearch {
+                vector: with_filters(vector, &filters),
+                options: hybrid,
+            })
+        } else if let Some(ref name) = payload.group_by {
+            if stream.is_some() {
+                return Err(Error::msg("grouped searches cannot be streamed"));
+            }
+
+            if payload.per_group_limit > MAX_GROUP_HITS {
+                return Err(Error::msg(format!("per_group_limit cannot be more than {}", MAX_GROUP_HITS)));
+            }
+
+            Ranking::Group(Grouping {
+                field: NumericColumn::validate(&self.schema, name)?,
+                groups_limit: payload.groups_limit,
+                per_group_limit: payload.per_group_limit,
+            })
+        } else if !payload.sort.is_empty() {
+            let mut keys = Vec::with_capacity(payload.sort.len());
+            for key in payload.sort.iter() {
+                let field = match key.field.as_str() {
+                    SCORE_SORT_KEY => SortField::Score,
+                    name => SortField::Field(NumericColumn::validate(&self.schema, name)?),
+                };
+                keys.push((field, key.order));
+            }
+
+            Ranking::Sort(keys)
+        } else if let Some(seed) = payload.shuffle_seed {
+            let field = self
+                .schema
+                .get_field(&self.id_field)
+                .ok_or_else(|| Error::msg("missing a required private field, this is a bug."))?;
+            Ranking::Shuffle(ShuffledTies::new(field, seed))
+        } else if let Some(ref name) = payload.collapse {
+            if stream.is_some() {
+                return Err(Error::msg("collapsed searches cannot be streamed"));
+            }
+
+            if payload.collapse_inner_hits >= MAX_GROUP_HITS {
+                return Err(Error::msg(format!(
+                    "collapse_inner_hits must be less than {}",
+                    MAX_GROUP_HITS
+                )));
+            }
+
+            Ranking::Collapse(Collapse {
+                field: NumericColumn::validate(&self.schema, name)?,
+                inner_hits: payload.collapse_inner_hits,
+            })
+        } else {
+            Ranking::Score
+        };
+
+        let histogram = match payload.date_histogram {
+            None => DateHistogram::disabled(),
+            Some(ref options) if options.utc_offset.unsigned_abs() >= 24 * 60 => {
+                return Err(Error::msg("date histogram utc_offset must be less than a day"))
+            },
+            Some(ref options) => {
+                let field = NumericColumn::validate(&self.schema, &options.field)?;
+                if self.schema.get_field_entry(field).field_type().value_type() != Type::Date {
+                    return Err(Error::msg(format!(
+                        "date histogram field {:?} must be a date field",
+                        &options.field
+                    )));
+                }
+
+                DateHistogram::new(field, options.interval, options.utc_offset as i64 * 60)
+            },
+        };
+
+        let result_cache = self.result_cache.clone();
+        let recorder = MatchRecorder::new(payload.cache_results);
+
+        let name = self.name.clone();
+        let query_logger = self.query_logger.read().clone();
+        let schema = self.schema.clone();
+        let id_field = self.id_field.clone();
+        let private_fields = self.private_fields.clone();
+        let decimal_fields = self.decimal_fields.clone();
+        let skip_malformed_docs = self.skip_malformed_docs;
+        let term_vectors = if payload.term_vectors.is_empty() {
+            None
+        } else {
+            Some(TermVectorExtractor::create(&self.index, payload.term_vectors)?)
+        };
+        let highlight = payload.highlight;
+        let return_fields: Option<HashSet<String>> = match payload.return_fields {
+            None => None,
+            Some(names) => {
+                for name in names.iter() {
+                    let field = self.schema.get_field(name).ok_or_else(|| {
+                        Error::msg(format!("return field {:?} does not exist", name))
+                    })?;
+
+                    if !self.schema.get_field_entry(field).is_stored() {
+                        return Err(Error::msg(format!("return field {:?} must be stored", name)));
+                    }
+                }
+
+                Some(names.into_iter().collect())
+            },
+        };
+        let match_positions = payload.match_positions;
+        let parser = self.parser.clone();
+        let limit = payload.limit;
+        let offset = payload.offset;
+        let timeout = payload.timeout.map(Duration::from_millis);
+        let debug = payload.debug;
+        let cache_results = payload.cache_results;
+        let explain = payload.explain;
+        let explain_top = payload.explain_top;
+        let count_cap = payload.count_cap;
+        let max_scored_docs = payload.max_scored_docs;
+        let scoring = payload.scoring;
+        let include_total_docs = payload.include_total_docs;
+        let previous_hash = payload.previous_hash;
+        let include_results_hash = payload.include_results_hash || previous_hash.is_some();
+        if previous_hash.is_some() && stream.is_some() {
+            return Err(Error::msg("previous_hash cannot be used with streamed searches"));
+        }
+        let field_aliases = payload.field_aliases;
+        if field_aliases.values().any(|alias| alias == self.id_field.as_str()) {
+            return Err(Error::msg(format!(
+                "fields cannot be aliased to the id field {:?}",
+                self.id_field.as_str()
+            )));
+        }
+        let mut aliases = HashSet::with_capacity(field_aliases.len());
+        for alias in field_aliases.values() {
+            // A field which isn't renamed itself would be overwritten by the alias.
+            if self.schema.get_field(alias).is_some() && !field_aliases.contains_key(alias) {
+                return Err(Error::msg(format!(
+                    "alias {:?} collides with an existing field of the same name",
+                    alias
+                )));
+            }
+
+            if !aliases.insert(alias) {
+                return Err(Error::msg(format!("multiple fields are aliased to {:?}", alias)));
+            }
+        }
+        let relative_min_score = match payload.relative_min_score {
+            Some(ratio) if !(0.0..=1.0).contains(&ratio) => {
+                return Err(Error::msg("relative_min_score must be between 0 and 1"))
+            },
+            // Only the rankings ordered by score can drop the trailing hits.
+            Some(_) if !matches!(
+                ranking,
+                Ranking::Score | Ranking::Boost(_) | Ranking::Hybrid(_) | Ranking::Shuffle(_)
+            ) => {
+                return Err(Error::msg(
+                    "relative_min_score cannot be used when the hits are ordered, sorted, grouped or collapsed",
+                ))
+            },
+            ratio => ratio,
+        };
+
+        let escalation = match (payload.min_results, &query_input) {
+            (None, _) => None,
+            (Some(_), _) if options.custom_query.is_some() => {
+                return Err(Error::msg("min_results cannot be used with a custom query"))
+            },
+            // The retries parse the query string as plain words, any other
+            // mode's query syntax would be lost.
+            (Some(_), _) if !matches!(mode, QueryMode::Fuzzy) => {
+                return Err(Error::msg("min_results can only be used with the `Fuzzy` query mode"))
+            },
+            (Some(min_results), Some(Either::Left(query))) => {
+                // The standard fuzzy system already tolerates a single typo.
+                let first_distance = if options.use_fast_fuzzy { 1 } else { 2 };
+
+                Some(Escalation {
+                    min_results,
+                    query: query.clone(),
+                    first_distance,
+                    search_fields: self.search_fields.clone(),
+                })
+            },
+            (Some(_), _) => return Err(Error::msg("min_results requires a query string")),
+        };
+
+        // The name of the query system which actually parses the query,
+        // this is resolved from the same options the query is parsed with.
+        let mode_name = if options.custom_query.is_some() {
+            "Custom".to_string()
+        } else {
+            match mode {
+                QueryMode::Fuzzy if options.use_fast_fuzzy => "FastFuzzy".to_string(),
+                mode => format!("{:?}", mode),
+            }
+        };
+
+        let search_fields = self.search_fields.clone();
+        let fuzzy_fields = self.search_fields.clone();
+        let executor = self.executor_pool.acquire_kind(payload.parallelism).await?;
+
+        // The overflow executors are single-threaded so a parallel search
+        // served by one is reported as having fallen back.
+        let parallel_fallback = matches!(payload.parallelism, Some(ExecutorKind::Parallel)) && executor.is_overflow();
+
+        let start = std::time::Instant::now();
+        let limits = SearchLimits::new(
+            Deadline::new(timeout.map(|timeout| start + timeout)),
+            DocBudget::new(max_scored_docs),
+        );
+        self.thread_pool.spawn(move || {
+            // The permit is held until every hit has been processed and
+            // the executor has been returned to the pool, locals are dropped
+            // in reverse order so the executor is always returned first.
+            let _permit = permit;
+            let executor = executor;
+
+            let run = || -> Result<QueryResults> {
+                let ref_document = match doc_id {
+                    None => None,
+                    Some(doc) => Some(lookup_doc(
+                        &searcher,
+                        doc,
+                        options.mandatory_filter.as_ref(),
+                        executor.borrow(),
+                    )?),
+                };
+
+                // A reference document without any usable text would either
+                // match nothing or fail within tantivy so it's short circuited.
+                let note = match ref_document {
+                    Some(address)
+                        if matches!(mode, QueryMode::MoreLikeThis)
+                            && !has_interesting_terms(
+                                &searcher,
+                                address,
+                                options.more_like_this_fields.as_deref(),
+                            )? =>
+                    {
+                        Some(
+                            "the reference document has no indexed text to compare against, no similar documents can be found"
+                                .to_string(),
+                        )
+                    },
+                    _ => None,
+                };
+
+                let query = if note.is_some() {
+                    Box::new(EmptyQuery) as Box<dyn Query>
+                } else {
+                    parse_query(
+                        &searcher,
+                        parser,
+                        search_fields,
+                        query_input,
+                        ref_document,
+                        mode,
+                        &options,
+                    )
+                    .map_err(|e| {
+                        info!("rejecting parse");
+                        e
+                    })?
+                    .0
+                };
+
+                let query = with_filters(query, &filters);
+
+                let (query, escalated) = match escalation {
+                    Some(ref escalation) if note.is_none() => {
+                        escalation.apply(&searcher, executor.borrow(), query, &filters, &options, &limits)?
+                    },
+                    _ => (query, false),
+                };
+
+                let query: Box<dyn Query> = if scoring {
+                    query
+                } else {
+                    Box::new(ConstantScoreQuery::new(query, 1.0))
+                };
+
+                let highlighter = match highlight {
+                    None => None,
+                    Some(options) => Some(Highlighter::create(
+                        &searcher,
+                        query.as_ref(),
+                        highlight_query.as_deref(),
+                        options,
+                        return_fields.as_ref(),
+                    )?),
+                };
+
+                let position_finder = if match_positions.is_empty() {
+                    None
+                } else {
+                    Some(Highlighter::create(
+                        &searcher,
+                        query.as_ref(),
+                        highlight_query.as_deref(),
+                        HighlightOptions::with_fields(match_positions),
+                        None,
+                    )?)
+                };
+
+                let fuzzy_matcher = match (debug, mode, highlight_query.as_deref()) {
+                    (true, QueryMode::Fuzzy, Some(query)) => Some(FuzzyMatcher::create(
+                        searcher.index(),
+                        fuzzy_fields.as_ref(),
+                        query,
+                    )),
+                    _ => None,
+                };
+
+                let explainer = if explain {
+                    Some(ScoreExplainer::create(
+                        &searcher,
+                        query.as_ref(),
+                        fuzzy_fields.as_ref(),
+                        options.bm25,
+                        &options.constant_fields,
+                    ))
+                } else {
+                    None
+                };
+
+                let top_explainer = match explain_top {
+                    Some(top) if top > 0 => Some(TopHitsExplainer::create(query.as_ref(), top)),
+                    _ => None,
+                };
+
+                let ctx = HitContext {
+                    schema,
+                    id_field,
+                    private_fields,
+                    explainer,
+                    top_explainer,
+                    decimal_fields,
+                    highlighter,
+                    position_finder,
+                    fuzzy_matcher,
+                    stream,
+                    field_aliases,
+                    return_fields,
+                    skip_malformed_docs,
+                    skipped_docs: Cell::new(0),
+                    term_vectors,
+                };
+
+                let request = SearchRequest {
+                    limit,
+                    offset,
+                    ranking,
+                    limits,
+                    recorder: recorder.clone(),
+                    histogram,
+                    count_cap,
+                    relative_min_score,
+                    debug,
+                };
+                let mut res = search(query, &searcher, executor.borrow(), ctx, request)?;
+
+                res.note = note;
+                res.echo = echo;
+                res.escalated = escalated;
+                res.parallel_fallback = parallel_fallback;
+                res.generation = searcher_generation(&searcher);
+
+                // Deleted documents are excluded unlike `max_doc`.
+                if include_total_docs {
+                    res.total_docs = Some(searcher.num_docs());
+                }
+
+                if cache_results {
+                    res.results_token = Some(result_cache.insert(&searcher, recorder));
+                }
+
+                // Hashing every hit's id is wasted work unless it's wanted.
+                if include_results_hash {
+                    res.results_hash = Some(res.hits_hash().to_string());
+                }
+
+                if previous_hash.is_some() && previous_hash == res.results_hash {
+                    res.not_modified = true;
+                    res.hits.clear();
+                    res.groups = None;
+                }
+
+                Ok(res)
+            };
+
+            let res = run().map(|mut res| {
+                let time_taken = start.elapsed();
+
+                info!(
+                    "[ SEARCH @ {} ] took {:?} with limit={}, mode={} and {} results total",
+                    &name,
+                    time_taken,
+                    limit,
+                    &mode_name,
+                    res.count
+                );
+
+                query_logger.log(QueryLogRecord {
+                    index: name,
+                    query: query_string,
+                    mode: mode_name.clone(),
+                    count: res.count,
+                    time_taken: time_taken.as_secs_f32(),
+                    timestamp: chrono::Utc::now(),
+                });
+
+                res.time_taken = time_taken.as_secs_f32();
+                res.mode = mode_name;
+                res
+            });
+
+            on_complete(res);
+        });
+
+        Ok(())
+    }
+}
+
+/// The parts of a payload which are needed to parse it's query.
+struct QueryInputs {
+    /// The id term of the reference document if any.
+    doc_id: Option<Term>,
+
+    /// The query string or map of fields to query strings.
+    query: Option<Either<String, HashMap<String, MapQueryValue>>>,
+
+    /// The mode of the query.
+    mode: QueryMode,
+
+    /// The options which alter how the query is parsed.
+    options: ParseOptions,
+}
+
+impl QueryInputs {
+    /// Parses the query, looking up the reference document if there is one.
+    ///
+    /// Returns the query along with the amount of fuzzy term clauses it has.
+    fn parse(
+        self,
+        searcher: &Searcher,
+        parser: Arc<QueryParser>,
+        search_fields: Arc<Vec<(Field, Score)>>,
+        executor: &Executor,
+    ) -> Result<(Box<dyn Query>, usize)> {
+        let ref_document = match self.doc_id {
+            None => None,
+            Some(doc) => Some(lookup_doc(
+                searcher,
+                doc,
+                self.options.mandatory_filter.as_ref(),
+                executor,
+            )?),
+        };
+
+        parse_query(
+            searcher,
+            parser,
+            search_fields,
+            self.query,
+            ref_document,
+            self.mode,
+            &self.options,
+        )
+    }
+}
+
+/// The per-request options which alter how a query is parsed.
+struct ParseOptions {
+    /// Whether fuzzy queries are split into words with the tokenizer of
+    /// each search field rather than on spaces.
+    analyze_query: bool,
+
+    /// Whether or not to use the fast fuzzy system for fuzzy queries.
+    use_fast_fuzzy: bool,
+
+    /// Whether or not to strip out stop words in fast fuzzy queries.
+    strip_stop_words: bool,
+
+    /// The maximum amount of correction candidates per word with fast fuzzy.
+    correction_candidates: usize,
+
+    /// How symspell selects the correction candidates.
+    correction_verbosity: CorrectionVerbosity,
+
+    /// The boost factor applied to words altered by the correction.
+    correction_penalty: Score,
+
+    /// Whether or not to structure standard fuzzy queries for autocomplete.
+    autocomplete: bool,
+
+    /// A set of field names mapping to the tokenizer used to analyze
+    /// their value in the map query.
+    tokenizer_overrides: HashMap<String, String>,
+
+    /// The custom query builder which takes precedence over the query mode.
+    custom_query: Option<CustomQueryBuilder>,
+
+    /// The search fields which are matched with a constant score.
+    constant_fields: HashSet<Field>,
+
+    /// The maximum amount of fuzzy terms a fuzzy query can generate.
+    max_fuzzy_terms: Option<usize>,
+
+    /// The custom BM25 parameters used to score term queries if any.
+    bm25: Option<Bm25Params>,
+
+    /// The language fuzzy query words are stemmed with if any.
+    stemming: Option<Language>,
+
+    /// The query of a vector query if any.
+    vector: Option<VectorQuery>,
+
+    /// The filter every query is restricted to regardless of the
+    /// query mode if any.
+    mandatory_filter: Option<Box<dyn Query>>,
+
+    /// The terms which exclude a document from the results of every
+    /// query mode if the document has any of them.
+    exclusions: Vec<Term>,
+
+    /// The lowercased query terms mapping to the factor they're boosted by.
+    term_boosts: HashMap<String, Score>,
+
+    /// The minimum length of a word in characters for it to be matched
+    /// with typo tolerance by the standard fuzzy system.
+    min_fuzzy_len: usize,
+
+    /// The lowercased words mapping to the synonyms they're expanded to.
+    synonyms: HashMap<String, Vec<String>>,
+
+    /// The fields a more like this query derives it's terms from if
+    /// not every field of the reference document.
+    more_like_this_fields: Option<Vec<Field>>,
+
+    /// Whether or not the diacritics of fuzzy query words are folded
+    /// to ascii before the terms are built.
+    fold_diacritics: bool,
+
+    /// The tie breaker of combining a fuzzy query word's field scores by
+    /// their maximum, if `None` the scores are summed.
+    dis_max: Option<Score>,
+}
+
+/// Generates a query from any of the 3 possible systems to
+/// query documents.
+///
+/// The query is always restricted to the mandatory filter if there is one,
+/// this applies to every query mode including custom queries.
+///
+/// Returns the query along with the amount of fuzzy term clauses it has.
+fn parse_query(
+    searcher: &Searcher,
+    parser: Arc<QueryParser>,
+    search_fields: Arc<Vec<(Field, Score)>>,
+    query: Option<Either<String, HashMap<String, MapQueryValue>>>,
+    ref_document: Option<DocAddress>,
+    mode: QueryMode,
+    options: &ParseOptions,
+) -> Result<(Box<dyn Query>, usize)> {
+    let (query, fuzzy_clauses) = build_query(searcher, parser, search_fields, query, ref_document, mode, options)?;
+    Ok((restrict_query(query, options), fuzzy_clauses))
+}
+
+/// Restricts the query to the mandatory filter of the options if any,
+/// excluding any documents with one of the excluded terms.
+fn restrict_query(query: Box<dyn Query>, options: &ParseOptions) -> Box<dyn Query> {
+    let query = match options.mandatory_filter {
+        None => query,
+        Some(ref filter) => with_filters(query, std::slice::from_ref(filter)),
+    };
+
+    if options.exclusions.is_empty() {
+        return query;
+    }
+
+    let mut clauses = Vec::with_capacity(options.exclusions.len() + 1);
+    clauses.push((Occur::Must, query));
+    for term in options.exclusions.iter() {
+        let exclusion: Box<dyn Query> = Box::new(TermQuery::new(term.clone(), IndexRecordOption::Basic));
+        clauses.push((Occur::MustNot, exclusion));
+    }
+
+    Box::new(BooleanQuery::new(clauses))
+}
+
+/// Builds the query of the given query mode.
+fn build_query(
+    searcher: &Searcher,
+    parser: Arc<QueryParser>,
+    search_fields: Arc<Vec<(Field, Score)>>,
+    query: Option<Either<String, HashMap<String, MapQueryValue>>>,
+    ref_document: Option<DocAddress>,
+    mode: QueryMode,
+    options: &ParseOptions,
+) -> Result<(Box<dyn Query>, usize)> {
+    let index = searcher.index();
+    if let Some(builder) = options.custom_query.as_ref() {
+        debug!("constructing query {:?} with custom query builder", query);
+        let query_string = match query {
+            Some(Either::Left(ref query)) => Some(query.as_str()),
+            _ => None,
+        };
+
+        // Custom queries are opaque so their fuzzy clauses can't be counted.
+        return Ok((builder(index, search_fields.as_ref(), query_string)?, 0));
+    }
+
+    let start = std::time::Instant::now();
+    let out = match (mode, &query, ref_document) {
+        (QueryMode::Normal, None, _) => Err(Error::msg(
+            "query mode was `Normal` but query string is `None`",
+        )),
+        (QueryMode::Normal, Some(Either::Left(query)), _) => {
+            Ok((parser.parse_query(&boost_query_terms(query, &options.term_boosts))?, 0))
+        },
+        (QueryMode::Normal, Some(Either::Right(query)), _) => {
+            let mut fuzzy_clauses = 0;
+            let queries = query.iter().map(|(name, value)| {
+                let query = value.query();
+                let field = match index.schema().get_field(name) {
+                    Some(f) => f,
+                    None => {
+                        return Ok(None)
+                    }
+                };
+
+                // Keyword fields are not tokenized so the whole value must
+                // match exactly, the query parser would split it up instead.
+                if !options.tokenizer_overrides.contains_key(name) && is_raw_text_field(index, field) {
+                    let term = Term::from_field_text(field, query);
+                    return Ok(Some((value.occur(), Box::new(TermQuery::new(term, IndexRecordOption::Basic)) as Box<dyn Query>)));
+                }
+
+                // A leading `~` is shorthand for matching the field fuzzily.
+                let (query, mode) = match query.strip_prefix('~') {
+                    Some(rest) => (rest, MapParseMode::Fuzzy),
+                    None => (query, value.mode()),
+                };
+
+                if let MapParseMode::Fuzzy = mode {
+                    let (query, clauses) = parse_fuzzy_query(index, query, Arc::new(vec![(field, 0.0)]), options);
+                    fuzzy_clauses += clauses;
+                    return Ok(Some((value.occur(), query)));
+                }
+
+                let mut parser = match options.tokenizer_overrides.get(name) {
+                    None => QueryParser::for_index(index, vec![field]),
+                    Some(tokenizer) => parser_with_tokenizer(index, field, tokenizer)?,
+                };
+                parser.set_conjunction_by_default();
+
+                let query = match mode {
+                    MapParseMode::Phrase => Cow::Owned(format!("\"{}\"", query.replace('"', ""))),
+                    _ => Cow::Borrowed(query),
+                };
+                match parser.parse_query(&query) {
+                    Ok(q) => Ok(Some((value.occur(), q))),
+                    Err(err) => Err(anyhow::Error::new(err))
+                }
+            })
+            .filter_map(|s| s.transpose())
+            .collect::<Result<Vec<_>, _>>()?;
+            Ok((Box::new(BooleanQuery::new(queries)) as Box<dyn Query>, fuzzy_clauses))
+        },
+        (QueryMode::Fuzzy, None, _) => Err(Error::msg(
+            "query mode was `Fuzzy` but query string is `None`",
+        )),
+        (QueryMode::Fuzzy, Some(Either::Left(query)), _) => {
+            let query = if options.fold_diacritics {
+                Cow::Owned(correction::fold_to_ascii(query))
+            } else {
+                Cow::Borrowed(query.as_str())
+            };
+
+            let qry = if options.use_fast_fuzzy {
+                parse_fast_fuzzy_query(&query, search_fields, options)?
+            } else if options.autocomplete {
+                parse_autocomplete_query(index, &query, search_fields, options)
+            } else {
+                parse_fuzzy_query(index, &query, search_fields, options)
+            };
+            Ok(qry)
+        },
+        (QueryMode::Fuzzy, Some(Either::Right(_)), _) => Err(Error::msg(
+            "query mode was `Fuzzy` but query string is `None`",
+        )),
+        (QueryMode::Prefix, Some(Either::Left(query)), _) => {
+            Ok(parse_prefix_query(query, search_fields, &options.constant_fields))
+        },
+        (QueryMode::CaseSensitive, Some(Either::Left(query)), _) => {
+            Ok((parse_case_sensitive_query(query, search_fields, options.bm25), 0))
+        },
+        (QueryMode::CaseSensitive, _, _) => Err(Error::msg(
+            "query mode was `CaseSensitive` but query string is `None`",
+        )),
+        (QueryMode::Prefix, _, _) => Err(Error::msg(
+            "query mode was `Prefix` but query string is `None`",
+        )),
+        (QueryMode::Vector, _, _) => match options.vector.clone() {
+            None => Err(Error::msg("query mode was `Vector` but query vector is `None`")),
+            Some(query) => Ok((Box::new(query) as Box<dyn Query>, 0)),
+        },
+        (QueryMode::MoreLikeThis, _, None) => Err(Error::msg(
+            "query mode was `MoreLikeThis` but reference document is `None`",
+        )),
+        (QueryMode::MoreLikeThis, _, Some(ref_document)) => Ok((
+            parse_more_like_this(searcher, ref_document, options.more_like_this_fields.as_deref())?,
+            0,
+        )),
+
+    };
 
     debug!(
         "constructing query {:?} or ref_doc {:?} with mode={:?} took {:?}",
@@ -382,172 +2397,1698 @@ fn parse_query(
         start.elapsed(),
     );
 
-    return out;
+    return out;
+}
+
+/// Parses a raw string value into a document value matching the field type.
+///
+/// Date fields accept either a unix timestamp or a RFC 3339 datetime.
+fn parse_field_value(field_type: &FieldType, value: &str) -> Result<DocumentValue> {
+    let invalid = |e: &dyn std::fmt::Display| {
+        Error::msg(format!(
+            "value {:?} is not valid for a {:?} field: {}",
+            value,
+            field_type.value_type(),
+            e
+        ))
+    };
+
+    let value = match field_type {
+        FieldType::Str(_) => DocumentValue::Text(value.to_string()),
+        FieldType::U64(_) => DocumentValue::U64(value.parse().map_err(|e| invalid(&e))?),
+        FieldType::I64(_) => DocumentValue::I64(value.parse().map_err(|e| invalid(&e))?),
+        FieldType::F64(_) => DocumentValue::F64(value.parse().map_err(|e| invalid(&e))?),
+        FieldType::Date(_) => match value.parse::<i64>() {
+            Ok(timestamp) => DocumentValue::I64(timestamp),
+            Err(_) => {
+                let dt = chrono::DateTime::parse_from_rfc3339(value).map_err(|e| invalid(&e))?;
+                DocumentValue::Datetime(dt.with_timezone(&chrono::Utc))
+            },
+        },
+        _ => {
+            return Err(Error::msg(format!(
+                "documents cannot be looked up by {:?} fields",
+                field_type.value_type()
+            )))
+        },
+    };
+
+    Ok(value)
+}
+
+/// Checks if the field is an indexed text field which is not tokenized.
+fn is_raw_text_field(index: &tantivy::Index, field: Field) -> bool {
+    match index.schema().get_field_entry(field).field_type() {
+        FieldType::Str(options) => options
+            .get_indexing_options()
+            .map(|indexing| indexing.tokenizer() == "raw")
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+/// Creates a query parser for a single field which analyzes the query
+/// with the given tokenizer rather than the field's own tokenizer.
+///
+/// The query is only analyzed with the tokenizer, the indexed terms are
+/// unchanged, so the tokenizer must produce terms compatible with the
+/// ones produced at index time (e.g. the same lower casing) otherwise
+/// nothing will match.
+fn parser_with_tokenizer(index: &tantivy::Index, field: Field, tokenizer: &str) -> Result<QueryParser> {
+    let schema = index.schema();
+    let entry = schema.get_field_entry(field);
+
+    let indexed_tokenizer = match entry.field_type() {
+        FieldType::Str(opts) => opts
+            .get_indexing_options()
+            .map(|indexing| indexing.tokenizer().to_string()),
+        _ => None,
+    }
+    .ok_or_else(|| {
+        Error::msg(format!(
+            "the tokenizer of field {:?} cannot be overridden as it is not an indexed text field",
+            entry.name()
+        ))
+    })?;
+
+    let analyzer = index
+        .tokenizers()
+        .get(tokenizer)
+        .ok_or_else(|| Error::msg(format!("no tokenizer exists with name {:?}", tokenizer)))?;
+
+    // The index's tokenizer manager is shared so we build a separate one
+    // where the field's tokenizer name resolves to the requested tokenizer.
+    let manager = TokenizerManager::default();
+    manager.register(&indexed_tokenizer, analyzer);
+
+    Ok(QueryParser::new(schema, vec![field], manager))
+}
+
+/// Creates a fuzzy matching query, this allows for an element
+/// of fault tolerance with spelling. This is the default
+/// config as it its the most plug and play setup.
+///
+/// Returns the query along with the amount of fuzzy term clauses it has.
+fn parse_fuzzy_query(
+    index: &tantivy::Index,
+    query: &str,
+    search_fields: Arc<Vec<(Field, Score)>>,
+    options: &ParseOptions,
+) -> (Box<dyn Query>, usize) {
+    parse_fuzzy_query_with_distance(index, query, search_fields, options, 1)
+}
+
+/// Creates a fuzzy matching query like `parse_fuzzy_query` where each
+/// word tolerates up to `distance` typos.
+///
+/// If the query is analyzed the words are the tokens the tokenizer of each
+/// search field produces from the query rather than the space separated
+/// words of the query, these tokens aren't stemmed again.
+fn parse_fuzzy_query_with_distance(
+    index: &tantivy::Index,
+    query: &str,
+    search_fields: Arc<Vec<(Field, Score)>>,
+    options: &ParseOptions,
+    distance: u8,
+) -> (Box<dyn Query>, usize) {
+    debug!("using default fuzzy system for {}", &query);
+    let mut parts: Vec<(Occur, Box<dyn Query>)> = Vec::new();
+    let mut fuzzy_clauses = 0;
+
+    let query = query.to_lowercase();
+
+    // Languages without spaces between words need each field's tokenizer
+    // to find the words, otherwise the whole query is a single word.
+    let groups = if options.analyze_query {
+        analyze_per_field(index, &query, &search_fields)
+    } else {
+        vec![(split_words(&query), search_fields.to_vec())]
+    };
+
+    // Each group of fields with the same tokenizer gets an even share of the limit.
+    let max_fuzzy_terms = options.max_fuzzy_terms.map(|max_terms| (max_terms / groups.len().max(1)).max(1));
+    for (tokens, search_fields) in groups {
+        // Synonyms are expanded before the words are limited so they count
+        // towards the limit, the synonyms come last so they're dropped first.
+        let mut words: Vec<(String, Score)> = tokens.into_iter().map(|word| (word, 1.0f32)).collect();
+        expand_synonyms(&mut words, &options.synonyms);
+        let (words, search_fields) = limit_fuzzy_terms(words, &search_fields, max_fuzzy_terms);
+
+        // Synonyms are expanded before stemming so they're stemmed the same,
+        // term boosts are given for the words as they're written. Tokens
+        // of the field's tokenizer are already stemmed if it stems.
+        let stemming = if options.analyze_query { None } else { options.stemming };
+        let stemmed = stem_words(words.iter().map(|(word, _)| word.as_str()), stemming);
+
+        for (search_term, (word, synonym_boost)) in stemmed.iter().zip(words.iter()) {
+            let search_term = search_term.as_str();
+            debug!("making fuzzy term for {}", &search_term);
+            let term_boost = options.term_boosts.get(word).copied().unwrap_or(1.0f32) * synonym_boost;
+
+            // Short words match too many unrelated words with typo tolerance.
+            let is_fuzzy = search_term.chars().count() >= options.min_fuzzy_len;
+
+            let mut field_parts: Vec<Box<dyn Query>> = Vec::with_capacity(search_fields.len());
+            for (field, boost) in search_fields.iter() {
+                let term = Term::from_field_text(*field, search_term);
+                let query: Box<dyn Query> = if is_fuzzy {
+                    fuzzy_clauses += 1;
+                    Box::new(FuzzyTermQuery::new_prefix(term, distance, true))
+                } else {
+                    Box::new(TermQuery::new(term, IndexRecordOption::WithFreqs))
+                };
+                let query = field_clause(query, *field, &options.constant_fields);
+
+                let boost = combine_boosts(*boost, term_boost);
+                if boost != 1.0f32 {
+                    field_parts.push(Box::new(BoostQuery::new(query, boost)));
+                    continue;
+                }
+
+                field_parts.push(query)
+            }
+
+            push_field_parts(&mut parts, field_parts, options.dis_max);
+        }
+    }
+
+    (Box::new(BooleanQuery::from(parts)), fuzzy_clauses)
+}
+
+/// Splits the query into it's whitespace separated words.
+fn split_words(query: &str) -> Vec<String> {
+    query.split_whitespace().map(String::from).collect()
+}
+
+/// Tokenizes the query with the tokenizer of each of the search fields,
+/// returning the tokens along with the fields which were indexed with the
+/// tokenizer that produced them.
+///
+/// Fields without a registered tokenizer use the space separated words
+/// of the query.
+fn analyze_per_field(
+    index: &tantivy::Index,
+    query: &str,
+    search_fields: &[(Field, Score)],
+) -> Vec<(Vec<String>, Vec<(Field, Score)>)> {
+    let schema = index.schema();
+    let mut groups: Vec<(Option<String>, Vec<(Field, Score)>)> = Vec::new();
+    for (field, boost) in search_fields.iter() {
+        let tokenizer = match schema.get_field_entry(*field).field_type() {
+            FieldType::Str(options) => options
+                .get_indexing_options()
+                .map(|indexing| indexing.tokenizer().to_string()),
+            _ => None,
+        };
+
+        match groups.iter_mut().find(|(name, _)| *name == tokenizer) {
+            Some((_, fields)) => fields.push((*field, *boost)),
+            None => groups.push((tokenizer, vec![(*field, *boost)])),
+        }
+    }
+
+    groups
+        .into_iter()
+        .map(|(name, fields)| {
+            let analyzer = name.and_then(|name| index.tokenizers().get(&name));
+            let tokens = match analyzer {
+                None => split_words(query),
+                Some(analyzer) => {
+                    let mut tokens = Vec::new();
+                    analyzer.token_stream(query).process(&mut |token| tokens.push(token.text.clone()));
+                    tokens
+                },
+            };
+
+            (tokens, fields)
+        })
+        .collect()
+}
+
+/// Builds the lookup of synonyms from the given synonym groups, keyed by
+/// each word and mapping to it's synonyms.
+///
+/// Synonyms apply both ways so each synonym of a word also has the word
+/// and the word's other synonyms as it's synonyms.
+///
+/// Query words never contain whitespace so a multi word synonym could
+/// never be matched, these are rejected.
+fn synonym_map(groups: HashMap<String, Vec<String>>) -> Result<HashMap<String, Vec<String>>> {
+    let mut synonyms: HashMap<String, Vec<String>> = HashMap::new();
+    for (word, group) in groups {
+        let mut words: Vec<String> = Some(word)
+            .into_iter()
+            .chain(group)
+            .map(|word| word.trim().to_lowercase())
+            .filter(|word| !word.is_empty())
+            .collect();
+
+        if let Some(word) = words.iter().find(|word| word.contains(char::is_whitespace)) {
+            return Err(Error::msg(format!("synonym {:?} must be a single word", word)));
+        }
+
+        words.sort_unstable();
+        words.dedup();
+
+        for word in words.iter() {
+            let entry = synonyms.entry(word.clone()).or_default();
+            for synonym in words.iter() {
+                if synonym != word && !entry.contains(synonym) {
+                    entry.push(synonym.clone());
+                }
+            }
+        }
+    }
+
+    Ok(synonyms)
+}
+
+/// Adds the synonyms of each of the words with a reduced boost, any
+/// synonyms which are already one of the words are not added again.
+fn expand_synonyms(words: &mut Vec<(String, Score)>, synonyms: &HashMap<String, Vec<String>>) {
+    if synonyms.is_empty() {
+        return;
+    }
+
+    let mut seen: HashSet<String> = words.iter().map(|(word, _)| word.clone()).collect();
+    let mut expanded = vec![];
+    for (word, boost) in words.iter() {
+        for synonym in synonyms.get(word).into_iter().flatten() {
+            if seen.insert(synonym.clone()) {
+                expanded.push((synonym.clone(), *boost * SYNONYM_BOOST));
+            }
+        }
+    }
+
+    words.extend(expanded);
+}
+
+/// Stems each of the words with the given language if any.
+///
+/// The search fields must be indexed with a tokenizer which stems with
+/// the same language e.g. `en_stem` for English, otherwise the stemmed
+/// words will not match the indexed terms.
+fn stem_words<'a>(words: impl Iterator<Item = &'a str>, language: Option<Language>) -> Vec<String> {
+    let analyzer = match language {
+        None => return words.map(|word| word.to_string()).collect(),
+        Some(language) => TextAnalyzer::from(RawTokenizer).filter(Stemmer::new(language)),
+    };
+
+    words.map(|word| stem_word(&analyzer, word)).collect()
+}
+
+/// Stems a single word with the given stemming analyzer.
+fn stem_word(analyzer: &TextAnalyzer, word: &str) -> String {
+    let mut stream = analyzer.token_stream(word);
+    if stream.advance() {
+        stream.token().text.clone()
+    } else {
+        word.to_string()
+    }
+}
+
+/// Limits the amount of fuzzy terms generated by the words of a query
+/// to at most `max_fuzzy_terms` terms.
+///
+/// Each word generates a fuzzy term per search field, so once the limit
+/// is exceeded the lowest boosted search fields are dropped first, if a
+/// single field still exceeds the limit the trailing words are dropped.
+fn limit_fuzzy_terms<W>(
+    mut words: Vec<W>,
+    search_fields: &[(Field, Score)],
+    max_fuzzy_terms: Option<usize>,
+) -> (Vec<W>, Vec<(Field, Score)>) {
+    let mut fields = search_fields.to_vec();
+    let max_terms = match max_fuzzy_terms {
+        None => return (words, fields),
+        Some(max_terms) => max_terms.max(1),
+    };
+
+    if words.len() * fields.len() <= max_terms {
+        return (words, fields);
+    }
+
+    // A boost of 0 means the field is not boosted.
+    let effective_boost = |boost: Score| if boost > 0.0f32 { boost } else { 1.0f32 };
+    fields.sort_by(|a, b| {
+        effective_boost(b.1)
+            .partial_cmp(&effective_boost(a.1))
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+
+    let max_fields = (max_terms / words.len().max(1)).max(1);
+    fields.truncate(max_fields);
+    words.truncate(max_terms / fields.len().max(1));
+
+    warn!(
+        "fuzzy query exceeded the limit of {} fuzzy terms, limited to {} words across {} fields",
+        max_terms,
+        words.len(),
+        fields.len(),
+    );
+
+    (words, fields)
+}
+
+/// Creates a term query scored with the custom BM25 parameters if
+/// any are set, otherwise tantivy's default term query.
+fn term_query(term: Term, bm25: Option<Bm25Params>) -> Box<dyn Query> {
+    match bm25 {
+        None => Box::new(TermQuery::new(term, IndexRecordOption::WithFreqs)),
+        Some(params) => Box::new(Bm25TermQuery::new(term, params)),
+    }
+}
+
+/// Gives the clause of a search field a constant score if the
+/// field has been excluded from BM25 scoring.
+fn field_clause(
+    query: Box<dyn Query>,
+    field: Field,
+    constant_fields: &HashSet<Field>,
+) -> Box<dyn Query> {
+    if constant_fields.contains(&field) {
+        Box::new(ConstantScoreQuery::new(query, 1.0))
+    } else {
+        query
+    }
+}
+
+/// Combines the boost of a search field with the boost of a query
+/// term, a field boost of 0 means the field is not boosted.
+fn combine_boosts(field_boost: Score, term_boost: Score) -> Score {
+    if field_boost > 0.0f32 {
+        field_boost * term_boost
+    } else {
+        term_boost
+    }
+}
+
+/// Boosts any plain words of a query parser query which have a term
+/// boost, using the `word^boost` syntax of the parser.
+///
+/// Words containing any query syntax, the boolean operators and the words
+/// of quoted phrases are left as they are.
+fn boost_query_terms(query: &str, term_boosts: &HashMap<String, Score>) -> String {
+    if term_boosts.is_empty() {
+        return query.to_string();
+    }
+
+    let mut in_phrase = false;
+    query
+        .split(' ')
+        .map(|word| {
+            let quotes = word.matches('"').count();
+            let is_phrase = in_phrase || quotes > 0;
+            if quotes % 2 == 1 {
+                in_phrase = !in_phrase;
+            }
+
+            let is_operator = matches!(word, "AND" | "OR" | "NOT");
+            let boost = if !is_phrase && !is_operator && word.chars().all(char::is_alphanumeric) {
+                term_boosts.get(&word.to_lowercase())
+            } else {
+                None
+            };
+
+            match boost {
+                Some(boost) => format!("{}^{}", word, boost),
+                None => word.to_string(),
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// Uses the standard fuzzy system structured for autocomplete.
+///
+/// Every word but the last must match at least one of the search fields
+/// with typo tolerance, either itself or one of it's synonyms. The last
+/// word is the one still being typed so it is neither stemmed nor expanded
+/// with synonyms, it is only added as an optional prefix clause which
+/// boosts the matches.
+///
+/// If the query is analyzed every group of fields sharing a tokenizer must
+/// match all of the words it's tokenizer produced, only one group has to
+/// match.
+///
+/// Returns the query along with the amount of fuzzy term clauses it has.
+fn parse_autocomplete_query(
+    index: &tantivy::Index,
+    query: &str,
+    search_fields: Arc<Vec<(Field, Score)>>,
+    options: &ParseOptions,
+) -> (Box<dyn Query>, usize) {
+    debug!("using autocomplete fuzzy system for {}", &query);
+    let query = query.to_lowercase();
+    let mut group_queries: Vec<(Occur, Box<dyn Query>)> = Vec::new();
+    let mut fuzzy_clauses = 0;
+
+    let groups = if options.analyze_query {
+        analyze_per_field(index, &query, &search_fields)
+    } else {
+        vec![(split_words(&query), search_fields.to_vec())]
+    };
+
+    // Tokens of the field's tokenizer are already stemmed if it stems.
+    let stemmer = match options.stemming {
+        Some(language) if !options.analyze_query => {
+            Some(TextAnalyzer::from(RawTokenizer).filter(Stemmer::new(language)))
+        },
+        _ => None,
+    };
+
+    // Each group of fields with the same tokenizer gets an even share of the limit.
+    let max_fuzzy_terms = options.max_fuzzy_terms.map(|max_terms| (max_terms / groups.len().max(1)).max(1));
+    for (mut tokens, search_fields) in groups {
+        let prefix = tokens.pop();
+
+        // Each word keeps the position of the completed word it was expanded
+        // from, `None` being the prefix. The prefix and then the synonyms
+        // come last so they're dropped first by the limit.
+        let mut words: Vec<(Option<usize>, String, Score)> = tokens
+            .iter()
+            .enumerate()
+            .map(|(position, word)| (Some(position), word.clone(), 1.0f32))
+            .collect();
+        words.extend(prefix.map(|prefix| (None, prefix, 1.0f32)));
+        for (position, word) in tokens.iter().enumerate() {
+            let mut expanded = vec![(word.clone(), 1.0f32)];
+            expand_synonyms(&mut expanded, &options.synonyms);
+            words.extend(
+                expanded
+                    .into_iter()
+                    .skip(1)
+                    .map(|(synonym, boost)| (Some(position), synonym, boost)),
+            );
+        }
+        let (words, search_fields) = limit_fuzzy_terms(words, &search_fields, max_fuzzy_terms);
+
+        let mut alternatives: Vec<Vec<(Occur, Box<dyn Query>)>> = tokens.iter().map(|_| Vec::new()).collect();
+        let mut prefix_parts: Vec<(Occur, Box<dyn Query>)> = Vec::new();
+        for (position, word, synonym_boost) in words {
+            // Term boosts are given for the words as they're written.
+            let term_boost = options.term_boosts.get(&word).copied().unwrap_or(1.0f32) * synonym_boost;
+            let search_term = match (position, stemmer.as_ref()) {
+                (Some(_), Some(stemmer)) => stem_word(stemmer, &word),
+                _ => word,
+            };
+
+            let mut field_parts: Vec<Box<dyn Query>> = Vec::with_capacity(search_fields.len());
+            for (field, boost) in search_fields.iter() {
+                let term = Term::from_field_text(*field, &search_term);
+                let query: Box<dyn Query> = if position.is_none() {
+                    fuzzy_clauses += 1;
+                    Box::new(FuzzyTermQuery::new_prefix(term, 1, true))
+                } else if search_term.chars().count() < options.min_fuzzy_len {
+                    Box::new(TermQuery::new(term, IndexRecordOption::WithFreqs))
+                } else {
+                    fuzzy_clauses += 1;
+                    Box::new(FuzzyTermQuery::new(term, 1, true))
+                };
+                let query = field_clause(query, *field, &options.constant_fields);
+
+                let boost = combine_boosts(*boost, term_boost);
+                if boost != 1.0f32 {
+                    field_parts.push(Box::new(BoostQuery::new(query, boost)));
+                    continue;
+                }
+
+                field_parts.push(query)
+            }
+
+            match position {
+                None => push_field_parts(&mut prefix_parts, field_parts, options.dis_max),
+                Some(position) => push_field_parts(&mut alternatives[position], field_parts, options.dis_max),
+            }
+        }
+
+        let mut parts: Vec<(Occur, Box<dyn Query>)> = alternatives
+            .into_iter()
+            .filter(|alternative| !alternative.is_empty())
+            .map(|alternative| (Occur::Must, Box::new(BooleanQuery::from(alternative)) as Box<dyn Query>))
+            .collect();
+
+        if !prefix_parts.is_empty() {
+            parts.push((Occur::Should, Box::new(BooleanQuery::from(prefix_parts))));
+        }
+
+        group_queries.push((Occur::Should, Box::new(BooleanQuery::from(parts))));
+    }
+
+    if group_queries.len() == 1 {
+        let (_, query) = group_queries.remove(0);
+        return (query, fuzzy_clauses);
+    }
+
+    (Box::new(BooleanQuery::from(group_queries)), fuzzy_clauses)
+}
+
+/// Matches each word of the query exactly without altering it's case.
+///
+/// Every word must match at least one of the search fields.
+fn parse_case_sensitive_query(
+    query: &str,
+    search_fields: Arc<Vec<(Field, Score)>>,
+    bm25: Option<Bm25Params>,
+) -> Box<dyn Query> {
+    debug!("using case sensitive system for {}", &query);
+    let mut parts: Vec<(Occur, Box<dyn Query>)> = Vec::new();
+
+    for search_term in query.split_whitespace() {
+        let mut field_parts: Vec<(Occur, Box<dyn Query>)> = Vec::with_capacity(search_fields.len());
+        for (field, boost) in search_fields.iter() {
+            let term = Term::from_field_text(*field, search_term);
+            let query = term_query(term, bm25);
+
+            if *boost > 0.0f32 {
+                field_parts.push((Occur::Should, Box::new(BoostQuery::new(query, *boost))));
+                continue;
+            }
+
+            field_parts.push((Occur::Should, query))
+        }
+
+        parts.push((Occur::Must, Box::new(BooleanQuery::from(field_parts))));
+    }
+
+    Box::new(BooleanQuery::from(parts))
+}
+
+/// Matches any terms starting with each word of the query.
+///
+/// This behaves like the standard fuzzy query with an edit distance
+/// of 0, which is considerably quicker and more precise for type-ahead.
+///
+/// Returns the query along with the amount of prefix term clauses it has.
+fn parse_prefix_query(
+    query: &str,
+    search_fields: Arc<Vec<(Field, Score)>>,
+    constant_fields: &HashSet<Field>,
+) -> (Box<dyn Query>, usize) {
+    debug!("using prefix system for {}", &query);
+    let mut parts: Vec<(Occur, Box<dyn Query>)> = Vec::new();
+
+    for search_term in query.to_lowercase().split(" ") {
+        if search_term.is_empty() {
+            continue;
+        }
+
+        for (field, boost) in search_fields.iter() {
+            let query = field_clause(
+                Box::new(FuzzyTermQuery::new_prefix(
+                    Term::from_field_text(*field, search_term),
+                    0,
+                    false,
+                )),
+                *field,
+                constant_fields,
+            );
+
+            if *boost > 0.0f32 {
+                parts.push((Occur::Should, Box::new(BoostQuery::new(query, *boost))));
+                continue;
+            }
+
+            parts.push((Occur::Should, query))
+        }
+    }
+
+    // Every clause is a prefix term walking the term dictionary.
+    let fuzzy_clauses = parts.len();
+    (Box::new(BooleanQuery::from(parts)), fuzzy_clauses)
+}
+
+/// Uses the fast fuzzy system to match similar documents with
+/// typo tolerance.
+///
+/// Unlike the standard fuzzy query which uses Levenshtein distance
+/// this system uses pre-computation via symspell which is considerably
+/// quicker than the standard method.
+///
+/// However, this requires additional private fields in the schema to
+/// be affective with relevancy as names often get corrected to dictionary
+/// words which alters the behaviour of the ranking.
+/// To counter act this, the system runs the same correction on indexed
+/// text fields to counter act this name handling issue.
+///
+/// If `correction_candidates` is greater than 1 the ranked alternative
+/// corrections (and the original word) of each query word are also searched
+/// as optional clauses, each candidate's boost decaying by half per rank.
+///
+/// With `autocomplete` the last word, the one being typed, is matched as
+/// an exact prefix rather than being corrected.
+///
+/// Returns the query along with the amount of prefix term clauses it has.
+fn parse_fast_fuzzy_query(
+    query: &str,
+    search_fields: Arc<Vec<(Field, Score)>>,
+    options: &ParseOptions,
+) -> Result<(Box<dyn Query>, usize)> {
+    debug!("using fast fuzzy system for {}", &query);
+    if query.trim().is_empty() {
+        return Ok((Box::new(EmptyQuery {}), 0));
+    }
+
+    // The word being typed is incomplete so correcting it would only
+    // produce unrelated words, instead it's matched as a prefix.
+    let (query, prefix) = if options.autocomplete && !query.ends_with(char::is_whitespace) {
+        match query.rsplit_once(char::is_whitespace) {
+            None => ("", Some(query.to_lowercase())),
+            Some((completed, last)) => (completed, Some(last.to_lowercase())),
+        }
+    } else {
+        (query, None)
+    };
+
+    let stop_words = crate::stop_words::get_hashset_words()?;
+    let mut parts: Vec<(Occur, Box<dyn Query>)> = Vec::new();
+
+    // Corrected words are less certain than the words which matched exactly.
+    let mut words: Vec<(String, Score)> = if query.trim().is_empty() {
+        vec![]
+    } else {
+        correct_sentence_words(query, 1)
+            .into_iter()
+            .map(|(word, corrected)| {
+                let boost = if corrected { options.correction_penalty } else { 1.0f32 };
+                (word, boost)
+            })
+            .collect()
+    };
+
+    if options.correction_candidates > 1 {
+        let mut seen: HashSet<String> = words.iter().map(|(word, _)| word.clone()).collect();
+
+        for original in query.to_lowercase().split_whitespace() {
+            let candidates = correct_word_candidates(
+                original,
+                1,
+                options.correction_candidates,
+                options.correction_verbosity,
+            );
+
+            let mut rank = 1;
+            for candidate in candidates {
+                if seen.insert(candidate.clone()) {
+                    words.push((candidate, 0.5f32.powi(rank)));
+                    rank += 1;
+                }
+            }
+
+            if seen.insert(original.to_string()) {
+                words.push((original.to_string(), 0.5f32.powi(rank)));
+            }
+        }
+    }
+
+    expand_synonyms(&mut words, &options.synonyms);
+
+    let mut ignore_stop_words = false;
+    if options.strip_stop_words && words.len() > 1 {
+        for (word, _) in words.iter() {
+            if !stop_words.contains(word) {
+                ignore_stop_words = true;
+                break;
+            }
+        }
+    }
+
+    let stemmer = options.stemming.map(|language| TextAnalyzer::from(RawTokenizer).filter(Stemmer::new(language)));
+    for (search_term, candidate_boost) in words.iter() {
+        debug!("making fast-fuzzy term for {}", &search_term);
+        if ignore_stop_words && stop_words.contains(search_term) {
+            continue;
+        }
+
+        // Stop words and term boosts are checked before stemming as
+        // they're given for the words as they're written.
+        let candidate_boost = candidate_boost * options.term_boosts.get(search_term).copied().unwrap_or(1.0f32);
+        let search_term = match stemmer.as_ref() {
+            None => search_term.clone(),
+            Some(stemmer) => stem_word(stemmer, search_term),
+        };
+
+        let mut field_parts: Vec<Box<dyn Query>> = Vec::with_capacity(search_fields.len());
+        for (field, boost) in search_fields.iter() {
+            let term = Term::from_field_text(*field, &search_term);
+            let query = field_clause(
+                term_query(term, options.bm25),
+                *field,
+                &options.constant_fields,
+            );
+
+            let boost = combine_boosts(*boost, candidate_boost);
+
+            if boost != 1.0f32 {
+                field_parts.push(Box::new(BoostQuery::new(query, boost)));
+                continue;
+            }
+
+            field_parts.push(query);
+        }
+
+        push_field_parts(&mut parts, field_parts, options.dis_max);
+    }
+
+    // Only the prefix of the word being typed walks the term dictionary,
+    // every other word is an exact term.
+    let mut fuzzy_clauses = 0;
+    if let Some(prefix) = prefix {
+        debug!("making fast-fuzzy prefix term for {}", &prefix);
+        fuzzy_clauses = search_fields.len();
+
+        let mut field_parts: Vec<Box<dyn Query>> = Vec::with_capacity(search_fields.len());
+        for (field, boost) in search_fields.iter() {
+            let query = field_clause(
+                Box::new(FuzzyTermQuery::new_prefix(
+                    Term::from_field_text(*field, &prefix),
+                    0,
+                    false,
+                )),
+                *field,
+                &options.constant_fields,
+            );
+
+            if *boost > 0.0f32 {
+                field_parts.push(Box::new(BoostQuery::new(query, *boost)));
+                continue;
+            }
+
+            field_parts.push(query);
+        }
+
+        push_field_parts(&mut parts, field_parts, options.dis_max);
+    }
+
+    Ok((Box::new(BooleanQuery::from(parts)), fuzzy_clauses))
+}
+
+/// Adds the clauses of a single query word across the search fields as
+/// optional clauses, either individually so their scores are summed or
+/// as one disjunction max clause with the given tie breaker.
+fn push_field_parts(
+    parts: &mut Vec<(Occur, Box<dyn Query>)>,
+    field_parts: Vec<Box<dyn Query>>,
+    dis_max: Option<Score>,
+) {
+    match dis_max {
+        Some(tie_breaker) if field_parts.len() > 1 => {
+            parts.push((Occur::Should, Box::new(DisMaxQuery::new(field_parts, tie_breaker))));
+        },
+        _ => parts.extend(field_parts.into_iter().map(|query| (Occur::Should, query))),
+    }
+}
+
+/// Generates a MoreLikeThisQuery which matches similar documents
+/// as the given reference document.
+///
+/// If fields are given only the text of those fields is used to
+/// derive the interesting terms of the reference document.
+fn parse_more_like_this(
+    searcher: &Searcher,
+    ref_document: DocAddress,
+    fields: Option<&[Field]>,
+) -> Result<Box<dyn Query>> {
+    let builder = MoreLikeThisQuery::builder()
+        .with_min_doc_frequency(1)
+        .with_max_doc_frequency(10)
+        .with_min_term_frequency(1)
+        .with_min_word_length(MLT_MIN_WORD_LENGTH)
+        .with_max_word_length(MLT_MAX_WORD_LENGTH)
+        .with_boost_factor(1.0)
+        .with_stop_words(crate::stop_words::get_stop_words()?);
+
+    let query = match fields {
+        None => builder.with_document(ref_document),
+        Some(fields) => {
+            let doc = searcher.doc(ref_document)?;
+            let doc_fields = fields
+                .iter()
+                .map(|field| (*field, doc.get_all(*field).cloned().collect()))
+                .collect();
+
+            builder.with_document_fields(doc_fields)
+        },
+    };
+
+    Ok(Box::new(query))
 }
 
-/// Creates a fuzzy matching query, this allows for an element
-/// of fault tolerance with spelling. This is the default
-/// config as it its the most plug and play setup.
-fn parse_fuzzy_query(query: &str, search_fields: Arc<Vec<(Field, Score)>>) -> Box<dyn Query> {
-    debug!("using default fuzzy system for {}", &query);
-    let mut parts: Vec<(Occur, Box<dyn Query>)> = Vec::new();
+/// Checks whether the reference document has any indexed text which a
+/// more like this query could extract terms from.
+///
+/// This uses the same word length bounds and stop words as the query.
+fn has_interesting_terms(
+    searcher: &Searcher,
+    ref_document: DocAddress,
+    fields: Option<&[Field]>,
+) -> Result<bool> {
+    let doc = searcher.doc(ref_document)?;
+    let schema = searcher.schema();
+    let stop_words = crate::stop_words::get_hashset_words()?;
 
-    for search_term in query.to_lowercase().split(" ") {
-        debug!("making fuzzy term for {}", &search_term);
-        if search_term.is_empty() {
+    for field_value in doc.field_values() {
+        let text = match field_value.value() {
+            Value::Str(text) => text,
+            _ => continue,
+        };
+
+        let field = field_value.field();
+        if matches!(fields, Some(fields) if !fields.contains(&field)) {
             continue;
         }
+        match schema.get_field_entry(field).field_type() {
+            FieldType::Str(options) if options.get_indexing_options().is_some() => {},
+            _ => continue,
+        }
 
-        for (field, boost) in search_fields.iter() {
-            let query = Box::new(FuzzyTermQuery::new_prefix(
-                Term::from_field_text(*field, search_term),
-                1,
-                true,
-            ));
+        let analyzer = searcher.index().tokenizer_for_field(field)?;
+        let mut stream = analyzer.token_stream(text);
+        while stream.advance() {
+            let word = &stream.token().text;
+            let len = word.chars().count();
+            if (MLT_MIN_WORD_LENGTH..=MLT_MAX_WORD_LENGTH).contains(&len) && !stop_words.contains(word) {
+                return Ok(true);
+            }
+        }
+    }
 
-            if *boost > 0.0f32 {
-                parts.push((Occur::Should, Box::new(BoostQuery::new(query, *boost))));
-                continue;
+    Ok(false)
+}
+
+/// What a single field of the schema can be used for.
+#[derive(Debug, Serialize)]
+pub struct FieldCaps {
+    /// The declared type of the field e.g. `text` or `u64`.
+    #[serde(rename = "type")]
+    field_type: &'static str,
+
+    /// Whether or not the field can be searched.
+    indexed: bool,
+
+    /// Whether or not the field is returned with the hits.
+    stored: bool,
+
+    /// Whether or not the field is a fast field.
+    fast: bool,
+
+    /// Whether or not the results can be ordered by the field.
+    sortable: bool,
+}
+
+/// Represents a single query result.
+#[derive(Serialize)]
+pub struct QueryHit {
+    /// The address of the given document, this can be used for
+    /// 'more like this' queries.
+    pub(super) document_id: String,
+
+    /// The content of the document itself.
+    pub(super) doc: NamedFieldDocument,
+
+    /// The ratio calculated for the search term and doc.
+    ///
+    /// If the search was ordered by a field this is the document's value
+    /// of the field, typed the same as the field's returned values e.g.
+    /// dates are RFC 3339 strings and decimals are exact decimal strings.
+    pub(super) ratio: serde_json::Value,
+
+    /// The highlighted fragments of each highlighted field with a match.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(super) highlights: Option<HashMap<String, String>>,
+
+    /// The character offsets of the matched terms in each requested field.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(super) positions: Option<HashMap<String, Vec<MatchPosition>>>,
+
+    /// The closest matched term and its edit distance for each query
+    /// term of a fuzzy query if debugging was requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(super) fuzzy_matches: Option<Vec<FuzzyTermMatch>>,
+
+    /// The contributions to the hit's score keyed by term if requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(super) score_components: Option<HashMap<String, Score>>,
+
+    /// The full explanation of the hit's score if it was one of the
+    /// top hits explanations were requested for.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(super) explanation: Option<Explanation>,
+
+    /// The terms and their frequencies of each requested field.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(super) term_vectors: Option<TermVectors>,
+
+    /// The other top hits sharing the collapsed field's value if the
+    /// search was collapsed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(super) inner_hits: Option<Vec<QueryHit>>,
+}
+
+/// A point in time view of the index.
+///
+/// Snapshots taken by the user hold a snapshot permit which is released
+/// once every clone of the snapshot has been dropped.
+#[derive(Clone)]
+pub struct SearcherSnapshot(Arc<LeasedItem<Searcher>>, Option<Arc<OwnedSemaphorePermit>>);
+
+impl Deref for SearcherSnapshot {
+    type Target = Searcher;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+/// The differences between the results of two searchers for the same query.
+#[derive(Default, Serialize)]
+pub struct ResultsDiff {
+    /// The documents which only appear in the current results.
+    added: Vec<String>,
+
+    /// The documents which only appear in the snapshot's results.
+    removed: Vec<String>,
+
+    /// The documents which appear in both results at a different rank.
+    moved: Vec<RankChange>,
+}
+
+/// The change in rank of a single document.
+#[derive(Serialize)]
+pub struct RankChange {
+    /// The id of the document.
+    document_id: String,
+
+    /// The rank of the document in the snapshot's results.
+    previous_rank: usize,
+
+    /// The rank of the document in the current results.
+    rank: usize,
+}
+
+/// The estimated cost of a search, see `IndexHandler::estimate_cost`.
+#[derive(Debug, Serialize)]
+pub struct QueryCost {
+    /// The amount of fuzzy term clauses, each of which builds a
+    /// levenshtein automaton and walks the term dictionary with it.
+    fuzzy_clauses: usize,
+
+    /// The amount of distinct exact terms the query matches on.
+    term_clauses: usize,
+
+    /// The sum of the document frequencies of the exact terms, this is
+    /// roughly how many postings the search reads excluding fuzzy terms.
+    estimated_postings: u64,
+}
+
+impl QueryCost {
+    /// Estimates the cost of a query with the given amount of fuzzy
+    /// term clauses, fuzzy queries don't report their terms.
+    fn estimate(searcher: &Searcher, query: &dyn Query, fuzzy_clauses: usize) -> Result<Self> {
+        let mut terms = BTreeSet::new();
+        query.query_terms(&mut terms);
+
+        let mut estimated_postings = 0;
+        for term in terms.iter() {
+            estimated_postings += searcher.doc_freq(term)?;
+        }
+
+        Ok(Self {
+            fuzzy_clauses,
+            term_clauses: terms.len(),
+            estimated_postings,
+        })
+    }
+}
+
+/// The results of searching a single segment, see `IndexHandler::search_segment`.
+#[derive(Debug, Serialize)]
+pub struct SegmentResults {
+    /// The id of the searched segment.
+    segment_id: String,
+
+    /// The amount of live documents in the segment.
+    num_docs: u32,
+
+    /// The amount of deleted documents in the segment.
+    num_deleted_docs: u32,
+
+    /// The amount of documents in the segment matching the query.
+    count: usize,
+
+    /// The top hits of the segment.
+    hits: Vec<SegmentHit>,
+}
+
+/// A single hit of a segment search.
+#[derive(Debug, Serialize)]
+pub struct SegmentHit {
+    /// The id of the document.
+    document_id: String,
+
+    /// The id of the document within the segment.
+    doc: DocId,
+
+    /// The score of the document.
+    score: Score,
+}
+
+/// Represents the overall query result(s)
+#[derive(Serialize)]
+pub struct QueryResults {
+    /// The retrieved documents.
+    hits: Vec<QueryHit>,
+
+    /// The total amount of documents matching the search
+    count: usize,
+
+    /// The current page starting from 0, this is `offset / limit` rounded
+    /// down if the offset is not a multiple of the limit.
+    page: usize,
+
+    /// The amount of hits per page, this is the limit of the search.
+    page_size: usize,
+
+    /// The total amount of pages of matching documents.
+    total_pages: usize,
+
+    /// The amount of time taken to search in seconds.
+    time_taken: f32,
+
+    /// The query system which actually handled the query, e.g. `Fuzzy`
+    /// if the fast fuzzy dictionary was not loaded yet.
+    mode: String,
+
+    /// Whether or not the query matched fewer than `min_results` documents
+    /// and was retried with more typo tolerance.
+    escalated: bool,
+
+    /// Whether or not a parallel executor was requested but none were
+    /// available, so the search ran on a single-threaded executor instead.
+    parallel_fallback: bool,
+
+    /// The amount of hits which were skipped for missing a valid id, this
+    /// is always 0 unless the index skips malformed documents.
+    skipped_docs: usize,
+
+    /// Whether or not the search exceeded its time or document budget
+    /// and only returned the results collected up until that point, or
+    /// the count exceeded the requested count cap.
+    truncated: bool,
+
+    /// Additional debugging information if it was requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    debug: Option<DebugInfo>,
+
+    /// The token referring to the cached matches of this search if caching
+    /// was requested, this can be given as `within` to refine the results.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    results_token: Option<String>,
+    /// An explanation of why the search returned no results if
+    /// it was short circuited.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    note: Option<String>,
+
+    /// The total amount of live documents in the index if requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_docs: Option<u64>,
+
+    /// The generation of the searcher the search ran against, this changes
+    /// whenever the searchable documents change.
+    ///
+    /// A write is visible once a search returns the generation returned
+    /// by reloading the index after the write was committed.
+    generation: u64,
+
+    /// The main parameters of the query if they were requested to be echoed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    echo: Option<QueryEcho>,
+
+    /// The top groups of hits if the search was grouped by a field.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    groups: Option<Vec<Group>>,
+
+    /// The amount of matching documents per interval of the date
+    /// histogram if one was requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    buckets: Option<Vec<HistogramBucket>>,
+
+    /// The hash of the ordered ids and scores of the hits, this can be
+    /// given as `previous_hash` when repeating the search.
+    ///
+    /// The hash is a string as it doesn't fit in a JSON number, it's only
+    /// included if requested or a `previous_hash` was given.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    results_hash: Option<String>,
+
+    /// Whether or not the hits are the same as the results of the
+    /// `previous_hash`, if so no hits or groups are returned.
+    not_modified: bool,
+}
+
+impl QueryResults {
+    /// Serializes the results to MessagePack, this is considerably cheaper
+    /// to parse than JSON for internal consumers.
+    ///
+    /// Structs are encoded as maps keyed by the same names as their JSON
+    /// fields, so the results decode to the same shape as the JSON results
+    /// including the documents and the arbitrary JSON of each `ratio`.
+    pub fn to_msgpack(&self) -> Result<Vec<u8>> {
+        Ok(rmp_serde::to_vec_named(self)?)
+    }
+
+    /// Hashes the ids and scores of the hits in order, including the
+    /// hits of any groups.
+    fn hits_hash(&self) -> u64 {
+        let hits: Vec<(&str, String)> = self
+            .hits
+            .iter()
+            .chain(self.groups.iter().flatten().flat_map(|group| group.hits.iter()))
+            .map(|hit| (hit.document_id.as_str(), hit.ratio.to_string()))
+            .collect();
+
+        hash(&hits)
+    }
+}
+
+/// A group of hits sharing the same value of the grouped field.
+#[derive(Serialize)]
+pub struct Group {
+    /// The value of the grouped field.
+    key: serde_json::Value,
+
+    /// The total amount of documents in the group matching the search.
+    count: usize,
+
+    /// The top hits of the group.
+    hits: Vec<QueryHit>,
+}
+
+/// The matching documents of a single interval of a date histogram.
+#[derive(Serialize)]
+pub struct HistogramBucket {
+    /// The start of the interval.
+    timestamp: serde_json::Value,
+
+    /// The amount of matching documents in the interval.
+    count: usize,
+}
+
+/// The parameters of a query which are echoed back with it's results.
+#[derive(Serialize)]
+pub struct QueryEcho {
+    /// The query string if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    query: Option<String>,
+
+    /// The reference document if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    document: Option<u64>,
+
+    /// The fields of the map query if any, the queries themselves are omitted.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    map_fields: Vec<String>,
+
+    /// The query mode.
+    mode: QueryMode,
+
+    /// The requested limit.
+    limit: usize,
+
+    /// The requested offset.
+    offset: usize,
+
+    /// The field the results are ordered by if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    order_by: Option<String>,
+}
+
+impl QueryEcho {
+    fn from_payload(payload: &QueryPayload) -> Self {
+        let mut map_fields: Vec<String> = payload.map.keys().cloned().collect();
+        map_fields.sort_unstable();
+
+        Self {
+            query: payload.query.clone(),
+            document: payload.document,
+            map_fields,
+            mode: payload.mode,
+            limit: payload.limit,
+            offset: payload.offset,
+            order_by: payload.order_by.clone(),
+        }
+    }
+}
+
+/// Additional information about how a search was executed.
+#[derive(Serialize)]
+pub struct DebugInfo {
+    /// The total amount of segments in the searcher.
+    segments_total: usize,
+
+    /// The amount of segments which produced at least one candidate.
+    segments_scanned: usize,
+
+    /// The amount of segments which were either empty or had every
+    /// document filtered out by the query.
+    segments_skipped: usize,
+}
+
+macro_rules! order_and_search {
+    ( $search:expr, $collector:expr, $field:expr, $query:expr, $executor:expr, $limits:expr, $stats:expr, $recorder:expr, $histogram:expr, $counter:expr) => {{
+        let collector = $collector.order_by_fast_field($field);
+        $search.search_with_executor(
+            $query,
+            &$limits.wrap($stats.wrap($recorder.wrap($histogram.wrap((collector, $counter))))),
+            $executor,
+        )
+    }};
+}
+
+/// The context used to convert the collected documents into hits.
+struct HitContext {
+    /// A cheaply cloneable schema reference.
+    schema: Schema,
+
+    /// The set of decimal fields and their scale.
+    decimal_fields: Arc<HashMap<String, u32>>,
+
+    /// The highlighter if highlighting was requested.
+    highlighter: Option<Highlighter>,
+
+    /// The highlighter locating matches if match positions were requested.
+    position_finder: Option<Highlighter>,
+
+    /// The fuzzy term matcher if debugging a fuzzy query.
+    fuzzy_matcher: Option<FuzzyMatcher>,
+
+    /// The name of the private field holding each document's id.
+    id_field: Arc<String>,
+
+    /// The names of the fast fuzzy fields removed from each hit.
+    private_fields: Arc<HashSet<String>>,
+
+    /// The score explainer if score components were requested.
+    explainer: Option<ScoreExplainer>,
+
+    /// The explainer of the top hits if explanations were requested.
+    top_explainer: Option<TopHitsExplainer>,
+
+    /// The sink hits are streamed to instead of being collected.
+    stream: Option<mpsc::Sender<Result<Bytes>>>,
+
+    /// The names returned fields are renamed to.
+    field_aliases: HashMap<String, String>,
+
+    /// The fields each hit is projected to if any.
+    return_fields: Option<HashSet<String>>,
+
+    /// Whether or not hits missing a valid id are skipped rather than
+    /// failing the search.
+    skip_malformed_docs: bool,
+
+    /// The amount of hits which were skipped for missing a valid id.
+    skipped_docs: Cell<usize>,
+
+    /// The extractor of the term vectors of each hit if requested.
+    term_vectors: Option<TermVectorExtractor>,
+}
+
+macro_rules! process_search {
+    ( $search:expr, $ctx:expr, $top_docs:expr ) => {{
+        let mut hits = Vec::with_capacity($top_docs.len());
+        for (rank, (ratio, ref_address)) in $top_docs.into_iter().enumerate() {
+            let retrieved_doc = $search.doc(ref_address)?;
+            let mut doc = $ctx.schema.to_named_doc(&retrieved_doc);
+            format_decimal_doc_values(&mut doc, &$ctx.decimal_fields);
+            strip_private_fields(&mut doc, &$ctx.private_fields);
+            if let Some(fields) = $ctx.return_fields.as_ref() {
+                project_fields(&mut doc, fields, &$ctx.id_field);
+            }
+
+            let document_id = match doc.0.remove($ctx.id_field.as_str()).as_deref() {
+                Some([Value::U64(v), ..]) => format!("{}", v),
+                _ if $ctx.skip_malformed_docs => {
+                    warn!("skipping document {:?} which is missing a valid identifier", ref_address);
+                    $ctx.skipped_docs.set($ctx.skipped_docs.get() + 1);
+                    continue;
+                },
+                _ => return Err(Error::msg("document has been missed labeled (missing identifier tag), the dataset is invalid")),
+            };
+
+            let highlights = $ctx
+                .highlighter
+                .as_ref()
+                .map(|highlighter| highlighter.highlight(&retrieved_doc));
+
+            let positions = $ctx
+                .position_finder
+                .as_ref()
+                .map(|finder| finder.match_positions(&retrieved_doc));
+
+            let fuzzy_matches = $ctx
+                .fuzzy_matcher
+                .as_ref()
+                .map(|matcher| matcher.match_doc(&retrieved_doc));
+
+            let term_vectors = $ctx
+                .term_vectors
+                .as_ref()
+                .map(|extractor| extractor.extract(&retrieved_doc));
+
+            let score_components = match $ctx.explainer.as_ref() {
+                None => None,
+                Some(explainer) => Some(explainer.explain($search, ref_address)?),
+            };
+
+            let explanation = match $ctx.top_explainer.as_ref() {
+                None => None,
+                Some(explainer) => explainer.explain($search, rank, ref_address)?,
+            };
+
+            apply_field_aliases(&mut doc, &$ctx.field_aliases);
+
+            let hit = QueryHit {
+                document_id,
+                doc,
+                ratio: serde_json::json!(ratio),
+                highlights,
+                positions,
+                fuzzy_matches,
+                score_components,
+                explanation,
+                term_vectors,
+                inner_hits: None,
+            };
+
+            match $ctx.stream.as_ref() {
+                None => hits.push(hit),
+                Some(stream) => {
+                    let mut line = serde_json::to_vec(&hit)?;
+                    line.push(b'\n');
+
+                    // The consumer has gone away so there's no point fetching the rest.
+                    if stream.blocking_send(Ok(Bytes::from(line))).is_err() {
+                        break;
+                    }
+                },
+            }
+        }
+
+        hits
+    }};
+}
+
+/// The names of the `_<hash>` corrected copies of the given text fields
+/// which are added by the fast fuzzy system.
+fn private_field_names(indexed_text_fields: &[String]) -> HashSet<String> {
+    indexed_text_fields
+        .iter()
+        .map(|name| format!("_{}", hash(name)))
+        .collect()
+}
+
+/// Removes the given internal fields from the document e.g. the
+/// `_<hash>` corrected copies of text fields added by the fast fuzzy
+/// system, these are indexing artifacts and not part of the document.
+///
+/// Any other fields are kept even if their name starts with `_`.
+fn strip_private_fields(doc: &mut NamedFieldDocument, private_fields: &HashSet<String>) {
+    doc.0.retain(|name, _| !private_fields.contains(name));
+}
+
+/// Removes every field of the document which isn't one of the given
+/// fields, the id field is kept as it's always returned.
+fn project_fields(doc: &mut NamedFieldDocument, fields: &HashSet<String>, id_field: &str) {
+    doc.0.retain(|name, _| name == id_field || fields.contains(name));
+}
+
+/// Renames the fields of the document to their aliases.
+///
+/// Every aliased field is removed before any are re-inserted so fields
+/// can swap names with each other.
+fn apply_field_aliases(doc: &mut NamedFieldDocument, aliases: &HashMap<String, String>) {
+    if aliases.is_empty() {
+        return;
+    }
+
+    let renamed: Vec<(String, Vec<Value>)> = aliases
+        .iter()
+        .filter_map(|(name, alias)| doc.0.remove(name).map(|values| (alias.clone(), values)))
+        .collect();
+
+    for (alias, values) in renamed {
+        doc.0.insert(alias, values);
+    }
+}
+
+/// Converts the stored values of any decimal fields into their exact
+/// decimal strings.
+fn format_decimal_doc_values(doc: &mut NamedFieldDocument, decimal_fields: &HashMap<String, u32>) {
+    for (name, scale) in decimal_fields.iter() {
+        if let Some(values) = doc.0.get_mut(name) {
+            for value in values.iter_mut() {
+                if let Value::I64(v) = value {
+                    *value = Value::Str(format_decimal(*v, *scale));
+                }
+            }
+        }
+    }
+}
+
+/// Converts the timestamp of a date fast field to JSON the same way the
+/// stored date values of a document are serialized.
+///
+/// Timestamps which are out of range are emitted as the raw number.
+fn timestamp_to_json(timestamp: i64) -> serde_json::Value {
+    match chrono::NaiveDateTime::from_timestamp_opt(timestamp, 0) {
+        Some(dt) => serde_json::json!(tantivy::DateTime::from_utc(dt, chrono::Utc)),
+        None => serde_json::json!(timestamp),
+    }
+}
+
+/// Converts the raw value of a single value fast field to JSON the same
+/// way the field's stored values are returned.
+fn fast_value_to_json(ctx: &HitContext, field: Field, value: u64) -> serde_json::Value {
+    let entry = ctx.schema.get_field_entry(field);
+    let scale = ctx.decimal_fields.get(entry.name()).copied();
+
+    match (entry.field_type().value_type(), scale) {
+        (Type::I64, Some(scale)) => serde_json::json!(format_decimal(u64_to_i64(value), scale)),
+        (Type::I64, None) => serde_json::json!(u64_to_i64(value)),
+        (Type::Date, _) => timestamp_to_json(u64_to_i64(value)),
+        (Type::F64, _) => serde_json::json!(u64_to_f64(value)),
+        _ => serde_json::json!(value),
+    }
+}
+
+/// Collects the terms of the query a document can match on, the terms
+/// of excluded clauses are skipped.
+fn positive_terms(query: &dyn Query, terms: &mut BTreeSet<Term>) {
+    match query.downcast_ref::<BooleanQuery>() {
+        None => query.query_terms(terms),
+        Some(query) => {
+            for (occur, clause) in query.clauses() {
+                if *occur != Occur::MustNot {
+                    positive_terms(clause.as_ref(), terms);
+                }
             }
+        },
+    }
+}
 
-            parts.push((Occur::Should, query))
-        }
+/// Wraps the query so every one of the filters must also match.
+fn with_filters(query: Box<dyn Query>, filters: &[Box<dyn Query>]) -> Box<dyn Query> {
+    if filters.is_empty() {
+        return query;
     }
 
-    Box::new(BooleanQuery::from(parts))
+    let mut clauses = Vec::with_capacity(filters.len() + 1);
+    clauses.push((Occur::Must, query));
+    for filter in filters {
+        clauses.push((Occur::Must, filter.box_clone()));
+    }
+
+    Box::new(BooleanQuery::new(clauses))
 }
 
-/// Uses the fast fuzzy system to match similar documents with
-/// typo tolerance.
-///
-/// Unlike the standard fuzzy query which uses Levenshtein distance
-/// this system uses pre-computation via symspell which is considerably
-/// quicker than the standard method.
-///
-/// However, this requires additional private fields in the schema to
-/// be affective with relevancy as names often get corrected to dictionary
-/// words which alters the behaviour of the ranking.
-/// To counter act this, the system runs the same correction on indexed
-/// text fields to counter act this name handling issue.
-fn parse_fast_fuzzy_query(
-    query: &str,
+/// The maximum amount of typos a word can be escalated to tolerate.
+const MAX_ESCALATION_DISTANCE: u8 = 2;
+
+/// Retries a query as a fuzzy query with increasing typo tolerance
+/// while it matches fewer than the minimum amount of documents.
+struct Escalation {
+    min_results: usize,
+    query: String,
+    first_distance: u8,
     search_fields: Arc<Vec<(Field, Score)>>,
-    strip_stop_words: bool,
-) -> Result<Box<dyn Query>> {
-    debug!("using fast fuzzy system for {}", &query);
-    if query.is_empty() {
-        return Ok(Box::new(EmptyQuery {}));
-    }
+}
 
-    let stop_words = crate::stop_words::get_hashset_words()?;
-    let mut parts: Vec<(Occur, Box<dyn Query>)> = Vec::new();
-    let sentence = correct_sentence(query, 1);
-    let words: Vec<&str> = sentence.split(" ").collect();
-    let mut ignore_stop_words = false;
-    if strip_stop_words && words.len() > 1 {
-        for word in words.iter() {
-            if !stop_words.contains(*word) {
-                ignore_stop_words = true;
+impl Escalation {
+    /// Counts the matches of the filtered query, escalating it until it
+    /// matches at least `min_results` documents or the maximum distance
+    /// is reached.
+    ///
+    /// Returns the query the search should run and whether it was escalated,
+    /// each escalation matches a superset of the previous query's words so
+    /// the most tolerant query needed is returned.
+    ///
+    /// The counts stop once `min_results` documents have been counted and
+    /// are subject to the search's limits, no further escalation is tried
+    /// once the deadline has passed.
+    fn apply(
+        &self,
+        searcher: &Searcher,
+        executor: &Executor,
+        query: Box<dyn Query>,
+        filters: &[Box<dyn Query>],
+        options: &ParseOptions,
+        limits: &SearchLimits,
+    ) -> Result<(Box<dyn Query>, bool)> {
+        let counter = || limits.wrap_capped(Count, self.min_results);
+        let mut count = searcher.search_with_executor(query.as_ref(), &counter(), executor)?;
+        let mut query = query;
+        let mut escalated = false;
+
+        for distance in self.first_distance..=MAX_ESCALATION_DISTANCE {
+            if count >= self.min_results || limits.deadline.expired() {
                 break;
             }
-        }
-    }
 
-    for search_term in words.iter() {
-        debug!("making fast-fuzzy term for {}", &search_term);
-        if ignore_stop_words && stop_words.contains(*search_term) {
-            continue;
-        }
-
-        for (field, boost) in search_fields.iter() {
-            let term = Term::from_field_text(*field, *search_term);
-            let query = Box::new(TermQuery::new(term, IndexRecordOption::WithFreqs));
+            let (fuzzy, _) = parse_fuzzy_query_with_distance(
+                searcher.index(),
+                &self.query,
+                self.search_fields.clone(),
+                options,
+                distance,
+            );
+            let fuzzy = with_filters(restrict_query(fuzzy, options), filters);
 
-            if *boost > 0.0f32 {
-                parts.push((Occur::Should, Box::new(BoostQuery::new(query, *boost))));
-                continue;
-            }
+            let escalated_count = searcher.search_with_executor(fuzzy.as_ref(), &counter(), executor)?;
+            debug!(
+                "escalated query to a distance of {} matching {} documents, previously {}",
+                distance, escalated_count, count,
+            );
 
-            parts.push((Occur::Should, query));
+            count = escalated_count;
+            query = fuzzy;
+            escalated = true;
         }
+
+        Ok((query, escalated))
     }
+}
 
-    Ok(Box::new(BooleanQuery::from(parts)))
+/// The field and limits of a grouped search.
+struct Grouping {
+    /// The single value fast field the hits are grouped by.
+    field: Field,
+
+    /// The maximum amount of groups.
+    groups_limit: usize,
+
+    /// The maximum amount of hits per group.
+    per_group_limit: usize,
 }
 
-/// Generates a MoreLikeThisQuery which matches similar documents
-/// as the given reference document.
-fn parse_more_like_this(ref_document: DocAddress) -> Result<Box<dyn Query>> {
-    let query = MoreLikeThisQuery::builder()
-        .with_min_doc_frequency(1)
-        .with_max_doc_frequency(10)
-        .with_min_term_frequency(1)
-        .with_min_word_length(2)
-        .with_max_word_length(18)
-        .with_boost_factor(1.0)
-        .with_stop_words(crate::stop_words::get_stop_words()?)
-        .with_document(ref_document);
+/// The field and inner hits of a collapsed search.
+struct Collapse {
+    /// The single value fast field the hits are collapsed by.
+    field: Field,
 
-    Ok(Box::new(query))
+    /// The maximum amount of inner hits per collapsed hit.
+    inner_hits: usize,
 }
 
-/// Represents a single query result.
-#[derive(Serialize)]
-pub struct QueryHit {
-    /// The address of the given document, this can be used for
-    /// 'more like this' queries.
-    pub(super) document_id: String,
+/// How the hits of a search are ranked, a search can only rank
+/// it's hits one way.
+enum Ranking {
+    /// The hits are ranked by their score.
+    Score,
 
-    /// The content of the document itself.
-    pub(super) doc: NamedFieldDocument,
+    /// The hits are ordered by the value of a fast field.
+    OrderBy(Field),
 
-    /// The ratio calculated for the search term and doc.
-    pub(super) ratio: serde_json::Value,
+    /// The hits are sorted by each of the keys in turn.
+    Sort(Vec<(SortField, SortOrder)>),
+
+    /// The scores of the hits are boosted by the value of a fast field.
+    Boost(FieldValueBoost),
+
+    /// The text ranking is fused with the ranking of a vector query.
+    Hybrid(HybridSearch),
+
+    /// The hits are grouped by the value of a fast field.
+    Group(Grouping),
+
+    /// The hits are ranked by their score with ties shuffled.
+    Shuffle(ShuffledTies),
+
+    /// The hits are collapsed to the best hit per value of a fast field.
+    Collapse(Collapse),
 }
 
-/// Represents the overall query result(s)
-#[derive(Serialize)]
-pub struct QueryResults {
-    /// The retrieved documents.
-    hits: Vec<QueryHit>,
+/// The parameters of executing a search besides the query itself.
+struct SearchRequest {
+    /// The maximum amount of hits returned.
+    limit: usize,
 
-    /// The total amount of documents matching the search
-    count: usize,
+    /// The amount of hits skipped before the returned hits.
+    offset: usize,
 
-    /// The amount of time taken to search in seconds.
-    time_taken: f32,
+    /// How the hits are ranked.
+    ranking: Ranking,
+
+    /// The time and document budget of the search.
+    limits: SearchLimits,
+
+    /// Records the matching documents if the results are cached.
+    recorder: MatchRecorder,
+
+    /// The date histogram of the matching documents if it's enabled.
+    histogram: DateHistogram,
+
+    /// The count the matching documents are counted up to if any.
+    count_cap: Option<usize>,
+
+    /// The ratio of the top hit's score hits must score at least if any.
+    relative_min_score: Option<Score>,
+
+    /// Whether or not to include additional debugging information.
+    debug: bool,
 }
 
-macro_rules! order_and_search {
-    ( $search:expr, $collector:expr, $field:expr, $query:expr, $executor:expr) => {{
-        let collector = $collector.order_by_fast_field($field);
-        $search.search_with_executor($query, &(collector, Count), $executor)
-    }};
+/// The vector half of a hybrid search.
+struct HybridSearch {
+    /// The filtered vector query.
+    vector: Box<dyn Query>,
+
+    /// How the text and vector rankings are fused.
+    options: HybridOptions,
 }
 
-macro_rules! process_search {
-    ( $search:expr, $schema:expr, $top_docs:expr ) => {{
-        let mut hits = Vec::with_capacity($top_docs.len());
-        for (ratio, ref_address) in $top_docs {
-            let retrieved_doc = $search.doc(ref_address)?;
-            let mut doc = $schema.to_named_doc(&retrieved_doc);
-            let id = doc.0
-                .remove("_id")
-                .ok_or_else(|| Error::msg("document has been missed labeled (missing identifier tag), the dataset is invalid"))?;
-
-            if let Value::U64(v) = id[0] {
-                hits.push(QueryHit {
-                    document_id: format!("{}", v),
-                    doc,
-                    ratio: serde_json::json!(ratio),
-                });
-            } else {
-                return Err(Error::msg("document has been missed labeled (missing identifier tag), the dataset is invalid"))
-            }
-        }
+/// Drops any hits scoring below the given ratio of the top hit's score
+/// and slices out the requested page of the remaining hits.
+///
+/// The hits are expected to be ordered by their score descending, starting
+/// from the top hit of the search rather than the top hit of the page.
+fn apply_relative_min_score(
+    top_docs: Vec<(Score, DocAddress)>,
+    ratio: Option<Score>,
+    offset: usize,
+    limit: usize,
+) -> Vec<(Score, DocAddress)> {
+    let min_score = match (ratio, top_docs.first()) {
+        (Some(ratio), Some((top_score, _))) => Some(top_score * ratio),
+        _ => None,
+    };
 
-        hits
-    }};
+    top_docs
+        .into_iter()
+        .filter(|(score, _)| min_score.map_or(true, |min_score| *score >= min_score))
+        .skip(offset)
+        .take(limit)
+        .collect()
 }
 
 /// Executes a search for a given query with a given searcher, limit and schema.
@@ -556,44 +4097,186 @@ macro_rules! process_search {
 /// data.
 fn search(
     query: Box<dyn Query>,
-    searcher: LeasedItem<Searcher>,
+    searcher: &Searcher,
     executor: &Executor,
-    limit: usize,
-    offset: usize,
-    schema: Schema,
-    order_by: Option<Field>,
+    ctx: HitContext,
+    request: SearchRequest,
 ) -> Result<QueryResults> {
+    let SearchRequest {
+        limit,
+        offset,
+        ranking,
+        limits,
+        recorder,
+        histogram,
+        count_cap,
+        relative_min_score,
+        debug,
+    } = request;
+
     let start = std::time::Instant::now();
+    let stats = SegmentStats::default();
+    let counter = CappedCount::new(count_cap);
+    let mut groups = None;
 
-    let collector = TopDocs::with_limit(limit).and_offset(offset);
+    // `TopDocs` panics with a limit of 0, it's never used if no hits are requested.
+    let collector = TopDocs::with_limit(limit.max(1)).and_offset(offset);
 
-    let (hits, count) = if let Some(field) = order_by {
-        match schema.get_field_entry(field).field_type() {
-            FieldType::I64(_) => {
-                let out: (Vec<(i64, DocAddress)>, usize) =
-                    order_and_search!(searcher, collector, field, &query, executor)?;
-                (process_search!(searcher, schema, out.0), out.1)
-            },
-            FieldType::U64(_) => {
-                let out: (Vec<(u64, DocAddress)>, usize) =
-                    order_and_search!(searcher, collector, field, &query, executor)?;
-                (process_search!(searcher, schema, out.0), out.1)
-            },
-            FieldType::F64(_) => {
-                let out: (Vec<(f64, DocAddress)>, usize) =
-                    order_and_search!(searcher, collector, field, &query, executor)?;
-                (process_search!(searcher, schema, out.0), out.1)
-            },
-            FieldType::Date(_) => {
-                let out: (Vec<(i64, DocAddress)>, usize) =
-                    order_and_search!(searcher, collector, field, &query, executor)?;
-                (process_search!(searcher, schema, out.0), out.1)
-            },
-            _ => return Err(Error::msg("field is not a fast field")),
-        }
-    } else {
-        let (out, count) = searcher.search_with_executor(&query, &(collector, Count), executor)?;
-        (process_search!(searcher, schema, out), count)
+    // The scored hits are collected from the top hit of the search so the
+    // relative min score isn't relative to the first hit of the page.
+    let scored = TopDocs::with_limit(offset + limit.max(1));
+
+    let (hits, count) = match ranking {
+        Ranking::Group(grouping) => {
+            let collector = GroupCollector::new(grouping.field, grouping.groups_limit, grouping.per_group_limit);
+            let ((fruits, _), count) =
+                searcher.search_with_executor(&query, &limits.wrap(stats.wrap(recorder.wrap(histogram.wrap((collector, counter))))), executor)?;
+
+            let mut out = Vec::with_capacity(fruits.len());
+            for fruit in fruits {
+                out.push(Group {
+                    key: fast_value_to_json(&ctx, grouping.field, fruit.key),
+                    count: fruit.count,
+                    hits: process_search!(searcher, ctx, fruit.hits),
+                });
+            }
+
+            groups = Some(out);
+            (vec![], count)
+        },
+        _ if limit == 0 => {
+            // Only the count is needed so scoring can stop once it exceeds the cap,
+            // unless the histogram needs to see every match.
+            let max_docs = if histogram.is_enabled() { usize::MAX } else { counter.limit() };
+            let count = searcher.search_with_executor(
+                &query,
+                &limits.wrap_capped(stats.wrap(recorder.wrap(histogram.wrap(counter))), max_docs),
+                executor,
+            )?;
+            (vec![], count)
+        },
+        Ranking::Collapse(collapse) => {
+            // The groups are ranked by their best hit so the top groups are
+            // the top collapsed hits, each distinct value is one collapsed hit.
+            let collector = GroupCollector::new(collapse.field, offset + limit, collapse.inner_hits + 1);
+            let ((fruits, count), _) =
+                searcher.search_with_executor(&query, &limits.wrap(stats.wrap(recorder.wrap(histogram.wrap((collector, counter))))), executor)?;
+
+            let mut out = Vec::with_capacity(limit);
+            for fruit in fruits.into_iter().skip(offset) {
+                let mut hits = process_search!(searcher, ctx, fruit.hits).into_iter();
+                if let Some(mut hit) = hits.next() {
+                    hit.inner_hits = Some(hits.collect());
+                    out.push(hit);
+                }
+            }
+
+            (out, count)
+        },
+        Ranking::Hybrid(hybrid) => {
+            // Every hit of either ranking counts as a match of the search, each
+            // of the three passes has the full document budget.
+            let union = BooleanQuery::union(vec![query.box_clone(), hybrid.vector.box_clone()]);
+            let count =
+                searcher.search_with_executor(&union, &limits.wrap(stats.wrap(recorder.wrap(histogram.wrap(counter)))), executor)?;
+
+            let depth = offset + limit;
+            let text = searcher.search_with_executor(&query, &limits.wrap(TopDocs::with_limit(depth)), executor)?;
+            let vector =
+                searcher.search_with_executor(&hybrid.vector, &limits.wrap(TopDocs::with_limit(depth)), executor)?;
+
+            let fused = fusion::fuse(text, vector, &hybrid.options);
+            let fused = apply_relative_min_score(fused, relative_min_score, offset, limit);
+            (process_search!(searcher, ctx, fused), count)
+        },
+        Ranking::Sort(keys) => {
+            let fields: Vec<SortField> = keys.iter().map(|(field, _)| *field).collect();
+            let collector = MultiSortCollector::new(keys, limit, offset);
+            let (out, count) =
+                searcher.search_with_executor(&query, &limits.wrap(stats.wrap(recorder.wrap(histogram.wrap((collector, counter))))), executor)?;
+
+            // Each hit's ratio is the array of it's key values.
+            let top_docs: Vec<(serde_json::Value, DocAddress)> = out
+                .into_iter()
+                .map(|(values, address)| {
+                    let values = fields
+                        .iter()
+                        .zip(values)
+                        .map(|(field, value)| match (field, value) {
+                            (SortField::Field(field), SortValue::Value(value)) => fast_value_to_json(&ctx, *field, value),
+                            (_, SortValue::Score(score)) => serde_json::json!(score),
+                            (SortField::Score, SortValue::Value(value)) => serde_json::json!(value),
+                        })
+                        .collect();
+                    (serde_json::Value::Array(values), address)
+                })
+                .collect();
+            (process_search!(searcher, ctx, top_docs), count)
+        },
+        Ranking::OrderBy(field) => {
+            let entry = ctx.schema.get_field_entry(field);
+            match entry.field_type() {
+                FieldType::I64(_) => {
+                    let out: (Vec<(i64, DocAddress)>, usize) =
+                        order_and_search!(searcher, collector, field, &query, executor, limits, stats, recorder, histogram, counter)?;
+
+                    // Decimal fields are sorted by their exact scaled value and
+                    // emit the exact decimal string.
+                    if let Some(scale) = ctx.decimal_fields.get(entry.name()) {
+                        let top_docs: Vec<(String, DocAddress)> = out.0
+                            .into_iter()
+                            .map(|(v, address)| (format_decimal(v, *scale), address))
+                            .collect();
+                        (process_search!(searcher, ctx, top_docs), out.1)
+                    } else {
+                        (process_search!(searcher, ctx, out.0), out.1)
+                    }
+                },
+                FieldType::U64(_) => {
+                    let out: (Vec<(u64, DocAddress)>, usize) =
+                        order_and_search!(searcher, collector, field, &query, executor, limits, stats, recorder, histogram, counter)?;
+                    (process_search!(searcher, ctx, out.0), out.1)
+                },
+                FieldType::F64(_) => {
+                    let out: (Vec<(f64, DocAddress)>, usize) =
+                        order_and_search!(searcher, collector, field, &query, executor, limits, stats, recorder, histogram, counter)?;
+                    (process_search!(searcher, ctx, out.0), out.1)
+                },
+                FieldType::Date(_) => {
+                    let out: (Vec<(i64, DocAddress)>, usize) =
+                        order_and_search!(searcher, collector, field, &query, executor, limits, stats, recorder, histogram, counter)?;
+
+                    // Dates are emitted the same way as the stored dates of the document.
+                    let top_docs: Vec<(serde_json::Value, DocAddress)> = out.0
+                        .into_iter()
+                        .map(|(v, address)| (timestamp_to_json(v), address))
+                        .collect();
+                    (process_search!(searcher, ctx, top_docs), out.1)
+                },
+                _ => return Err(Error::msg("field is not a fast field")),
+            }
+        },
+        Ranking::Shuffle(shuffle) => {
+            let collector = shuffle.wrap(scored);
+            let (out, count) =
+                searcher.search_with_executor(&query, &limits.wrap(stats.wrap(recorder.wrap(histogram.wrap((collector, counter))))), executor)?;
+            let out = out.into_iter().map(|((score, _), address)| (score, address)).collect();
+            let out = apply_relative_min_score(out, relative_min_score, offset, limit);
+            (process_search!(searcher, ctx, out), count)
+        },
+        Ranking::Boost(boost) => {
+            let collector = boost.wrap(scored);
+            let (out, count) =
+                searcher.search_with_executor(&query, &limits.wrap(stats.wrap(recorder.wrap(histogram.wrap((collector, counter))))), executor)?;
+            let out = apply_relative_min_score(out, relative_min_score, offset, limit);
+            (process_search!(searcher, ctx, out), count)
+        },
+        Ranking::Score => {
+            let (out, count) =
+                searcher.search_with_executor(&query, &limits.wrap(stats.wrap(recorder.wrap(histogram.wrap((scored, counter))))), executor)?;
+            let out = apply_relative_min_score(out, relative_min_score, offset, limit);
+            (process_search!(searcher, ctx, out), count)
+        },
     };
 
     let elapsed = start.elapsed();
@@ -603,9 +4286,407 @@ fn search(
         elapsed, limit, count
     );
 
+    let (count, count_capped) = counter.resolve(count);
+    let truncated = limits.deadline.expired() || limits.budget.exhausted() || count_capped;
+    if limits.deadline.expired() {
+        debug!("search exceeded its time budget, returning partial results");
+    }
+    if limits.budget.exhausted() {
+        debug!("search exceeded its document budget, returning partial results");
+    }
+
+    let debug = if debug {
+        Some(DebugInfo {
+            segments_total: stats.total(),
+            segments_scanned: stats.scanned(),
+            segments_skipped: stats.skipped(),
+        })
+    } else {
+        None
+    };
+
+    let (page, total_pages) = pagination(offset, limit, count);
+    let skipped_docs = ctx.skipped_docs.get();
+
     Ok(QueryResults {
         time_taken: 0f32, // filled in by handler later
+        mode: String::new(), // filled in by handler later
+        escalated: false, // filled in by handler later
+        parallel_fallback: false, // filled in by handler later
+        skipped_docs,
         hits,
         count,
+        page,
+        page_size: limit,
+        total_pages,
+        truncated,
+        debug,
+        results_token: None,
+        note: None,
+        total_docs: None,
+        echo: None,
+        generation: 0, // filled in by handler later
+        groups,
+        buckets: histogram.buckets().map(|buckets| {
+            buckets
+                .into_iter()
+                .map(|(timestamp, count)| HistogramBucket {
+                    timestamp: timestamp_to_json(timestamp),
+                    count,
+                })
+                .collect()
+        }),
+        results_hash: None, // filled in by handler later
+        not_modified: false,
     })
 }
+
+/// Calculates the current page and the total amount of pages of a search.
+///
+/// A limit of 0 only counts the matching documents so there are no pages.
+fn pagination(offset: usize, limit: usize, count: usize) -> (usize, usize) {
+    if limit == 0 {
+        return (0, 0);
+    }
+
+    let total_pages = (count + limit - 1) / limit;
+    (offset / limit, total_pages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(boosts: &[Score]) -> Vec<(Field, Score)> {
+        boosts
+            .iter()
+            .enumerate()
+            .map(|(id, boost)| (Field::from_field_id(id as u32), *boost))
+            .collect()
+    }
+
+    /// Creates an index with a field of every fast field type, the
+    /// `price` field is a decimal field with a scale of 2.
+    fn sort_index() -> (tantivy::Index, Arc<HashMap<String, u32>>) {
+        let opts = tantivy::schema::IntOptions::default()
+            .set_fast(Cardinality::SingleValue)
+            .set_indexed()
+            .set_stored();
+
+        let mut builder = Schema::builder();
+        let id = builder.add_u64_field("_id", opts.clone());
+        let int = builder.add_i64_field("int", opts.clone());
+        let uint = builder.add_u64_field("uint", opts.clone());
+        let float = builder.add_f64_field("float", opts.clone());
+        let price = builder.add_i64_field("price", opts.clone());
+        let date = builder.add_date_field("date", opts);
+
+        let index = tantivy::Index::create_in_ram(builder.build());
+        let mut writer = index.writer_with_num_threads(1, 15_000_000).unwrap();
+        for n in 1..=2 {
+            let mut doc = tantivy::Document::new();
+            doc.add_u64(id, n);
+            doc.add_i64(int, -(n as i64));
+            doc.add_u64(uint, n);
+            doc.add_f64(float, n as f64 + 0.5);
+            doc.add_i64(price, n as i64 * 1250);
+            let dt = chrono::NaiveDateTime::from_timestamp(1_600_000_000 + n as i64, 0);
+            doc.add_date(date, &tantivy::DateTime::from_utc(dt, chrono::Utc));
+            writer.add_document(doc);
+        }
+        writer.commit().unwrap();
+
+        let mut decimal_fields = HashMap::new();
+        decimal_fields.insert("price".to_string(), 2);
+        (index, Arc::new(decimal_fields))
+    }
+
+    /// Searches every document of the sort index with the ranking built
+    /// from it's schema, skipping the first `offset` hits.
+    fn search_sort_index(ranking: impl FnOnce(&Schema) -> Ranking, offset: usize) -> QueryResults {
+        let (index, decimal_fields) = sort_index();
+        let searcher = index.reader().unwrap().searcher();
+        let schema = index.schema();
+        let ranking = ranking(&schema);
+
+        let ctx = HitContext {
+            schema,
+            decimal_fields,
+            highlighter: None,
+            position_finder: None,
+            fuzzy_matcher: None,
+            id_field: Arc::new("_id".to_string()),
+            private_fields: Arc::new(HashSet::new()),
+            explainer: None,
+            top_explainer: None,
+            stream: None,
+            field_aliases: HashMap::new(),
+            return_fields: None,
+            skip_malformed_docs: false,
+            skipped_docs: Cell::new(0),
+            term_vectors: None,
+        };
+
+        let request = SearchRequest {
+            limit: 10,
+            offset,
+            ranking,
+            limits: SearchLimits::new(Deadline::new(None), DocBudget::new(None)),
+            recorder: MatchRecorder::new(false),
+            histogram: DateHistogram::disabled(),
+            count_cap: None,
+            relative_min_score: None,
+            debug: false,
+        };
+        search(Box::new(tantivy::query::AllQuery), &searcher, &Executor::single_thread(), ctx, request).unwrap()
+    }
+
+    /// Searches every document ordered by the given field, returning the
+    /// ratio of each hit.
+    fn ordered_ratios(field: &str) -> Vec<serde_json::Value> {
+        let results = search_sort_index(
+            |schema| match schema.get_field(field) {
+                None => Ranking::Score,
+                Some(field) => Ranking::OrderBy(field),
+            },
+            0,
+        );
+
+        results.hits.into_iter().map(|hit| hit.ratio).collect()
+    }
+
+    #[test]
+    fn collapsed_hits_skip_the_offset() {
+        let collapse = |schema: &Schema| {
+            Ranking::Collapse(Collapse {
+                field: schema.get_field("uint").unwrap(),
+                inner_hits: 1,
+            })
+        };
+
+        // Every document scores the same so the hits are in index order.
+        let values = |results: QueryResults| -> Vec<Value> {
+            results.hits.into_iter().map(|hit| hit.doc.0["uint"][0].clone()).collect()
+        };
+        assert_eq!(values(search_sort_index(collapse, 0)), vec![Value::U64(1), Value::U64(2)]);
+        assert_eq!(values(search_sort_index(collapse, 1)), vec![Value::U64(2)]);
+        assert!(values(search_sort_index(collapse, 2)).is_empty());
+    }
+
+    #[test]
+    fn i64_sort_values_are_signed_numbers() {
+        let ratios = ordered_ratios("int");
+        assert_eq!(ratios, vec![serde_json::json!(-1i64), serde_json::json!(-2i64)]);
+        assert!(ratios.iter().all(serde_json::Value::is_i64));
+    }
+
+    #[test]
+    fn u64_sort_values_are_unsigned_numbers() {
+        let ratios = ordered_ratios("uint");
+        assert_eq!(ratios, vec![serde_json::json!(2u64), serde_json::json!(1u64)]);
+        assert!(ratios.iter().all(serde_json::Value::is_u64));
+    }
+
+    #[test]
+    fn f64_sort_values_are_floats() {
+        let ratios = ordered_ratios("float");
+        assert_eq!(ratios, vec![serde_json::json!(2.5f64), serde_json::json!(1.5f64)]);
+        assert!(ratios.iter().all(serde_json::Value::is_f64));
+    }
+
+    #[test]
+    fn decimal_sort_values_are_exact_strings() {
+        let ratios = ordered_ratios("price");
+        assert_eq!(ratios, vec![serde_json::json!("25.00"), serde_json::json!("12.50")]);
+    }
+
+    #[test]
+    fn date_sort_values_are_rfc3339_strings() {
+        let ratios = ordered_ratios("date");
+        assert_eq!(
+            ratios,
+            vec![
+                serde_json::json!("2020-09-13T12:26:42Z"),
+                serde_json::json!("2020-09-13T12:26:41Z"),
+            ],
+        );
+    }
+
+    /// Decodes any MessagePack value into the JSON value serde_json would
+    /// produce for the same data, binary values become arrays of bytes.
+    struct Decoded(serde_json::Value);
+
+    impl<'de> serde::Deserialize<'de> for Decoded {
+        fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            deserializer.deserialize_any(DecodedVisitor).map(Decoded)
+        }
+    }
+
+    struct DecodedVisitor;
+
+    impl<'de> serde::de::Visitor<'de> for DecodedVisitor {
+        type Value = serde_json::Value;
+
+        fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            f.write_str("any MessagePack value")
+        }
+
+        fn visit_bool<E>(self, v: bool) -> Result<Self::Value, E> {
+            Ok(serde_json::json!(v))
+        }
+
+        fn visit_i64<E>(self, v: i64) -> Result<Self::Value, E> {
+            Ok(serde_json::json!(v))
+        }
+
+        fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E> {
+            Ok(serde_json::json!(v))
+        }
+
+        fn visit_f64<E>(self, v: f64) -> Result<Self::Value, E> {
+            Ok(serde_json::json!(v))
+        }
+
+        fn visit_str<E>(self, v: &str) -> Result<Self::Value, E> {
+            Ok(serde_json::json!(v))
+        }
+
+        fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E> {
+            Ok(serde_json::json!(v))
+        }
+
+        fn visit_unit<E>(self) -> Result<Self::Value, E> {
+            Ok(serde_json::Value::Null)
+        }
+
+        fn visit_seq<A: serde::de::SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut values = vec![];
+            while let Some(Decoded(value)) = seq.next_element()? {
+                values.push(value);
+            }
+            Ok(serde_json::Value::Array(values))
+        }
+
+        fn visit_map<A: serde::de::MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+            let mut values = serde_json::Map::new();
+            while let Some((key, Decoded(value))) = map.next_entry::<String, Decoded>()? {
+                values.insert(key, value);
+            }
+            Ok(serde_json::Value::Object(values))
+        }
+    }
+
+    #[test]
+    fn msgpack_results_round_trip() {
+        let dt = chrono::NaiveDateTime::from_timestamp(1_600_000_000, 0);
+
+        let mut doc = NamedFieldDocument(Default::default());
+        doc.0.insert("published".to_string(), vec![Value::Date(tantivy::DateTime::from_utc(dt, chrono::Utc))]);
+        doc.0.insert("thumbnail".to_string(), vec![Value::Bytes(vec![0, 1, 127, 255])]);
+        doc.0.insert("price".to_string(), vec![Value::Str(format_decimal(1250, 2))]);
+
+        let hit = QueryHit {
+            document_id: "1".to_string(),
+            doc,
+            ratio: timestamp_to_json(1_600_000_000),
+            highlights: None,
+            positions: None,
+            fuzzy_matches: None,
+            score_components: None,
+            explanation: None,
+            term_vectors: None,
+            inner_hits: None,
+        };
+
+        let results = QueryResults {
+            time_taken: 0.5,
+            mode: "Normal".to_string(),
+            escalated: false,
+            parallel_fallback: false,
+            skipped_docs: 0,
+            hits: vec![hit],
+            count: 1,
+            page: 0,
+            page_size: 10,
+            total_pages: 1,
+            truncated: false,
+            debug: None,
+            results_token: None,
+            note: None,
+            total_docs: Some(1),
+            echo: None,
+            generation: 3,
+            groups: None,
+            buckets: None,
+            results_hash: Some(u64::MAX.to_string()),
+            not_modified: false,
+        };
+
+        let packed = results.to_msgpack().unwrap();
+        let Decoded(decoded) = rmp_serde::from_slice(&packed).unwrap();
+        assert_eq!(decoded, serde_json::to_value(&results).unwrap());
+
+        let doc = &decoded["hits"][0]["doc"];
+        assert_eq!(doc["price"], serde_json::json!(["12.50"]));
+        assert!(doc["published"][0].is_string());
+        assert_eq!(decoded["hits"][0]["ratio"], serde_json::json!("2020-09-13T12:26:40Z"));
+    }
+
+    #[test]
+    fn fuzzy_terms_are_unlimited_without_a_cap() {
+        let words = vec!["a"; 100];
+        let search_fields = fields(&[0.0; 8]);
+
+        let (words, limited) = limit_fuzzy_terms(words, &search_fields, None);
+        assert_eq!(words.len(), 100);
+        assert_eq!(limited, search_fields);
+    }
+
+    #[test]
+    fn fuzzy_terms_under_the_cap_are_kept() {
+        let words = vec!["the", "quick", "fox"];
+        let search_fields = fields(&[0.0, 2.0]);
+
+        let (words, limited) = limit_fuzzy_terms(words, &search_fields, Some(6));
+        assert_eq!(words, vec!["the", "quick", "fox"]);
+        assert_eq!(limited, search_fields);
+    }
+
+    #[test]
+    fn long_fuzzy_query_is_capped() {
+        let query = "lorem ipsum dolor sit amet consectetur adipiscing elit sed do";
+        let words: Vec<&str> = query.split(' ').collect();
+        let search_fields = fields(&[0.0, 3.0, 0.5, 2.0, 0.0, 1.5, 0.0, 0.0]);
+
+        let (words, limited) = limit_fuzzy_terms(words, &search_fields, Some(20));
+        assert!(words.len() * limited.len() <= 20);
+
+        // The highest boosted fields are kept over the rest.
+        assert_eq!(words.len(), 10);
+        assert_eq!(
+            limited,
+            vec![(Field::from_field_id(1), 3.0), (Field::from_field_id(3), 2.0)],
+        );
+    }
+
+    #[test]
+    fn fuzzy_query_over_a_single_field_drops_trailing_words() {
+        let words = vec!["one", "two", "three", "four", "five"];
+        let search_fields = fields(&[0.0, 2.0]);
+
+        let (words, limited) = limit_fuzzy_terms(words, &search_fields, Some(3));
+        assert_eq!(words, vec!["one", "two", "three"]);
+        assert_eq!(limited, vec![(Field::from_field_id(1), 2.0)]);
+    }
+
+    #[test]
+    fn term_boosts_skip_phrases_and_operators() {
+        let mut term_boosts = HashMap::new();
+        term_boosts.insert("big".to_string(), 2.0);
+        term_boosts.insert("and".to_string(), 2.0);
+
+        assert_eq!(boost_query_terms("big dog", &term_boosts), "big^2 dog");
+        assert_eq!(boost_query_terms("\"a big dog\" big", &term_boosts), "\"a big dog\" big^2");
+        assert_eq!(boost_query_terms("cat AND big", &term_boosts), "cat AND big^2");
+    }
+}
@@ -0,0 +1,230 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use anyhow::{Error, Result};
+use hashbrown::HashMap;
+use parking_lot::Mutex;
+use tantivy::collector::{Collector, SegmentCollector};
+use tantivy::query::{ConstScorer, EmptyScorer, Explanation, Query, Scorer, Weight};
+use tantivy::{DocId, DocSet, Score, Searcher, SegmentId, SegmentReader, TantivyError};
+
+use crate::helpers::hash;
+use crate::index::queries::VecDocSet;
+
+/// The maximum amount of result sets kept for refinement per index,
+/// the oldest result sets are evicted first.
+const MAX_CACHED_RESULTS: usize = 64;
+
+/// Every document matched by a search, grouped by segment.
+#[derive(Debug)]
+struct CachedResults {
+    /// The segments of the searcher the results were collected from.
+    segments: Vec<SegmentId>,
+
+    /// The matched documents of each segment.
+    docs: HashMap<SegmentId, Vec<DocId>>,
+}
+
+/// A cache of the matched documents of previous searches which can be
+/// refined by further searches.
+///
+/// The cached results are tied to the segments of the searcher they were
+/// collected from, so they can only be used until the reader reloads.
+pub(super) struct ResultCache {
+    entries: Mutex<(HashMap<String, Arc<CachedResults>>, VecDeque<String>)>,
+}
+
+impl ResultCache {
+    pub(super) fn new() -> Self {
+        Self {
+            entries: Mutex::new((HashMap::new(), VecDeque::new())),
+        }
+    }
+
+    /// Stores the recorded matches returning the token which refers to them.
+    pub(super) fn insert(&self, searcher: &Searcher, recorder: MatchRecorder) -> String {
+        let docs = std::mem::take(&mut *recorder.matches.lock());
+        let results = CachedResults {
+            segments: searcher
+                .segment_readers()
+                .iter()
+                .map(|reader| reader.segment_id())
+                .collect(),
+            docs,
+        };
+
+        let token = format!("{}", hash(&uuid::Uuid::new_v4()));
+
+        let mut entries = self.entries.lock();
+        let (results_by_token, order) = &mut *entries;
+        results_by_token.insert(token.clone(), Arc::new(results));
+        order.push_back(token.clone());
+
+        while order.len() > MAX_CACHED_RESULTS {
+            if let Some(evicted) = order.pop_front() {
+                results_by_token.remove(&evicted);
+            }
+        }
+
+        token
+    }
+
+    /// Creates a query which only matches the cached results of the token.
+    ///
+    /// This fails if the token is unknown or the searcher's segments have
+    /// changed since the results were cached.
+    pub(super) fn within(&self, token: &str, searcher: &Searcher) -> Result<WithinQuery> {
+        let results = self
+            .entries
+            .lock()
+            .0
+            .get(token)
+            .cloned()
+            .ok_or_else(|| Error::msg("the given results token is unknown or has expired"))?;
+
+        let is_stale = searcher.segment_readers().len() != results.segments.len()
+            || searcher
+                .segment_readers()
+                .iter()
+                .zip(results.segments.iter())
+                .any(|(reader, segment_id)| reader.segment_id() != *segment_id);
+
+        if is_stale {
+            return Err(Error::msg(
+                "the index has changed since the results were cached, the search must be re-run",
+            ));
+        }
+
+        Ok(WithinQuery { results })
+    }
+}
+
+/// Records every document matched by a search if enabled.
+#[derive(Clone)]
+pub(super) struct MatchRecorder {
+    enabled: bool,
+    matches: Arc<Mutex<HashMap<SegmentId, Vec<DocId>>>>,
+}
+
+impl MatchRecorder {
+    pub(super) fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            matches: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Wraps a given collector so that it records the documents it collects.
+    pub(super) fn wrap<C: Collector>(&self, inner: C) -> MatchRecorderCollector<C> {
+        MatchRecorderCollector {
+            inner,
+            recorder: self.clone(),
+        }
+    }
+}
+
+/// A collector wrapper which records every document it collects.
+pub(super) struct MatchRecorderCollector<C: Collector> {
+    inner: C,
+    recorder: MatchRecorder,
+}
+
+impl<C: Collector> Collector for MatchRecorderCollector<C> {
+    type Fruit = C::Fruit;
+    type Child = MatchRecorderSegmentCollector<C::Child>;
+
+    fn for_segment(
+        &self,
+        segment_local_id: u32,
+        segment: &SegmentReader,
+    ) -> tantivy::Result<Self::Child> {
+        let inner = self.inner.for_segment(segment_local_id, segment)?;
+
+        Ok(MatchRecorderSegmentCollector {
+            inner,
+            recorder: self.recorder.clone(),
+            segment_id: segment.segment_id(),
+            docs: vec![],
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        self.inner.requires_scoring()
+    }
+
+    fn merge_fruits(
+        &self,
+        segment_fruits: Vec<<Self::Child as SegmentCollector>::Fruit>,
+    ) -> tantivy::Result<Self::Fruit> {
+        self.inner.merge_fruits(segment_fruits)
+    }
+}
+
+pub(super) struct MatchRecorderSegmentCollector<C: SegmentCollector> {
+    inner: C,
+    recorder: MatchRecorder,
+    segment_id: SegmentId,
+    docs: Vec<DocId>,
+}
+
+impl<C: SegmentCollector> SegmentCollector for MatchRecorderSegmentCollector<C> {
+    type Fruit = C::Fruit;
+
+    fn collect(&mut self, doc: DocId, score: Score) {
+        if self.recorder.enabled {
+            self.docs.push(doc);
+        }
+
+        self.inner.collect(doc, score);
+    }
+
+    fn harvest(self) -> Self::Fruit {
+        if self.recorder.enabled && !self.docs.is_empty() {
+            self.recorder.matches.lock().insert(self.segment_id, self.docs);
+        }
+
+        self.inner.harvest()
+    }
+}
+
+/// Matches only the documents of a cached result set.
+///
+/// Every match scores 0 so the query does not alter the ranking of
+/// the query it is combined with.
+#[derive(Debug, Clone)]
+pub(super) struct WithinQuery {
+    results: Arc<CachedResults>,
+}
+
+impl Query for WithinQuery {
+    fn weight(&self, _searcher: &Searcher, _scoring_enabled: bool) -> tantivy::Result<Box<dyn Weight>> {
+        Ok(Box::new(WithinWeight {
+            results: self.results.clone(),
+        }))
+    }
+}
+
+struct WithinWeight {
+    results: Arc<CachedResults>,
+}
+
+impl Weight for WithinWeight {
+    fn scorer(&self, reader: &SegmentReader, _boost: Score) -> tantivy::Result<Box<dyn Scorer>> {
+        match self.results.docs.get(&reader.segment_id()) {
+            None => Ok(Box::new(EmptyScorer)),
+            Some(docs) => Ok(Box::new(ConstScorer::new(VecDocSet::new(docs.clone()), 0.0))),
+        }
+    }
+
+    fn explain(&self, reader: &SegmentReader, doc: DocId) -> tantivy::Result<Explanation> {
+        let mut scorer = self.scorer(reader, 1.0)?;
+        if scorer.seek(doc) != doc {
+            return Err(TantivyError::InvalidArgument(format!(
+                "document {} is not part of the cached results",
+                doc
+            )));
+        }
+
+        Ok(Explanation::new("WithinQuery", 0.0))
+    }
+}
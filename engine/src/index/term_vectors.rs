@@ -0,0 +1,74 @@
+use std::collections::BTreeMap;
+
+use anyhow::{Error, Result};
+use hashbrown::HashMap;
+use tantivy::schema::{Field, FieldType, Value};
+use tantivy::tokenizer::TextAnalyzer;
+use tantivy::{Document, Index};
+
+/// The terms of each field of a document mapping to their frequency.
+pub type TermVectors = HashMap<String, BTreeMap<String, u32>>;
+
+/// Extracts the terms and their frequencies of the given fields of
+/// each document.
+///
+/// Tantivy doesn't store a term vector per document, instead the stored
+/// values are re-tokenized with the field's tokenizer which produces the
+/// same terms as were indexed.
+pub(super) struct TermVectorExtractor {
+    fields: Vec<(String, Field, TextAnalyzer)>,
+}
+
+impl TermVectorExtractor {
+    /// Creates a new extractor for the given fields, each field must be
+    /// an indexed and stored text field.
+    pub(super) fn create(index: &Index, names: Vec<String>) -> Result<Self> {
+        let schema = index.schema();
+
+        let mut fields = Vec::with_capacity(names.len());
+        for name in names {
+            let field = schema
+                .get_field(&name)
+                .ok_or_else(|| Error::msg(format!("term vector field {:?} does not exist", &name)))?;
+
+            let entry = schema.get_field_entry(field);
+            let is_indexed_text = matches!(
+                entry.field_type(),
+                FieldType::Str(options) if options.get_indexing_options().is_some()
+            );
+            if !(is_indexed_text && entry.is_stored()) {
+                return Err(Error::msg(format!(
+                    "term vector field {:?} must be an indexed and stored text field",
+                    &name
+                )));
+            }
+
+            let analyzer = index.tokenizer_for_field(field)?;
+            fields.push((name, field, analyzer));
+        }
+
+        Ok(Self { fields })
+    }
+
+    /// Gets the term vectors of the fields of a given document.
+    ///
+    /// The terms of every value of a multi-valued field are combined.
+    pub(super) fn extract(&self, doc: &Document) -> TermVectors {
+        let mut vectors = HashMap::with_capacity(self.fields.len());
+
+        for (name, field, analyzer) in self.fields.iter() {
+            let mut terms = BTreeMap::new();
+            for value in doc.get_all(*field) {
+                if let Value::Str(text) = value {
+                    analyzer.token_stream(text).process(&mut |token| {
+                        *terms.entry(token.text.clone()).or_insert(0) += 1;
+                    });
+                }
+            }
+
+            vectors.insert(name.clone(), terms);
+        }
+
+        vectors
+    }
+}
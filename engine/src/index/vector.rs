@@ -0,0 +1,174 @@
+use std::sync::Arc;
+
+use tantivy::fastfield::BytesFastFieldReader;
+use tantivy::query::{Explanation, Query, Scorer, Weight};
+use tantivy::schema::{Field, FieldType};
+use tantivy::{DocId, DocSet, Score, Searcher, SegmentReader, TantivyError, TERMINATED};
+
+use crate::structures::{decode_vector, VectorSimilarity};
+
+/// Matches every document with a vector in the given field, scoring each
+/// by the similarity of it's vector to the query vector.
+///
+/// This is a brute force search, every document's vector is read from the
+/// fast field and compared so the cost scales with the size of the index.
+/// Documents without a vector of the same dimensions are not matched.
+#[derive(Debug, Clone)]
+pub(super) struct VectorQuery {
+    field: Field,
+    vector: Arc<Vec<f32>>,
+    similarity: VectorSimilarity,
+}
+
+impl VectorQuery {
+    pub(super) fn new(field: Field, vector: Vec<f32>, similarity: VectorSimilarity) -> Self {
+        Self {
+            field,
+            vector: Arc::new(vector),
+            similarity,
+        }
+    }
+}
+
+impl Query for VectorQuery {
+    fn weight(&self, searcher: &Searcher, _scoring_enabled: bool) -> tantivy::Result<Box<dyn Weight>> {
+        let entry = searcher.schema().get_field_entry(self.field);
+        if !matches!(entry.field_type(), FieldType::Bytes(opts) if opts.is_fast()) {
+            return Err(TantivyError::SchemaError(format!(
+                "field {:?} must be a vector field",
+                entry.name(),
+            )));
+        }
+
+        let norm = norm(self.vector.iter().copied());
+
+        Ok(Box::new(VectorWeight {
+            field: self.field,
+            vector: self.vector.clone(),
+            norm,
+            similarity: self.similarity,
+        }))
+    }
+}
+
+struct VectorWeight {
+    field: Field,
+    vector: Arc<Vec<f32>>,
+    norm: f32,
+    similarity: VectorSimilarity,
+}
+
+impl Weight for VectorWeight {
+    fn scorer(&self, reader: &SegmentReader, boost: Score) -> tantivy::Result<Box<dyn Scorer>> {
+        let mut scorer = VectorScorer {
+            column: reader.fast_fields().bytes(self.field)?,
+            vector: self.vector.clone(),
+            norm: self.norm,
+            similarity: self.similarity,
+            boost,
+            max_doc: reader.max_doc(),
+            doc: 0,
+            score: 0.0,
+        };
+
+        // The first document may not have a vector.
+        if !scorer.score_current() {
+            scorer.advance();
+        }
+
+        Ok(Box::new(scorer))
+    }
+
+    fn explain(&self, reader: &SegmentReader, doc: DocId) -> tantivy::Result<Explanation> {
+        let mut scorer = self.scorer(reader, 1.0)?;
+        if scorer.seek(doc) != doc {
+            return Err(TantivyError::InvalidArgument(format!(
+                "document {} does not have a vector with matching dimensions",
+                doc
+            )));
+        }
+
+        Ok(Explanation::new(format!("{:?} similarity", self.similarity), scorer.score()))
+    }
+}
+
+struct VectorScorer {
+    column: BytesFastFieldReader,
+    vector: Arc<Vec<f32>>,
+    norm: f32,
+    similarity: VectorSimilarity,
+    boost: Score,
+    max_doc: DocId,
+    doc: DocId,
+    score: Score,
+}
+
+impl VectorScorer {
+    /// Scores the current document returning false if it has no vector
+    /// of the same dimensions as the query vector.
+    fn score_current(&mut self) -> bool {
+        if self.doc >= self.max_doc {
+            self.doc = TERMINATED;
+            return true;
+        }
+
+        let bytes = self.column.get_bytes(self.doc);
+        if bytes.len() != self.vector.len() * 4 {
+            return false;
+        }
+
+        let dot: f32 = decode_vector(bytes)
+            .zip(self.vector.iter())
+            .map(|(a, b)| a * b)
+            .sum();
+
+        let similarity = match self.similarity {
+            VectorSimilarity::Dot => dot,
+            VectorSimilarity::Cosine => {
+                let norms = norm(decode_vector(bytes)) * self.norm;
+                if norms == 0.0 {
+                    0.0
+                } else {
+                    dot / norms
+                }
+            },
+        };
+
+        self.score = similarity * self.boost;
+        true
+    }
+}
+
+impl DocSet for VectorScorer {
+    fn advance(&mut self) -> DocId {
+        loop {
+            if self.doc == TERMINATED {
+                return TERMINATED;
+            }
+
+            self.doc += 1;
+            if self.score_current() {
+                return self.doc;
+            }
+        }
+    }
+
+    fn doc(&self) -> DocId {
+        self.doc
+    }
+
+    fn size_hint(&self) -> u32 {
+        self.max_doc
+    }
+}
+
+impl Scorer for VectorScorer {
+    fn score(&mut self) -> Score {
+        self.score
+    }
+}
+
+/// Calculates the euclidean norm of a vector.
+fn norm(vector: impl Iterator<Item = f32>) -> f32 {
+    vector.map(|v| v * v).sum::<f32>().sqrt()
+}
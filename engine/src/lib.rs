@@ -5,9 +5,12 @@ mod correction;
 mod engine;
 pub mod helpers;
 mod index;
+pub mod preprocess;
+pub mod query_log;
 mod stop_words;
 mod storage;
 pub mod structures;
 
+pub use correction::CorrectionNormalizer;
 pub use engine::{LeasedIndex, SearchEngine};
 pub use tantivy;
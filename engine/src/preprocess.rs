@@ -0,0 +1,24 @@
+use anyhow::Result;
+
+use crate::structures::QueryPayload;
+
+/// A hook which runs on every search payload before it's parsed.
+///
+/// A preprocessor can rewrite the query, add mandatory filters or reject
+/// the search entirely by returning an error, which is returned to the
+/// caller in place of the results.
+///
+/// The preprocessor is invoked inline on the async search path so
+/// implementations **must not** block or do any expensive work.
+pub trait QueryPreprocessor: Send + Sync {
+    fn preprocess(&self, index: &str, payload: &mut QueryPayload) -> Result<()>;
+}
+
+/// A preprocessor that leaves every payload untouched. (Default)
+pub struct NoOpPreprocessor;
+
+impl QueryPreprocessor for NoOpPreprocessor {
+    fn preprocess(&self, _index: &str, _payload: &mut QueryPayload) -> Result<()> {
+        Ok(())
+    }
+}
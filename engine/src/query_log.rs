@@ -0,0 +1,66 @@
+use chrono::{DateTime, Utc};
+
+/// A structured record of a single executed search.
+#[derive(Debug, Clone)]
+pub struct QueryLogRecord {
+    /// The name of the index that was searched.
+    pub index: String,
+
+    /// The query string if one was given.
+    pub query: Option<String>,
+
+    /// The query mode that was actually used to parse the query.
+    pub mode: String,
+
+    /// The total amount of documents matching the search.
+    pub count: usize,
+
+    /// The amount of time taken to search in seconds.
+    pub time_taken: f32,
+
+    /// The time the search completed at.
+    pub timestamp: DateTime<Utc>,
+}
+
+/// A sink for structured query analytics.
+///
+/// The logger is invoked once per completed search on the async search
+/// path, implementations **must not** block or do any expensive work
+/// inline, instead they should hand the record off to be processed elsewhere.
+pub trait QueryLogger: Send + Sync {
+    fn log(&self, record: QueryLogRecord);
+}
+
+/// A logger that discards every record. (Default)
+pub struct NoOpQueryLogger;
+
+impl QueryLogger for NoOpQueryLogger {
+    fn log(&self, _record: QueryLogRecord) {}
+}
+
+/// A logger which sends records down a bounded channel to be consumed
+/// by a separate task.
+///
+/// If the channel is full the record is dropped rather than waiting
+/// for the consumer to catch up.
+pub struct ChannelQueryLogger {
+    sender: async_channel::Sender<QueryLogRecord>,
+}
+
+impl ChannelQueryLogger {
+    /// Creates a new logger with a given channel capacity returning
+    /// the receiving half of the channel.
+    pub fn create(capacity: usize) -> (Self, async_channel::Receiver<QueryLogRecord>) {
+        let (sender, receiver) = async_channel::bounded(capacity);
+
+        (Self { sender }, receiver)
+    }
+}
+
+impl QueryLogger for ChannelQueryLogger {
+    fn log(&self, record: QueryLogRecord) {
+        if let Err(e) = self.sender.try_send(record) {
+            debug!("[ QUERY-LOG ] dropping query log record: {}", e);
+        }
+    }
+}
@@ -1,5 +1,6 @@
 use core::fmt;
 use std::collections::BTreeMap;
+use std::convert::TryFrom;
 use std::str::FromStr;
 
 use anyhow::{Error, Result};
@@ -8,6 +9,7 @@ use hashbrown::HashMap;
 use serde::de::Visitor;
 use serde::{Deserialize, Deserializer, Serialize};
 use tantivy::schema::{
+    BytesOptions,
     Cardinality,
     Document as InternalDocument,
     Field,
@@ -19,8 +21,11 @@ use tantivy::schema::{
     STRING,
     TEXT,
 };
-use tantivy::{DateTime, Score};
+use tantivy::query::Occur;
+use tantivy::tokenizer::Language;
+use tantivy::{DateTime, ReloadPolicy, Score, Term};
 
+use crate::correction::CorrectionVerbosity;
 use crate::helpers::hash;
 
 /// A declared schema field type.
@@ -54,6 +59,23 @@ pub enum FieldDeclaration {
     ///
     /// This wont be tokenized.
     String { stored: bool },
+
+    /// A fixed point decimal field with given options.
+    ///
+    /// Values are stored as a i64 scaled by `10^scale` which gives exact
+    /// ordering and range filtering unlike f64 fields, values are returned
+    /// as exact decimal strings e.g. `"19.99"`.
+    Decimal {
+        scale: u32,
+        #[serde(flatten)]
+        options: IntOptions,
+    },
+
+    /// A dense vector of `dims` floats used by vector queries.
+    ///
+    /// Vectors are kept in a bytes fast field so they can be read per
+    /// document when scoring, they are neither indexed nor returned.
+    Vector { dims: usize },
 }
 
 /// The storage backend to store index documents in.
@@ -76,6 +98,8 @@ pub struct IndexDeclaration {
     writer_buffer: usize,
     writer_threads: Option<usize>,
     max_concurrency: u32,
+    #[serde(default)]
+    overflow_concurrency: u32,
     reader_threads: Option<u32>,
     search_fields: Vec<String>,
     #[serde(default)]
@@ -88,10 +112,78 @@ pub struct IndexDeclaration {
     use_fast_fuzzy: bool,
     #[serde(default)]
     strip_stop_words: bool,
+    #[serde(default)]
+    max_fuzzy_terms: Option<usize>,
+    #[serde(default = "default_id_field")]
+    id_field: String,
+    #[serde(default)]
+    bm25: Option<Bm25Params>,
+    #[serde(default)]
+    reload_policy: ReloadMode,
+    #[serde(default)]
+    skip_malformed_docs: bool,
+}
+
+/// When the index reader picks up newly committed documents.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ReloadMode {
+    /// The reader reloads shortly after every commit. (Default)
+    OnCommit,
+
+    /// The reader only reloads when explicitly asked to, this avoids
+    /// reloading after every commit during bulk indexing.
+    Manual,
+}
+
+impl Default for ReloadMode {
+    fn default() -> Self {
+        Self::OnCommit
+    }
+}
+
+impl From<ReloadMode> for ReloadPolicy {
+    fn from(v: ReloadMode) -> Self {
+        match v {
+            ReloadMode::OnCommit => ReloadPolicy::OnCommit,
+            ReloadMode::Manual => ReloadPolicy::Manual,
+        }
+    }
+}
+
+/// The parameters of the BM25 scoring function.
+///
+/// These only apply to the exact terms of the fast-fuzzy and case sensitive
+/// query modes, the standard fuzzy system and the tantivy query parser
+/// (`Normal` queries) always score with tantivy's defaults of 1.2 and 0.75.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct Bm25Params {
+    /// Controls the term frequency saturation, the default is 1.2.
+    #[serde(default = "default_bm25_k1")]
+    pub(crate) k1: Score,
+
+    /// Controls how much the field length normalizes the score,
+    /// the default is 0.75 and 0 disables length normalization.
+    #[serde(default = "default_bm25_b")]
+    pub(crate) b: Score,
+}
+
+fn default_bm25_k1() -> Score {
+    1.2
+}
+
+fn default_bm25_b() -> Score {
+    0.75
+}
+
+fn default_id_field() -> String {
+    "_id".to_string()
 }
 
 impl IndexDeclaration {
-    pub(crate) fn into_schema(self) -> LoadedIndex {
+    pub(crate) fn into_schema(self) -> Result<LoadedIndex> {
+        let mut decimal_fields = HashMap::new();
+        let mut vector_fields = HashMap::new();
         let mut indexed_text_fields = vec![];
         let mut fuzzy_search_fields = vec![];
         let mut schema = InternalSchemaBuilder::new();
@@ -101,12 +193,18 @@ impl IndexDeclaration {
             .set_stored()
             .set_indexed();
 
-        schema.add_u64_field("_id", opts);
+        // The id is always generated, a declared field of the same name
+        // would hold a second value the index can't look documents up by.
+        if self.fields.contains_key(&self.id_field) {
+            return Err(Error::msg(format!(
+                "field {:?} has the same name as the generated id field, set `id_field` to another name",
+                &self.id_field
+            )));
+        }
+
+        schema.add_u64_field(&self.id_field, opts);
 
         for (name, field) in self.fields {
-            if name == "_id" {
-                continue;
-            }
 
             match field {
                 FieldDeclaration::F64(opts) => {
@@ -121,6 +219,14 @@ impl IndexDeclaration {
                 FieldDeclaration::Date(opts) => {
                     schema.add_date_field(&name, opts);
                 },
+                FieldDeclaration::Decimal { scale, options } => {
+                    schema.add_i64_field(&name, options);
+                    decimal_fields.insert(name.clone(), scale);
+                },
+                FieldDeclaration::Vector { dims } => {
+                    schema.add_bytes_field(&name, BytesOptions::default().set_fast());
+                    vector_fields.insert(name.clone(), dims);
+                },
                 FieldDeclaration::String { stored } => {
                     let mut opts = STRING;
 
@@ -159,11 +265,12 @@ impl IndexDeclaration {
             };
         }
 
-        LoadedIndex {
+        Ok(LoadedIndex {
             name: self.name.into(),
             writer_buffer: self.writer_buffer,
             writer_threads: self.writer_threads.unwrap_or_else(|| num_cpus::get()),
             max_concurrency: self.max_concurrency,
+            overflow_concurrency: self.overflow_concurrency,
             reader_threads: self.reader_threads.unwrap_or(1),
             search_fields: self.search_fields,
             storage_type: self.storage_type,
@@ -171,10 +278,17 @@ impl IndexDeclaration {
             boost_fields: self.boost_fields,
             set_conjunction_by_default: self.set_conjunction_by_default,
             indexed_text_fields,
+            decimal_fields,
+            vector_fields,
             fuzzy_search_fields,
             use_fast_fuzzy: self.use_fast_fuzzy,
             strip_stop_words: self.strip_stop_words,
-        }
+            max_fuzzy_terms: self.max_fuzzy_terms,
+            id_field: self.id_field,
+            bm25: self.bm25,
+            reload_policy: self.reload_policy,
+            skip_malformed_docs: self.skip_malformed_docs,
+        })
     }
 }
 
@@ -195,6 +309,14 @@ pub struct LoadedIndex {
     /// The maximum searches that can be done at any one time.
     pub(crate) max_concurrency: u32,
 
+    /// The amount of additional searches that can be done at any one time
+    /// when the index is under load.
+    ///
+    /// These searches run on single-threaded executors which own no threads,
+    /// however the thread pool and searchers are always created with
+    /// `max_concurrency` + `overflow_concurrency` workers and searchers.
+    pub(crate) overflow_concurrency: u32,
+
     /// The number of reader threads to use.
     ///
     /// The current implementation is rather naive : multithreading is by splitting search
@@ -226,6 +348,12 @@ pub struct LoadedIndex {
     /// The set of fields which are indexed.
     pub(crate) indexed_text_fields: Vec<String>,
 
+    /// The set of decimal fields and their scale.
+    pub(crate) decimal_fields: HashMap<String, u32>,
+
+    /// The set of vector fields and their dimensions.
+    pub(crate) vector_fields: HashMap<String, usize>,
+
     /// The set of fields which are indexed.
     pub(crate) fuzzy_search_fields: Vec<(Field, Score)>,
 
@@ -239,12 +367,32 @@ pub struct LoadedIndex {
     ///
     /// This only applies to the fast-fuzzy query system.
     pub(crate) strip_stop_words: bool,
+
+    /// The maximum amount of fuzzy terms a single fuzzy query can
+    /// generate across all of it's words and search fields.
+    pub(crate) max_fuzzy_terms: Option<usize>,
+
+    /// The name of the private field holding each document's id.
+    pub(crate) id_field: String,
+
+    /// The custom BM25 parameters used to score term queries if any.
+    ///
+    /// This only applies to the fast-fuzzy and case sensitive query modes,
+    /// see `Bm25Params`.
+    pub(crate) bm25: Option<Bm25Params>,
+
+    /// When the index reader picks up newly committed documents.
+    pub(crate) reload_policy: ReloadMode,
+
+    /// Whether or not hits missing a valid id are skipped and logged
+    /// rather than failing the whole search.
+    pub(crate) skip_malformed_docs: bool,
 }
 
 /// The mode of the query.
 ///
 /// This can change how the system parses and handles the query.
-#[derive(Debug, Copy, Clone, Deserialize)]
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub enum QueryMode {
     /// This uses the tantivy query parser.
@@ -255,6 +403,117 @@ pub enum QueryMode {
 
     /// Gets documents similar to the reference document.
     MoreLikeThis,
+
+    /// Matches terms starting with each word of the query without any
+    /// typo tolerance, useful for fast autocomplete.
+    Prefix,
+
+    /// Matches each word of the query exactly as given without lowercasing.
+    ///
+    /// The search fields must be indexed with a case preserving tokenizer
+    /// (e.g. `raw` or `whitespace`) for this to match anything, fields
+    /// indexed with the default tokenizer are lowercased when indexing.
+    CaseSensitive,
+
+    /// Ranks documents by the similarity of their vector to the query
+    /// vector, see `vector` and `vector_field`.
+    ///
+    /// This compares the query vector against the vector of every
+    /// document so it scales linearly with the size of the index.
+    Vector,
+}
+
+/// How the similarity of two vectors is measured.
+#[derive(Debug, Copy, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum VectorSimilarity {
+    /// The cosine of the angle between the vectors. (Default)
+    Cosine,
+
+    /// The dot product of the vectors, this is equivalent to cosine
+    /// for normalized vectors but cheaper to compute.
+    Dot,
+}
+
+/// How the rankings of the text and vector queries of a hybrid search
+/// are combined.
+#[derive(Debug, Copy, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FusionMethod {
+    /// Reciprocal rank fusion, each hit scores `weight / (k + rank)` per
+    /// ranking which ignores the scale of the scores entirely. (Default)
+    Rrf,
+
+    /// Each ranking's scores are normalized to 0..1 and combined linearly.
+    Linear,
+}
+
+impl Default for FusionMethod {
+    fn default() -> Self {
+        Self::Rrf
+    }
+}
+
+/// How the scores of a query word matching several search fields are
+/// combined by the fuzzy and fast-fuzzy query modes.
+#[derive(Debug, Copy, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FieldCombination {
+    /// The scores of every matching field are added up. (Default)
+    Sum,
+
+    /// Only the best matching field counts plus the `tie_breaker` times
+    /// the scores of the other matching fields, so a word appearing in
+    /// many fields doesn't outrank a strong match in a single field.
+    Max,
+}
+
+impl Default for FieldCombination {
+    fn default() -> Self {
+        Self::Sum
+    }
+}
+
+/// The options of a hybrid search combining the text query with a
+/// vector query, see `vector` and `vector_field`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct HybridOptions {
+    /// How the rankings are combined, defaults to reciprocal rank fusion.
+    #[serde(default)]
+    pub(crate) method: FusionMethod,
+
+    /// The weight (0 to 1) of the vector ranking, the text ranking is
+    /// weighted by the remainder. The default is 0.5.
+    #[serde(default = "default_query_data::default_vector_weight")]
+    pub(crate) vector_weight: Score,
+
+    /// The rank constant `k` of reciprocal rank fusion, the default is 60.
+    #[serde(default = "default_query_data::default_rank_constant")]
+    pub(crate) rank_constant: usize,
+}
+
+/// The options of boosting recently dated documents, see `recency`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RecencyOptions {
+    /// The date fast field the age of a document is derived from.
+    pub(crate) field: String,
+
+    /// The age in seconds at which a document gets half of the boost,
+    /// the default is 7 days.
+    #[serde(default = "default_query_data::default_half_life")]
+    pub(crate) half_life: u64,
+
+    /// The maximum factor added to the score of a document, a document
+    /// dated now has it's score multiplied by `1 + weight`. The default
+    /// is 0.25 so the recency mostly breaks ties between similar documents.
+    #[serde(default = "default_query_data::default_recency_weight")]
+    pub(crate) weight: Score,
+}
+
+impl Default for VectorSimilarity {
+    fn default() -> Self {
+        Self::Cosine
+    }
 }
 
 impl Default for QueryMode {
@@ -263,17 +522,200 @@ impl Default for QueryMode {
     }
 }
 
-#[derive(Debug, Deserialize)]
+/// The width of each bucket of a date histogram.
+#[derive(Debug, Copy, Clone, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HistogramInterval {
+    /// Calendar days.
+    Day,
+
+    /// Weeks starting on Monday.
+    Week,
+
+    /// Calendar months.
+    Month,
+}
+
+/// The options of counting the matching documents per date interval,
+/// see `date_histogram`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct DateHistogramOptions {
+    /// The single value date fast field the documents are bucketed by.
+    pub(crate) field: String,
+
+    /// The width of each bucket.
+    pub(crate) interval: HistogramInterval,
+
+    /// The offset from UTC in minutes the buckets are aligned to, e.g.
+    /// `540` for days starting at midnight in Tokyo. The default is 0.
+    #[serde(default)]
+    pub(crate) utc_offset: i32,
+}
+
+/// The direction a sort key orders the hits in.
+#[derive(Debug, Copy, Clone, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    /// The smallest values first.
+    Asc,
+
+    /// The largest values first. (Default)
+    Desc,
+}
+
+impl Default for SortOrder {
+    fn default() -> Self {
+        Self::Desc
+    }
+}
+
+/// A single key of a multi key sort, see `sort`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SortKey {
+    /// The single value numeric fast field to sort by, or `_score` to
+    /// sort by the relevance score.
+    pub(crate) field: String,
+
+    /// The direction of the key, the default is descending.
+    #[serde(default)]
+    pub(crate) order: SortOrder,
+}
+
+/// The kind of executor a search runs on.
+///
+/// This trades off the latency of a single search against the
+/// throughput of the index as a whole.
+#[derive(Debug, Copy, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExecutorKind {
+    /// Runs the search on the calling thread only, preserving throughput
+    /// for interactive queries.
+    Single,
+
+    /// Runs the search across the configured reader threads, for
+    /// expensive queries which should complete as quickly as possible.
+    Parallel,
+}
+
+/// How a single field of a map query contributes to the matches.
+#[derive(Debug, Copy, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MapOccur {
+    /// The field must match. (Default)
+    Must,
+
+    /// The field is optional and only boosts the documents it matches.
+    Should,
+
+    /// The field must not match.
+    MustNot,
+}
+
+impl Default for MapOccur {
+    fn default() -> Self {
+        Self::Must
+    }
+}
+
+impl From<MapOccur> for Occur {
+    fn from(v: MapOccur) -> Self {
+        match v {
+            MapOccur::Must => Occur::Must,
+            MapOccur::Should => Occur::Should,
+            MapOccur::MustNot => Occur::MustNot,
+        }
+    }
+}
+
+/// How the query string of a single field of a map query is parsed.
+#[derive(Debug, Copy, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MapParseMode {
+    /// Every term of the query must match. (Default)
+    Terms,
+
+    /// The words are matched with typo tolerance like `QueryMode::Fuzzy`.
+    Fuzzy,
+
+    /// The words must appear next to each other in order, the field
+    /// must be indexed with positions.
+    Phrase,
+}
+
+impl Default for MapParseMode {
+    fn default() -> Self {
+        Self::Terms
+    }
+}
+
+/// The query of a single field of a map query.
+///
+/// This is either the query string alone which must match, or the
+/// query string along with how it contributes to the matches and
+/// how it's parsed.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum MapQueryValue {
+    Query(String),
+    WithOccur {
+        query: String,
+
+        #[serde(default)]
+        occur: MapOccur,
+
+        #[serde(default)]
+        mode: MapParseMode,
+    },
+}
+
+impl MapQueryValue {
+    pub(crate) fn query(&self) -> &str {
+        match self {
+            Self::Query(query) => query,
+            Self::WithOccur { query, .. } => query,
+        }
+    }
+
+    pub(crate) fn occur(&self) -> Occur {
+        match self {
+            Self::Query(_) => Occur::Must,
+            Self::WithOccur { occur, .. } => (*occur).into(),
+        }
+    }
+
+    pub(crate) fn mode(&self) -> MapParseMode {
+        match self {
+            Self::Query(_) => MapParseMode::default(),
+            Self::WithOccur { mode, .. } => *mode,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
 pub struct  QueryPayload {
-    /// A query string for `QueryMode::Fuzzy` and `QueryMode::Normal` queries.
+    /// A query string for `QueryMode::Fuzzy`, `QueryMode::Prefix` and `QueryMode::Normal` queries.
     pub(crate) query: Option<String>,
 
     /// A reference document for `QueryMode::MoreLikeThis`.
     pub(crate) document: Option<u64>,
 
+    /// Restricts `QueryMode::MoreLikeThis` to derive it's terms from only
+    /// the given fields of the reference document.
+    ///
+    /// Each field must be an indexed and stored text field.
+    pub(crate) more_like_this_fields: Option<Vec<String>>,
+
     /// A map of fields to query strings.
+    ///
+    /// Each field must match by default, a field can instead be given
+    /// as `{"query": ..., "occur": "should"}` to make it optional. The
+    /// query of a field can be matched fuzzily or as a phrase with
+    /// `{"query": ..., "mode": "fuzzy"}` or `"mode": "phrase"`, a query
+    /// starting with `~` e.g. `"~laptp"` is also matched fuzzily.
+    ///
+    /// Keyword (`string`) fields always match their whole value exactly.
     #[serde(default)]
-    pub map: HashMap<String, String>,
+    pub map: HashMap<String, MapQueryValue>,
 
     /// The query mode which determines which query system will be
     /// used.
@@ -281,6 +723,9 @@ pub struct  QueryPayload {
     pub(crate) mode: QueryMode,
 
     /// The amount of results to limit by, the default is 20.
+    ///
+    /// A limit of 0 only counts the matching documents without
+    /// fetching any hits.
     #[serde(default = "default_query_data::default_limit")]
     pub(crate) limit: usize,
 
@@ -290,7 +735,661 @@ pub struct  QueryPayload {
 
     /// The field to order content by, this has to be a fast field if
     /// not `None`.
+    ///
+    /// The reserved `_score` key explicitly orders by relevance score.
+    ///
+    /// The hits can only be ranked one way, so only one of `order_by`,
+    /// `sort`, `boost_field`, `recency`, `hybrid`, `group_by`, `shuffle_seed`
+    /// and `collapse` can be set.
     pub(crate) order_by: Option<String>,
+
+    /// Orders the hits by each of the keys in turn, the later keys only
+    /// ordering hits which are tied on every earlier key.
+    ///
+    /// Each hit's `ratio` is the array of it's key values. This cannot be
+    /// combined with any other ranking, see `order_by`.
+    #[serde(default)]
+    pub(crate) sort: Vec<SortKey>,
+
+    /// Rejects the search if `order_by` is not a single value fast field,
+    /// rather than silently ordering by relevance if the field doesn't exist.
+    #[serde(default)]
+    pub(crate) strict_order_by: bool,
+
+    /// A set of field names mapping to the name of the tokenizer used to
+    /// analyze their query in the `map` query instead of the field's own tokenizer.
+    ///
+    /// The terms produced by the tokenizer must be compatible with the terms
+    /// produced at index time, e.g. they must be lower cased in the same way.
+    #[serde(default)]
+    pub(crate) tokenizers: HashMap<String, String>,
+
+    /// A numeric fast field whose value multiplies the relevance score of
+    /// each document, e.g. a popularity signal.
+    ///
+    /// The value is normalized with `1 + ln(1 + value)` and clamped to
+    /// `max_field_boost`. This cannot be combined with any other ranking,
+    /// see `order_by`.
+    pub(crate) boost_field: Option<String>,
+
+    /// The maximum factor a document's score can be boosted by `boost_field`,
+    /// the default is 10.
+    #[serde(default = "default_query_data::default_max_field_boost")]
+    pub(crate) max_field_boost: Score,
+
+    /// A set of range filters the matched documents must all satisfy.
+    #[serde(default)]
+    pub(crate) ranges: Vec<RangeFilter>,
+
+    /// Generates highlighted snippets of the given fields for each hit.
+    pub(crate) highlight: Option<HighlightOptions>,
+
+    /// Returns the terms and their frequencies of the given fields for
+    /// each hit, e.g. for building related term clouds.
+    ///
+    /// Each field must be an indexed and stored text field, the terms are
+    /// produced by re-tokenizing the stored values.
+    #[serde(default)]
+    pub(crate) term_vectors: Vec<String>,
+
+    /// Only returns the given fields of each hit if set, the id of the
+    /// document is always returned.
+    ///
+    /// These are the field names of the schema, not their aliases, each
+    /// field must be a stored field.
+    pub(crate) return_fields: Option<Vec<String>>,
+
+    /// Includes additional debugging information in the results.
+    #[serde(default)]
+    pub(crate) debug: bool,
+
+    /// The maximum amount of time in milliseconds the search can take.
+    ///
+    /// If the search exceeds this time budget, any results collected so
+    /// far are returned and the results are marked as truncated.
+    ///
+    /// This is a soft limit, the time is only checked periodically while
+    /// scoring and the documents are still fetched after it has passed.
+    pub(crate) timeout: Option<u64>,
+
+    /// The maximum amount of ranked correction candidates searched per word
+    /// with the fast-fuzzy system, the default is 1 (the best correction only).
+    ///
+    /// Additional candidates are added as optional clauses with decreasing boosts.
+    #[serde(default = "default_query_data::default_correction_candidates")]
+    pub(crate) correction_candidates: usize,
+
+    /// How symspell selects the additional correction candidates per word.
+    ///
+    /// `top` only ever selects a single candidate so it can't be used with
+    /// more than 1 `correction_candidates`, the default is `closest` if more
+    /// candidates are requested and `top` otherwise.
+    #[serde(default)]
+    pub(crate) correction_verbosity: Option<CorrectionVerbosity>,
+
+    /// The factor applied to the boost of words which were altered by the
+    /// fast-fuzzy correction, the default is 0.8.
+    ///
+    /// Corrected words are less certain than words which matched exactly,
+    /// a factor of 1 weights them equally.
+    #[serde(default = "default_query_data::default_correction_penalty")]
+    pub(crate) correction_penalty: Score,
+
+    /// Folds the diacritics of the `Fuzzy` query words to ascii, e.g.
+    /// `café` is searched as `cafe`.
+    ///
+    /// This only matches if the indexed text was folded the same way, the
+    /// search fields must be indexed with a tokenizer using tantivy's
+    /// `AsciiFoldingFilter` otherwise accented words no longer match.
+    #[serde(default)]
+    pub(crate) fold_diacritics: bool,
+
+    /// How the scores of a word matching several search fields are combined
+    /// by the fuzzy and fast-fuzzy query modes, the default sums them.
+    #[serde(default)]
+    pub(crate) field_combination: FieldCombination,
+
+    /// The factor (0 to 1) the scores of the other matching fields are added
+    /// with when the `field_combination` is `max`, the default is 0.
+    #[serde(default)]
+    pub(crate) tie_breaker: Score,
+
+    /// Splits `Fuzzy` queries and fuzzy (`~`) field queries into words
+    /// with the tokenizer each field is indexed with rather than on spaces,
+    /// e.g. for CJK text indexed with an n-gram tokenizer.
+    ///
+    /// The tokens are not stemmed again, the standard fuzzy system is always
+    /// used as the fast-fuzzy correction only understands space separated words.
+    #[serde(default)]
+    pub(crate) analyze_query: bool,
+
+    /// Skips the fast-fuzzy correction of the query words for this query
+    /// even if the index uses it, the standard fuzzy system is used instead.
+    ///
+    /// This only affects the query, with fast-fuzzy the documents' text
+    /// is only indexed after being corrected so any words the correction
+    /// altered at index time, e.g. product codes, still can't be matched
+    /// exactly. Fields which must match exactly should be `string` fields.
+    #[serde(default)]
+    pub(crate) disable_correction: bool,
+
+    /// The language used to stem the words of fuzzy queries if any.
+    ///
+    /// The search fields must be indexed with a tokenizer stemming the
+    /// same language, e.g. `en_stem` for `English`.
+    pub(crate) stemming: Option<Language>,
+
+    /// Query terms mapping to the factor their matches are boosted by,
+    /// e.g. from an external popularity signal.
+    ///
+    /// Terms are compared case insensitively against the words of the
+    /// query, any terms which are not in the query are ignored. This
+    /// applies to the fuzzy, fast-fuzzy and normal query modes.
+    #[serde(default)]
+    pub(crate) term_boosts: HashMap<String, Score>,
+
+    /// Only match documents which have at least one value for this field.
+    ///
+    /// The field must be either indexed or a multi-value fast field, for
+    /// indexed fields every value of the field is read so this is costly
+    /// for fields with many distinct values.
+    pub(crate) exists: Option<String>,
+
+    /// Field names mapping to values which exclude a document from the
+    /// results if the field has any of them, e.g. `{"category": ["spam"]}`.
+    ///
+    /// This applies to every query mode. Each value is parsed according
+    /// to the field's type, text values must be of `string` fields as
+    /// `text` fields are tokenized.
+    #[serde(default)]
+    pub(crate) exclude: HashMap<String, Vec<String>>,
+
+    /// Words mapping to their synonyms which the fuzzy and fast-fuzzy
+    /// query modes expand each query word to, e.g. `{"laptop": ["notebook"]}`.
+    ///
+    /// Synonyms apply both ways and are matched with a slightly reduced
+    /// boost, each synonym must be a single word.
+    #[serde(default)]
+    pub(crate) synonyms: HashMap<String, Vec<String>>,
+
+    /// The minimum length of a word in characters for the standard fuzzy
+    /// system to match it with typo tolerance, the default is 4.
+    ///
+    /// Shorter words are matched exactly as they would otherwise match
+    /// many unrelated words.
+    #[serde(default = "default_query_data::default_min_fuzzy_len")]
+    pub(crate) min_fuzzy_len: usize,
+
+    /// Structures fuzzy queries for autocomplete.
+    ///
+    /// With standard fuzzy queries all completed words of the query are
+    /// required while the last word, the one being typed, is only matched
+    /// as an optional prefix. With fast-fuzzy the last word is matched as
+    /// an exact prefix instead of being corrected. Synonyms and stemming
+    /// only apply to the completed words.
+    #[serde(default)]
+    pub(crate) autocomplete: bool,
+
+    /// A hint for which kind of executor the search should run on.
+    ///
+    /// If not set the search runs on whatever executor is available. If
+    /// `parallel` is requested but every parallel executor is busy the
+    /// search falls back to a single-threaded executor and is reported
+    /// as `parallel_fallback` in the results.
+    pub(crate) parallelism: Option<ExecutorKind>,
+
+    /// The name of a registered custom query builder to build the
+    /// query with instead of the query mode.
+    pub(crate) custom_query: Option<String>,
+
+    /// Search fields which are matched with a constant score rather than
+    /// BM25, so long values are not penalized by length normalization.
+    ///
+    /// This applies to the fuzzy, fast-fuzzy and prefix query modes.
+    #[serde(default)]
+    pub(crate) constant_score_fields: Vec<String>,
+
+    /// Returns the character offsets of the matched terms in each of the
+    /// given fields for each hit, these must be stored text fields.
+    ///
+    /// This re-tokenizes the stored values of every hit so it is opt-in.
+    #[serde(default)]
+    pub(crate) match_positions: Vec<String>,
+
+    /// Caches every document matched by the search so following searches
+    /// can be restricted to them with the returned `results_token`.
+    #[serde(default)]
+    pub(crate) cache_results: bool,
+
+    /// Restricts the search to the documents matched by a previous search
+    /// which cached it's results, this fails once the index has changed.
+    pub(crate) within: Option<String>,
+
+    /// Returns the contribution of each query term to the score of each hit.
+    ///
+    /// This explains every hit's score which is relatively expensive.
+    #[serde(default)]
+    pub(crate) explain: bool,
+
+    /// The maximum amount of matching documents the search scores.
+    ///
+    /// Once exceeded, scoring stops and any results collected so far are
+    /// returned with the results marked as truncated. Unlike `timeout`
+    /// this doesn't depend on the speed of the hardware, however with
+    /// parallel executors which documents are scored first can vary.
+    ///
+    /// The budget applies to each pass of the search, hybrid searches make
+    /// three passes so they can score up to three times as many documents.
+    pub(crate) max_scored_docs: Option<usize>,
+
+    /// Attaches the full explanation of the score to only the first
+    /// `explain_top` hits, bounding the cost of explaining.
+    pub(crate) explain_top: Option<usize>,
+
+    /// The maximum count to report, if more documents match the count is
+    /// set to the cap and the results are marked as truncated.
+    pub(crate) count_cap: Option<usize>,
+
+    /// Whether or not hits should be scored by relevance.
+    ///
+    /// Disabling this skips computing scores entirely which is useful
+    /// when the search is only used as a filter and ordered by a field,
+    /// every hit is given the same score.
+    #[serde(default = "default_query_data::default_scoring")]
+    pub(crate) scoring: bool,
+
+    /// Drops any hits scoring below this ratio (0 to 1) of the top hit's
+    /// score, e.g. `0.2` drops hits scoring below 20% of the top hit.
+    ///
+    /// The top hit is the top hit of the search rather than of the page,
+    /// the hits are filtered after the search so the count still reflects
+    /// every matching document. This cannot be used when the hits are
+    /// ordered, sorted, grouped or collapsed by a field.
+    pub(crate) relative_min_score: Option<Score>,
+
+    /// Renames the returned fields of each hit, keyed by the field name
+    /// and mapping to the name it's returned as.
+    ///
+    /// This keeps the response stable across schema changes, the id is
+    /// always returned under it's canonical name. An alias can only be
+    /// the name of an existing field if that field is aliased as well.
+    #[serde(default)]
+    pub(crate) field_aliases: HashMap<String, String>,
+
+    /// The query vector for `QueryMode::Vector` queries.
+    pub(crate) vector: Option<Vec<f32>>,
+
+    /// The vector field compared against for `QueryMode::Vector` queries.
+    pub(crate) vector_field: Option<String>,
+
+    /// How the similarity of the vectors is measured, defaults to cosine.
+    #[serde(default)]
+    pub(crate) similarity: VectorSimilarity,
+
+    /// Combines the ranking of the text query with the ranking of the
+    /// query vector if set.
+    ///
+    /// Both queries are ran separately and fused, so only the top
+    /// `offset + limit` hits of each are considered. This cannot be
+    /// combined with any other ranking, see `order_by`.
+    pub(crate) hybrid: Option<HybridOptions>,
+
+    /// Boosts the recently dated documents of a `MoreLikeThis` query so
+    /// fresh documents surface among equally similar ones.
+    ///
+    /// This cannot be combined with any other ranking, see `order_by`.
+    pub(crate) recency: Option<RecencyOptions>,
+
+    /// Only matches documents which contain one of the query's words at
+    /// least this many times in a search field, e.g. to filter out documents
+    /// which only mention the query in passing.
+    ///
+    /// Words are counted exactly without typo tolerance, the words of a
+    /// `Normal` query are the terms it searches for excluding any negated
+    /// terms. This requires a `Fuzzy` or `Normal` query string and the
+    /// search fields must be indexed with term frequencies.
+    pub(crate) min_term_freq: Option<u32>,
+
+    /// Retries the query with increasing typo tolerance if it matches
+    /// fewer than this amount of documents, up to 2 typos per word.
+    ///
+    /// The retries use the standard fuzzy system so the query string is
+    /// parsed as plain words, this requires a `Fuzzy` query string.
+    pub(crate) min_results: Option<usize>,
+
+    /// Includes the total amount of live documents in the index with
+    /// the results, e.g. for showing "42 of 10,000 match".
+    #[serde(default)]
+    pub(crate) include_total_docs: bool,
+
+    /// Includes the `results_hash` of the hits with the results, which can
+    /// be given as `previous_hash` when repeating the search.
+    #[serde(default)]
+    pub(crate) include_results_hash: bool,
+
+    /// The `results_hash` of the previous results of this search, if the
+    /// results are unchanged no hits are returned and the results are
+    /// marked as `not_modified`. The hash of the new results is always
+    /// included when this is set.
+    ///
+    /// This cannot be used with streamed searches.
+    pub(crate) previous_hash: Option<String>,
+
+    /// Groups the hits by the value of this field, returning the top
+    /// `groups_limit` groups ranked by their best hit, each with their
+    /// top `per_group_limit` hits.
+    ///
+    /// This must be a single value numeric fast field, the grouped hits
+    /// are returned as `groups` in place of `hits` and the `limit` and
+    /// `offset` are ignored. This cannot be combined with any other
+    /// ranking, see `order_by`.
+    pub(crate) group_by: Option<String>,
+
+    /// The maximum amount of groups returned, the default is 10.
+    #[serde(default = "default_query_data::default_groups_limit")]
+    pub(crate) groups_limit: usize,
+
+    /// The maximum amount of hits returned per group, the default is 3
+    /// and at most 100.
+    #[serde(default = "default_query_data::default_per_group_limit")]
+    pub(crate) per_group_limit: usize,
+
+    /// Collapses the hits sharing the same value of this field into the
+    /// best scoring hit of the value, e.g. showing one product per family.
+    ///
+    /// This must be a single value numeric fast field. The `limit` and
+    /// `offset` apply to the collapsed hits and the `count` is the amount
+    /// of distinct values of the matching documents. This cannot be
+    /// combined with any other ranking, see `order_by`.
+    pub(crate) collapse: Option<String>,
+
+    /// The maximum amount of other hits with the same value returned as
+    /// the `inner_hits` of each collapsed hit, the default is 0 and at most 99.
+    #[serde(default)]
+    pub(crate) collapse_inner_hits: usize,
+
+    /// Orders hits with the same score by the hash of this seed and their
+    /// id, so ties are shuffled differently per seed but the same seed
+    /// always gives the same order, e.g. one seed per session.
+    ///
+    /// This cannot be combined with any other ranking, see `order_by`.
+    pub(crate) shuffle_seed: Option<u64>,
+
+    /// Counts the matching documents per day, week or month of a date
+    /// field, returned as `buckets` ordered by their start.
+    ///
+    /// Only buckets containing at least one match are returned.
+    pub(crate) date_histogram: Option<DateHistogramOptions>,
+
+    /// Echoes the main parameters of the query back with the results so
+    /// clients can correlate responses without tracking their requests.
+    ///
+    /// Large inputs like the query vector are never echoed.
+    #[serde(default)]
+    pub(crate) echo: bool,
+
+    /// Filters every query of this request must be restricted to.
+    ///
+    /// These can only be set by the server, see `add_mandatory_filter`.
+    #[serde(skip)]
+    pub(crate) mandatory_filters: Vec<MandatoryFilter>,
+}
+
+impl QueryPayload {
+    /// Creates a builder for a query payload.
+    pub fn builder() -> QueryPayloadBuilder {
+        QueryPayloadBuilder::new()
+    }
+
+    /// The query string if any.
+    pub fn query(&self) -> Option<&str> {
+        self.query.as_deref()
+    }
+
+    /// Replaces the query string, e.g. when rewriting the query.
+    pub fn set_query(&mut self, query: impl Into<String>) {
+        self.query = Some(query.into());
+    }
+
+    /// The query mode.
+    pub fn mode(&self) -> QueryMode {
+        self.mode
+    }
+
+    /// Restricts every query of this request to documents where the given
+    /// field has the given value, e.g. the tenant of the request.
+    ///
+    /// This is applied to the query regardless of the query mode and can
+    /// not be set or removed by the request payload itself.
+    pub fn add_mandatory_filter(&mut self, field: impl Into<String>, value: DocumentValue) {
+        self.mandatory_filters.push(MandatoryFilter {
+            field: field.into(),
+            value,
+        });
+    }
+}
+
+/// Builds a `QueryPayload` for searching an index directly from Rust.
+///
+/// Any parameter which isn't set has the same default as a request
+/// payload which omits it.
+#[derive(Debug, Clone)]
+pub struct QueryPayloadBuilder {
+    payload: QueryPayload,
+}
+
+impl Default for QueryPayloadBuilder {
+    fn default() -> Self {
+        let payload = serde_json::from_value(serde_json::Value::Object(Default::default()))
+            .expect("every query payload field has a default");
+
+        Self { payload }
+    }
+}
+
+impl QueryPayloadBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The query string of a `Fuzzy`, `Normal`, `Prefix` or `CaseSensitive` query.
+    pub fn query(mut self, query: impl Into<String>) -> Self {
+        self.payload.query = Some(query.into());
+        self
+    }
+
+    /// The reference document of a `MoreLikeThis` query.
+    pub fn document(mut self, document: u64) -> Self {
+        self.payload.document = Some(document);
+        self
+    }
+
+    /// Adds a field to the map query of a `Normal` query.
+    pub fn map_field(mut self, field: impl Into<String>, query: impl Into<String>) -> Self {
+        self.payload
+            .map
+            .insert(field.into(), MapQueryValue::Query(query.into()));
+        self
+    }
+
+    pub fn mode(mut self, mode: QueryMode) -> Self {
+        self.payload.mode = mode;
+        self
+    }
+
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.payload.limit = limit;
+        self
+    }
+
+    pub fn offset(mut self, offset: usize) -> Self {
+        self.payload.offset = offset;
+        self
+    }
+
+    pub fn order_by(mut self, field: impl Into<String>) -> Self {
+        self.payload.order_by = Some(field.into());
+        self
+    }
+
+    /// Adds a range filter the matched documents must satisfy.
+    pub fn range(
+        mut self,
+        field: impl Into<String>,
+        gte: Option<DocumentValue>,
+        lte: Option<DocumentValue>,
+    ) -> Self {
+        self.payload.ranges.push(RangeFilter {
+            field: field.into(),
+            gte,
+            lte,
+        });
+        self
+    }
+
+    /// The maximum amount of time in milliseconds the search can take.
+    pub fn timeout(mut self, millis: u64) -> Self {
+        self.payload.timeout = Some(millis);
+        self
+    }
+
+    /// The minimum length of a word for it to be matched with typo tolerance.
+    pub fn min_fuzzy_len(mut self, len: usize) -> Self {
+        self.payload.min_fuzzy_len = len;
+        self
+    }
+
+    pub fn stemming(mut self, language: Language) -> Self {
+        self.payload.stemming = Some(language);
+        self
+    }
+
+    pub fn disable_correction(mut self, disable: bool) -> Self {
+        self.payload.disable_correction = disable;
+        self
+    }
+
+    pub fn min_results(mut self, min_results: usize) -> Self {
+        self.payload.min_results = Some(min_results);
+        self
+    }
+
+    /// The query vector and vector field of a `Vector` query.
+    pub fn vector(mut self, field: impl Into<String>, vector: Vec<f32>) -> Self {
+        self.payload.vector_field = Some(field.into());
+        self.payload.vector = Some(vector);
+        self
+    }
+
+    pub fn debug(mut self, debug: bool) -> Self {
+        self.payload.debug = debug;
+        self
+    }
+
+    /// Validates that the query mode has the inputs it requires.
+    pub fn build(self) -> Result<QueryPayload> {
+        let payload = self.payload;
+
+        // Custom queries decide which inputs they require themselves.
+        if payload.custom_query.is_some() {
+            return Ok(payload);
+        }
+
+        match payload.mode {
+            QueryMode::Normal if payload.query.is_none() && payload.map.is_empty() => Err(
+                Error::msg("query mode was `Normal` but query string and map are empty"),
+            ),
+            QueryMode::Fuzzy | QueryMode::Prefix | QueryMode::CaseSensitive
+                if payload.query.is_none() =>
+            {
+                Err(Error::msg(format!(
+                    "query mode was `{:?}` but query string is `None`",
+                    payload.mode
+                )))
+            },
+            QueryMode::MoreLikeThis if payload.document.is_none() => Err(Error::msg(
+                "query mode was `MoreLikeThis` but reference document is `None`",
+            )),
+            QueryMode::Vector if payload.vector.is_none() => Err(Error::msg(
+                "query mode was `Vector` but query vector is `None`",
+            )),
+            _ => Ok(payload),
+        }
+    }
+}
+
+/// A field value every matched document must have.
+#[derive(Debug, Clone)]
+pub struct MandatoryFilter {
+    /// The field to filter, this must be an indexed field.
+    pub(crate) field: String,
+
+    /// The value the field must have.
+    pub(crate) value: DocumentValue,
+}
+
+/// Restricts the values of a given field to a range.
+///
+/// Both bounds are inclusive and either bound can be omitted
+/// to leave that side of the range unbounded.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RangeFilter {
+    /// The field to filter, this must be an indexed field.
+    pub(crate) field: String,
+
+    /// The lower bound of the range.
+    ///
+    /// Date fields also accept relative expressions e.g. `now-7d`.
+    pub(crate) gte: Option<DocumentValue>,
+
+    /// The upper bound of the range.
+    pub(crate) lte: Option<DocumentValue>,
+}
+
+/// The options for highlighting matched terms in the returned documents.
+#[derive(Debug, Clone, Deserialize)]
+pub struct HighlightOptions {
+    /// The fields to generate snippets for, these must be stored text fields.
+    pub(crate) fields: Vec<String>,
+
+    /// The maximum amount of characters in a single fragment, the default is 150.
+    #[serde(default = "default_query_data::default_max_num_chars")]
+    pub(crate) max_num_chars: usize,
+
+    /// The maximum amount of fragments per field, the default is 1.
+    #[serde(default = "default_query_data::default_num_fragments")]
+    pub(crate) num_fragments: usize,
+
+    /// The separator used to join multiple fragments, the default is `" … "`.
+    #[serde(default = "default_query_data::default_fragment_separator")]
+    pub(crate) fragment_separator: String,
+
+    /// Returns the start of the stored value truncated to `max_num_chars`
+    /// when a field has no matches rather than omitting the field.
+    #[serde(default)]
+    pub(crate) fallback_to_prefix: bool,
+
+    /// Skips the snippets of a hit which matched within one of the
+    /// `return_fields` of the query, as the match is already visible.
+    ///
+    /// Highlight fields which are returned themselves never get a snippet.
+    /// This has no effect if the query doesn't set `return_fields`.
+    #[serde(default)]
+    pub(crate) skip_returned: bool,
+}
+
+impl HighlightOptions {
+    /// Creates the default highlighting options for the given fields.
+    pub(crate) fn with_fields(fields: Vec<String>) -> Self {
+        Self {
+            fields,
+            max_num_chars: default_query_data::default_max_num_chars(),
+            num_fragments: default_query_data::default_num_fragments(),
+            fragment_separator: default_query_data::default_fragment_separator(),
+            fallback_to_prefix: false,
+            skip_returned: false,
+        }
+    }
 }
 
 mod default_query_data {
@@ -301,6 +1400,62 @@ mod default_query_data {
     pub fn default_offset() -> usize {
         0
     }
+
+    pub fn default_correction_candidates() -> usize {
+        1
+    }
+
+    pub fn default_max_field_boost() -> tantivy::Score {
+        10.0
+    }
+
+    pub fn default_correction_penalty() -> tantivy::Score {
+        0.8
+    }
+
+    pub fn default_max_num_chars() -> usize {
+        150
+    }
+
+    pub fn default_num_fragments() -> usize {
+        1
+    }
+
+    pub fn default_fragment_separator() -> String {
+        " … ".to_string()
+    }
+
+    pub fn default_scoring() -> bool {
+        true
+    }
+
+    pub fn default_vector_weight() -> tantivy::Score {
+        0.5
+    }
+
+    pub fn default_rank_constant() -> usize {
+        60
+    }
+
+    pub fn default_half_life() -> u64 {
+        7 * 24 * 60 * 60
+    }
+
+    pub fn default_recency_weight() -> tantivy::Score {
+        0.25
+    }
+
+    pub fn default_min_fuzzy_len() -> usize {
+        4
+    }
+
+    pub fn default_groups_limit() -> usize {
+        10
+    }
+
+    pub fn default_per_group_limit() -> usize {
+        3
+    }
 }
 
 /// A tantivy document representation.
@@ -318,7 +1473,7 @@ pub enum DocumentItem {
 }
 
 /// A document value that can be processed by tantivy.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum DocumentValue {
     /// A signed 64 bit integer.
     I64(i64),
@@ -383,22 +1538,34 @@ impl<'de> Deserialize<'de> for DocumentValue {
 }
 
 impl Document {
-    pub(crate) fn parse_into_document(self, schema: &InternalSchema) -> Result<InternalDocument> {
+    pub(crate) fn parse_into_document(
+        self,
+        schema: &InternalSchema,
+        decimal_fields: &HashMap<String, u32>,
+        vector_fields: &HashMap<String, usize>,
+    ) -> Result<InternalDocument> {
         let mut doc = InternalDocument::new();
         for (key, values) in self.0 {
             let field = schema
                 .get_field(&key)
                 .ok_or_else(|| Error::msg(format!("field {:?} does not exist in schema", &key)))?;
 
+            // The values of a vector make up a single value.
+            if let Some(dims) = vector_fields.get(&key) {
+                doc.add_bytes(field, encode_vector(&parse_vector(&key, values, *dims)?));
+                continue;
+            }
+
             let entry = schema.get_field_entry(field);
             let field_type = entry.field_type();
+            let scale = decimal_fields.get(&key).copied();
 
             match values {
                 DocumentItem::Single(value) =>
-                    add_value(&key, field, field_type, value, &mut doc)?,
+                    add_value(&key, field, field_type, scale, value, &mut doc)?,
                 DocumentItem::Multi(values) => {
                     for value in values {
-                        add_value(&key, field, field_type, value, &mut doc)?;
+                        add_value(&key, field, field_type, scale, value, &mut doc)?;
                     }
                 },
             };
@@ -408,7 +1575,12 @@ impl Document {
     }
 }
 
-fn add_value(key: &String, field: Field, field_type: &FieldType, value: DocumentValue, doc: &mut InternalDocument) -> Result<()> {
+fn add_value(key: &String, field: Field, field_type: &FieldType, scale: Option<u32>, value: DocumentValue, doc: &mut InternalDocument) -> Result<()> {
+    if let Some(scale) = scale {
+        doc.add_i64(field, scale_decimal(value, scale)?);
+        return Ok(());
+    }
+
     match (value, field_type) {
         (DocumentValue::I64(v), FieldType::I64(_)) => doc.add_i64(field, v),
         (DocumentValue::U64(v), FieldType::U64(_)) => doc.add_u64(field, v),
@@ -440,4 +1612,293 @@ fn add_value(key: &String, field: Field, field_type: &FieldType, value: Document
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Builds a `Term` from a given field and value.
+///
+/// If the field is a decimal field the value is scaled by the
+/// decimal's scale first.
+pub(crate) fn value_to_term(field: Field, field_type: &FieldType, scale: Option<u32>, value: DocumentValue) -> Result<Term> {
+    if let Some(scale) = scale {
+        return Ok(Term::from_field_i64(field, scale_decimal(value, scale)?));
+    }
+
+    let term = match (value, field_type) {
+        (DocumentValue::I64(v), FieldType::I64(_)) => Term::from_field_i64(field, v),
+        (DocumentValue::U64(v), FieldType::U64(_)) => Term::from_field_u64(field, v),
+        (DocumentValue::F64(v), FieldType::F64(_)) => Term::from_field_f64(field, v),
+        (DocumentValue::Text(v), FieldType::Str(_)) => Term::from_field_text(field, &v),
+        (DocumentValue::Datetime(v), FieldType::Str(_)) => Term::from_field_text(field, &v.to_string()),
+        (DocumentValue::Datetime(v), FieldType::Date(_)) => Term::from_field_date(field, &v),
+        (DocumentValue::I64(v), FieldType::Date(_)) => {
+            match chrono::NaiveDateTime::from_timestamp_opt(v, 0) {
+                Some(dt) => {
+                    let dt = chrono::DateTime::from_utc(dt, Utc);
+                    Term::from_field_date(field, &dt)
+                },
+                None =>
+                    return Err(Error::msg(format!("filed {:?} is type {:?} in schema but did not get a valid value (invalid timestamp)", &field, field_type))),
+            }
+        },
+        (DocumentValue::U64(v), FieldType::Date(_)) => {
+            match chrono::NaiveDateTime::from_timestamp_opt(v as i64, 0) {
+                Some(dt) => {
+                    let dt = chrono::DateTime::from_utc(dt, Utc);
+                    Term::from_field_date(field, &dt)
+                },
+                None =>
+                    return Err(Error::msg(format!("filed {:?} is type {:?} in schema but did not get a valid value (invalid timestamp)", &field, field_type))),
+            }
+        },
+        _ => return Err(Error::msg(format!("filed {:?} is type {:?} in schema but did not get a valid value", &field, field_type)))
+    };
+
+    Ok(term)
+}
+
+/// Converts a given value into a decimal scaled by `10^scale`.
+///
+/// Text values are parsed exactly while floats are rounded to the
+/// nearest representable decimal.
+pub(crate) fn scale_decimal(value: DocumentValue, scale: u32) -> Result<i64> {
+    let factor = 10i64
+        .checked_pow(scale)
+        .ok_or_else(|| Error::msg(format!("decimal scale {} is too large", scale)))?;
+
+    let scaled = match value {
+        DocumentValue::I64(v) => v.checked_mul(factor),
+        DocumentValue::U64(v) => i64::try_from(v).ok().and_then(|v| v.checked_mul(factor)),
+        DocumentValue::F64(v) => {
+            let scaled = (v * factor as f64).round();
+            if scaled.is_finite() && scaled.abs() < i64::MAX as f64 {
+                Some(scaled as i64)
+            } else {
+                None
+            }
+        },
+        DocumentValue::Text(v) => parse_decimal(&v, scale, factor),
+        DocumentValue::Datetime(_) => None,
+    };
+
+    scaled.ok_or_else(|| Error::msg(format!("value is not a valid decimal with a scale of {}", scale)))
+}
+
+/// Parses a decimal string exactly, rejecting any more fractional
+/// digits than the scale allows.
+fn parse_decimal(value: &str, scale: u32, factor: i64) -> Option<i64> {
+    let value = value.trim();
+    let (negative, value) = match value.strip_prefix('-') {
+        Some(value) => (true, value),
+        None => (false, value),
+    };
+
+    let (whole, fraction) = match value.split_once('.') {
+        Some((whole, fraction)) => (whole, fraction),
+        None => (value, ""),
+    };
+
+    if fraction.len() > scale as usize || !fraction.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    // The sign has already been stripped so the whole part must be digits
+    // only, otherwise `parse` would accept a second sign e.g. `--5`.
+    if !whole.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    let whole: i64 = whole.parse().ok()?;
+    let fraction: i64 = if fraction.is_empty() {
+        0
+    } else {
+        fraction.parse::<i64>().ok()? * 10i64.pow(scale - fraction.len() as u32)
+    };
+
+    let scaled = whole.checked_mul(factor)?.checked_add(fraction)?;
+    if negative {
+        Some(-scaled)
+    } else {
+        Some(scaled)
+    }
+}
+
+/// Converts the values of a vector field into a vector of the given dimensions.
+fn parse_vector(key: &str, item: DocumentItem, dims: usize) -> Result<Vec<f32>> {
+    let values = match item {
+        DocumentItem::Single(value) => vec![value],
+        DocumentItem::Multi(values) => values,
+    };
+
+    if values.len() != dims {
+        return Err(Error::msg(format!(
+            "vector field {:?} expects {} dimensions but got {}",
+            key,
+            dims,
+            values.len()
+        )));
+    }
+
+    values
+        .into_iter()
+        .map(|value| match value {
+            DocumentValue::F64(v) => Ok(v as f32),
+            DocumentValue::I64(v) => Ok(v as f32),
+            DocumentValue::U64(v) => Ok(v as f32),
+            _ => Err(Error::msg(format!("vector field {:?} must only contain numbers", key))),
+        })
+        .collect()
+}
+
+/// Encodes a vector as the little endian bytes of each dimension.
+pub(crate) fn encode_vector(vector: &[f32]) -> Vec<u8> {
+    vector.iter().flat_map(|v| v.to_le_bytes()).collect()
+}
+
+/// Decodes a vector encoded by `encode_vector`.
+pub(crate) fn decode_vector(bytes: &[u8]) -> impl Iterator<Item = f32> + '_ {
+    bytes
+        .chunks_exact(4)
+        .map(|chunk| f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+}
+
+/// Formats a scaled decimal as it's exact decimal string, e.g. `1999`
+/// with a scale of 2 is formatted as `"19.99"`.
+pub(crate) fn format_decimal(value: i64, scale: u32) -> String {
+    let sign = if value < 0 { "-" } else { "" };
+    let digits = value.unsigned_abs().to_string();
+    if scale == 0 {
+        return format!("{}{}", sign, digits);
+    }
+
+    // The digits are padded so there's always a whole part.
+    let scale = scale as usize;
+    let digits = format!("{:0>width$}", digits, width = scale + 1);
+    let (whole, fraction) = digits.split_at(digits.len() - scale);
+    format!("{}{}.{}", sign, whole, fraction)
+}
+
+/// Resolves a relative date expression against the given time.
+///
+/// Expressions are either `now` or `now` followed by a signed offset
+/// e.g. `now-7d`, `now-12h` or `now+30m`. The supported units are
+/// `s` (seconds), `m` (minutes), `h` (hours), `d` (days) and `w` (weeks).
+pub(crate) fn resolve_relative_date(expr: &str, now: DateTime) -> Result<DateTime> {
+    let invalid = || {
+        Error::msg(format!(
+            "invalid relative date {:?}, expected an expression like `now` or `now-7d`",
+            expr
+        ))
+    };
+
+    let offset = expr.trim().strip_prefix("now").ok_or_else(invalid)?;
+    if offset.is_empty() {
+        return Ok(now);
+    }
+
+    let mut chars = offset.chars();
+    let negative = match chars.next() {
+        Some('-') => true,
+        Some('+') => false,
+        _ => return Err(invalid()),
+    };
+    let unit = chars.next_back().ok_or_else(invalid)?;
+    let amount = chars.as_str();
+    if amount.is_empty() || !amount.chars().all(|c| c.is_ascii_digit()) {
+        return Err(invalid());
+    }
+
+    let unit_seconds: i64 = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        'w' => 7 * 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+
+    // chrono panics on durations larger than `i64::MAX` milliseconds.
+    let out_of_range = || Error::msg(format!("relative date {:?} is out of range", expr));
+    let seconds = amount
+        .parse::<i64>()
+        .ok()
+        .and_then(|amount| amount.checked_mul(unit_seconds))
+        .filter(|seconds| *seconds <= i64::MAX / 1000)
+        .ok_or_else(out_of_range)?;
+    let duration = chrono::Duration::seconds(seconds);
+
+    let resolved = if negative {
+        now.checked_sub_signed(duration)
+    } else {
+        now.checked_add_signed(duration)
+    };
+
+    resolved.ok_or_else(out_of_range)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn now() -> DateTime {
+        DateTime::from_str("2021-06-15T12:00:00Z").unwrap()
+    }
+
+    #[test]
+    fn decimals_are_formatted_exactly() {
+        assert_eq!(format_decimal(1999, 2), "19.99");
+        assert_eq!(format_decimal(1250, 2), "12.50");
+        assert_eq!(format_decimal(5, 3), "0.005");
+        assert_eq!(format_decimal(-5, 2), "-0.05");
+        assert_eq!(format_decimal(42, 0), "42");
+        assert_eq!(format_decimal(i64::MIN, 2), "-92233720368547758.08");
+    }
+
+    #[test]
+    fn relative_date_now() {
+        assert_eq!(resolve_relative_date("now", now()).unwrap(), now());
+        assert_eq!(resolve_relative_date(" now ", now()).unwrap(), now());
+    }
+
+    #[test]
+    fn relative_date_offsets() {
+        let cases = [
+            ("now-30s", chrono::Duration::seconds(-30)),
+            ("now+5m", chrono::Duration::minutes(5)),
+            ("now-12h", chrono::Duration::hours(-12)),
+            ("now-7d", chrono::Duration::days(-7)),
+            ("now+2w", chrono::Duration::weeks(2)),
+        ];
+
+        for (expr, offset) in cases {
+            let resolved = resolve_relative_date(expr, now()).unwrap();
+            assert_eq!(resolved, now() + offset, "{}", expr);
+        }
+    }
+
+    #[test]
+    fn relative_date_rejects_invalid_expressions() {
+        let cases = [
+            "", "today", "now7d", "now-", "now-d", "now-7", "now-7y", "now--7d", "now-+7d",
+            "now-7 d", "now€", "now-7é", "now-€d", "now+é",
+        ];
+
+        for expr in cases {
+            assert!(resolve_relative_date(expr, now()).is_err(), "{:?}", expr);
+        }
+    }
+
+    #[test]
+    fn relative_date_rejects_out_of_range_offsets() {
+        let cases = [
+            "now-9223372036854775807w",
+            "now+9223372036854775807d",
+            "now-99999999999999999999s",
+            "now+9999999999999999s",
+            "now-999999999999w",
+        ];
+
+        for expr in cases {
+            assert!(resolve_relative_date(expr, now()).is_err(), "{:?}", expr);
+        }
+    }
+}
@@ -29,7 +29,7 @@ mod auth;
 mod responders;
 mod routes;
 
-use engine::SearchEngine;
+use engine::{CorrectionNormalizer, SearchEngine};
 use tower::util::MapResponseLayer;
 use tower_http::add_extension::AddExtensionLayer;
 
@@ -92,6 +92,22 @@ struct Settings {
     /// This system expects for frequency dictionary
     #[structopt(long, env, takes_value = false)]
     enable_fast_fuzzy: bool,
+
+    /// How text is normalized before being corrected by the fast fuzzy
+    /// system, either `raw` or `ascii-folding`.
+    ///
+    /// The same normalizer applies to documents and queries, changing it
+    /// requires re-indexing documents added with the fast fuzzy system.
+    #[structopt(long, default_value = "raw", env)]
+    correction_normalizer: CorrectionNormalizer,
+
+    /// A custom frequency dictionary used by the fast fuzzy system instead
+    /// of the bundled dictionary.
+    ///
+    /// Each line must contain a term and it's count separated by a space,
+    /// this can be replaced at runtime via `/admin/dictionary`.
+    #[structopt(long, env)]
+    correction_dictionary: Option<String>,
 }
 
 fn main() {
@@ -187,7 +203,12 @@ async fn start(settings: Settings) -> Result<()> {
 
     info!("setting up the search engine");
     let engine =
-        Arc::new(SearchEngine::create("./lnx-data/meta", settings.enable_fast_fuzzy).await?);
+        Arc::new(SearchEngine::create(
+            "./lnx-data/meta",
+            settings.enable_fast_fuzzy,
+            settings.correction_normalizer,
+            settings.correction_dictionary.clone(),
+        ).await?);
 
     let super_user_middleware = ServiceBuilder::new()
         .layer(RequireAuthorizationLayer::custom(
@@ -208,6 +229,7 @@ async fn start(settings: Settings) -> Result<()> {
             ),
         )
         .layer(AddExtensionLayer::new(authorization_manager))
+        .layer(AddExtensionLayer::new(engine.clone()))
         .layer(MapResponseLayer::new(routes::map_status))
         .into_inner();
 
@@ -216,6 +238,7 @@ async fn start(settings: Settings) -> Result<()> {
         .route("/tokens/permissions", post(routes::modify_permissions))
         .route("/tokens/create", post(routes::create_token))
         .route("/tokens/clear", delete(routes::revoke_all))
+        .route("/dictionary", post(routes::load_dictionary))
         .layer(super_user_middleware);
 
     let search_auth = auth::UserAuthIfEnabled::bearer(
@@ -255,9 +278,14 @@ async fn start(settings: Settings) -> Result<()> {
             "/indexes/:index_name/search",
             get(routes::search_index.layer(RequireAuthorizationLayer::custom(search_auth.clone())))
                 .post(
-                    routes::search_index_json.layer(RequireAuthorizationLayer::custom(search_auth)),
+                    routes::search_index_json.layer(RequireAuthorizationLayer::custom(search_auth.clone())),
                 ),
         )
+        .route(
+            "/indexes/:index_name/fields",
+            get(routes::get_field_capabilities
+                .layer(RequireAuthorizationLayer::custom(search_auth))),
+        )
         .route(
             "/indexes/:index_name/commit",
             post(
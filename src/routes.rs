@@ -4,7 +4,7 @@ use axum::body::{box_body, Body, BoxBody};
 use axum::extract::rejection::{JsonRejection, PathParamsRejection, QueryRejection};
 use axum::extract::{self, Extension, Path, Query};
 use axum::http::{Response, StatusCode};
-use engine::structures::{Document, DocumentValue, IndexDeclaration, QueryPayload};
+use engine::structures::{Document, DocumentValue, IndexDeclaration, MapQueryValue, QueryPayload};
 use engine::{LeasedIndex, SearchEngine};
 use hashbrown::HashMap;
 use serde::Deserialize;
@@ -13,7 +13,7 @@ use crate::auth::{AuthManager, Permissions};
 use crate::responders::json_response;
 
 type SharedEngine = Arc<SearchEngine>;
-type FieldSearch = HashMap<String, String>;
+type FieldSearch = HashMap<String, MapQueryValue>;
 
 /// Extracts a leased index or returns a json response
 /// with a 400 status code.
@@ -317,6 +317,19 @@ pub async fn get_document(
     json_response(StatusCode::OK, &doc)
 }
 
+/// Gets what each field of the index can be used for, e.g. whether
+/// the results can be ordered by it.
+pub async fn get_field_capabilities(
+    index_name: Result<Path<String>, PathParamsRejection>,
+    Extension(engine): Extension<SharedEngine>,
+) -> Response<Body> {
+    let index_name = check_path!(index_name);
+
+    let index: LeasedIndex = get_index_or_reject!(engine, index_name.as_str());
+
+    json_response(StatusCode::OK, &index.field_capabilities())
+}
+
 /// Deletes any documents matching the set of given terms.
 pub async fn delete_documents(
     index_name: Result<Path<String>, PathParamsRejection>,
@@ -460,6 +473,28 @@ pub async fn revoke_all(Extension(auth_manager): Extension<Arc<AuthManager>>) ->
     json_response(StatusCode::OK, "tokens revoked")
 }
 
+/// The payload for replacing the fast fuzzy dictionary.
+#[derive(Deserialize)]
+pub struct LoadDictionaryPayload {
+    /// The path of the frequency dictionary on the server.
+    path: String,
+}
+
+/// Replaces the fast fuzzy dictionary with the dictionary at the
+/// given path without restarting.
+pub async fn load_dictionary(
+    payload: Result<extract::Json<LoadDictionaryPayload>, JsonRejection>,
+    Extension(engine): Extension<SharedEngine>,
+) -> Response<Body> {
+    let payload = check_json!(payload);
+    check_error!(
+        engine.load_correction_dictionary(payload.0.path).await,
+        "load dictionary"
+    );
+
+    json_response(StatusCode::OK, "dictionary loaded")
+}
+
 /// The query parameters for changing the permissions
 /// of an access token.
 ///